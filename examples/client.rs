@@ -11,7 +11,7 @@ use bevy::app::{ScheduleRunnerSettings, EventReader};
 use bevy_naia_laminar::prelude::*;
 use bevy_naia_laminar::client::prelude::*;
 
-use std::{net::SocketAddr, time::Duration};
+use std::time::Duration;
 
 const SERVER_PORT: u16 = 14191;
 
@@ -38,6 +38,7 @@ fn main() {
     let link_conditioner = None;
     let net_plugin = ClientNetworkingPlugin{
         link_conditioner,
+        connection_state: true,
         ..Default::default()
     };
 
@@ -52,33 +53,78 @@ fn main() {
         .add_plugin(net_plugin)
         // Our networking
         .add_startup_system(startup.system())
-        .add_system(send_packets.system())
+        .add_system(connect_to_discovered.system())
+        .add_system(log_server_info.system())
+        .add_system_set(SystemSet::on_enter(ConnectionState::Connected).with_system(on_connected.system()))
+        .add_system_set(SystemSet::on_exit(ConnectionState::Connected).with_system(on_disconnected.system()))
+        .add_system_set(SystemSet::on_update(ConnectionState::Connected).with_system(send_packets.system()))
         .add_system(handle_packets.system())
         .run();
 }
 
+fn laminar_config() -> LaminarConfig {
+    LaminarConfig {
+        idle_connection_timeout: Duration::from_millis(3000),
+        heartbeat_interval: Some(Duration::from_millis(1000)),
+        ..Default::default()
+    }
+}
+
+// native builds look for the server on the LAN, browsers can't broadcast
+#[cfg(not(target_arch = "wasm32"))]
+fn startup(mut net: ResMut<NetworkResource>) {
+    log::info!("Looking for servers on the LAN");
+    net.discover_servers().expect("can't broadcast for servers");
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_to_discovered(mut net: ResMut<NetworkResource>, mut discovered: EventReader<ServerDiscovered>) {
+    for server in discovered.iter() {
+        log::info!("Found {:?} at {} ({} players)", server.name, server.addr, server.players);
+        // doesn't need a connection, just to show how
+        if let Err(err) = net.query_server(server.addr) {
+            log::warn!("Can't query {}: {}", server.addr, err);
+        }
+        if !net.initialized() {
+            log::info!("Starting client (--> {:?})", server.addr);
+            net.connect(server.addr, laminar_config());
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
 fn startup(mut net: ResMut<NetworkResource>) {
     // compile_error!("Set the ip to the one your server is listening on");
     log::warn!("I hope you edited the examples/client.rs and set the IP address");
-    let server_address: SocketAddr = "10.0.0.123:14191".parse().expect("can't parse server addr"); 
+    let server_address: std::net::SocketAddr = "10.0.0.123:14191".parse().expect("can't parse server addr"); 
     log::info!("Starting client (--> {:?})", server_address);
 
-    let config = LaminarConfig {
-        idle_connection_timeout: Duration::from_millis(3000),
-        heartbeat_interval: Some(Duration::from_millis(1000)),
-        ..Default::default()
-    };
+    net.connect(server_address, laminar_config());
+}
 
-    net.connect(server_address, config);
+#[cfg(not(target_arch = "wasm32"))]
+fn log_server_info(mut received: EventReader<ServerInfoReceived>) {
+    for answer in received.iter() {
+        log::info!("{} is {:?} playing {:?}, ping {:?}", answer.addr, answer.info.name, answer.info.map, answer.rtt);
+    }
 }
 
+#[cfg(target_arch = "wasm32")]
+fn connect_to_discovered() {}
+
+#[cfg(target_arch = "wasm32")]
+fn log_server_info() {}
+
+fn on_connected(net: Res<NetworkResource>) {
+    log::info!("Connected to {}", net.server_addr());
+}
+
+fn on_disconnected(net: Res<NetworkResource>) {
+    log::info!("No longer connected, now {:?}", net.connection_state());
+}
+
+// only runs while we're Connected
 fn send_packets(mut net: ResMut<NetworkResource>, time: Res<Time>, mut n: Local<u32>, mut ttp: Local<f64>) {
-    if !net.initialized() {
-        return;
-    }
-    if net.connection_state() != ConnectionState::Connected {
-        return;
-    }
     *ttp += time.delta_seconds_f64();
     if *ttp >= 1.0 {
         *ttp = 0.0;
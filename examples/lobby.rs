@@ -0,0 +1,113 @@
+// Lobby service on localhost, native only. In three terminals:
+//
+//  cargo run --example lobby -- server
+//  cargo run --example lobby -- host alice
+//  cargo run --example lobby -- join bob
+//
+// alice creates a lobby, bob finds it, joins and readies up, and once he has alice starts the game.
+// Both are then told to connect to the game server from examples/server.rs.
+
+use bevy::prelude::*;
+use bevy::log::{self, LogPlugin};
+use bevy::app::{ScheduleRunnerSettings, EventReader};
+
+use bevy_naia_laminar::prelude::*;
+
+use std::{net::SocketAddr, time::Duration};
+
+const LOBBY_ADDR: &str = "127.0.0.1:14193";
+const GAME_ADDR: &str = "127.0.0.1:14191";
+
+struct PlayerName(String);
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut app = App::build();
+    app
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / 60.0,
+        )))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin);
+
+    match args.get(0).map(String::as_str) {
+        Some("server") => {
+            app
+                .add_plugin(bevy_naia_laminar::server::ServerNetworkingPlugin::default())
+                .add_plugin(LobbyServerPlugin {
+                    game_server: Some(GAME_ADDR.parse().unwrap()),
+                })
+                .add_startup_system(start_server.system())
+                .add_system(log_started.system());
+        }
+        Some(role @ "host") | Some(role @ "join") => {
+            let name = args.get(1).cloned().unwrap_or_else(|| role.to_string());
+            app
+                .insert_resource(PlayerName(name))
+                .add_plugin(bevy_naia_laminar::client::ClientNetworkingPlugin::default())
+                .add_plugin(LobbyClientPlugin)
+                .add_startup_system(start_client.system())
+                .add_system(lobby_events.system());
+            if role == "host" {
+                app.add_startup_system(create_lobby.system());
+            } else {
+                app.add_startup_system(list_lobbies.system());
+            }
+        }
+        _ => {
+            eprintln!("usage: lobby server | lobby host <name> | lobby join <name>");
+            return;
+        }
+    }
+    app.run();
+}
+
+fn start_server(mut net: ResMut<bevy_naia_laminar::server::NetworkResource>) {
+    let addr: SocketAddr = LOBBY_ADDR.parse().unwrap();
+    log::info!("Lobby server on {}", addr);
+    net.listen_with_settings(addr, None, None);
+}
+
+fn log_started(mut started: EventReader<LobbyStarted>) {
+    for event in started.iter() {
+        log::info!("Lobby {} started with {} players", event.lobby.id, event.peers.len());
+    }
+}
+
+fn start_client(mut net: ResMut<bevy_naia_laminar::client::NetworkResource>) {
+    net.connect_with_settings(LOBBY_ADDR.parse().unwrap());
+}
+
+// requests made before we're connected are sent once we are
+fn create_lobby(mut lobby: ResMut<LobbyClient>, name: Res<PlayerName>) {
+    let settings = LobbySettings {
+        name: format!("{}'s game", name.0),
+        max_players: 4,
+        ..Default::default()
+    };
+    lobby.create(settings, &name.0);
+}
+
+fn list_lobbies(mut lobby: ResMut<LobbyClient>) {
+    lobby.list();
+}
+
+fn lobby_events(mut lobby: ResMut<LobbyClient>, name: Res<PlayerName>, mut events: EventReader<LobbyEvent>) {
+    for event in events.iter() {
+        log::info!("{:?}", event);
+        match event {
+            LobbyEvent::List(lobbies) => match lobbies.first() {
+                Some(info) => lobby.join(info.id, &name.0),
+                None => log::warn!("No lobbies yet, start the host first"),
+            },
+            LobbyEvent::Joined(_) if !lobby.is_host() => lobby.set_ready(true),
+            LobbyEvent::Changed(info) if lobby.is_host() && info.members.len() > 1 && info.all_ready() => {
+                lobby.start(None);
+            }
+            LobbyEvent::Started { game_server, .. } => {
+                log::info!("Game on! Connect to {}", game_server);
+            }
+            _ => {}
+        }
+    }
+}
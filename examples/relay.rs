@@ -0,0 +1,113 @@
+// Relaying on localhost, native only. In three terminals:
+//
+//  cargo run --example relay -- relay
+//  cargo run --example relay -- host
+//  cargo run --example relay -- guest <the handle the host printed>
+//
+// The guest pings the host once a second through the relay, and the host answers.
+
+use bevy::prelude::*;
+use bevy::log::{self, LogPlugin};
+use bevy::app::{ScheduleRunnerSettings, EventReader};
+
+use bevy_naia_laminar::prelude::*;
+use bevy_naia_laminar::client::prelude::LaminarPacket;
+
+use std::{net::SocketAddr, time::Duration};
+
+const RELAY_ADDR: &str = "127.0.0.1:14195";
+
+struct HostHandle(Option<PeerHandle>);
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut app = App::build();
+    app
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / 60.0,
+        )))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin);
+
+    match args.get(0).map(String::as_str) {
+        Some("relay") => {
+            app
+                .add_plugin(bevy_naia_laminar::server::ServerNetworkingPlugin::default())
+                .add_plugin(RelayServerPlugin)
+                .add_startup_system(start_relay.system());
+        }
+        Some(role @ "host") | Some(role @ "guest") => {
+            let host = match args.get(1) {
+                Some(handle) => Some(handle.parse::<SocketAddr>().expect("host handle should be ip:port")),
+                None if role == "guest" => {
+                    eprintln!("usage: relay guest <host handle>");
+                    return;
+                }
+                None => None,
+            };
+            app
+                .insert_resource(HostHandle(host))
+                .add_plugin(bevy_naia_laminar::client::ClientNetworkingPlugin::default())
+                .add_plugin(RelayClientPlugin)
+                .add_startup_system(start_client.system())
+                .add_system(relay_events.system())
+                .add_system(ping_host.system());
+        }
+        _ => {
+            eprintln!("usage: relay relay | relay host | relay guest <host handle>");
+            return;
+        }
+    }
+    app.run();
+}
+
+fn start_relay(mut net: ResMut<bevy_naia_laminar::server::NetworkResource>) {
+    let addr: SocketAddr = RELAY_ADDR.parse().unwrap();
+    log::info!("Relay on {}", addr);
+    net.listen_with_settings(addr, None, None);
+}
+
+fn start_client(
+    mut net: ResMut<bevy_naia_laminar::client::NetworkResource>,
+    mut relay: ResMut<RelayClient>,
+    host: Res<HostHandle>,
+) {
+    net.connect_with_settings(RELAY_ADDR.parse().unwrap());
+    match host.0 {
+        Some(host) => relay.join(host),
+        None => relay.host(),
+    }
+}
+
+fn relay_events(mut relay: ResMut<RelayClient>, mut events: EventReader<RelayEvent>) {
+    for event in events.iter() {
+        match event {
+            RelayEvent::Hosting { handle } => {
+                log::info!("Hosting, join with: cargo run --example relay -- guest {}", handle);
+            }
+            RelayEvent::Message { packet } => {
+                let text = String::from_utf8_lossy(packet.payload()).to_string();
+                log::info!(">>> {} says {}", packet.addr(), text);
+                if let Some(RelayRole::Host { .. }) = relay.role() {
+                    let reply = format!("PONG ({})", text).into_bytes();
+                    relay.send(LaminarPacket::reliable_ordered(packet.addr(), reply, None));
+                }
+            }
+            event => log::info!("{:?}", event),
+        }
+    }
+}
+
+fn ping_host(mut relay: ResMut<RelayClient>, time: Res<Time>, mut n: Local<u32>, mut ttp: Local<f64>) {
+    let host = match relay.role() {
+        Some(RelayRole::Guest { host }) => host,
+        _ => return,
+    };
+    *ttp += time.delta_seconds_f64();
+    if *ttp >= 1.0 {
+        *ttp = 0.0;
+        *n += 1;
+        let payload = format!("PING {}", *n).into_bytes();
+        relay.send(LaminarPacket::reliable_ordered(host, payload, None));
+    }
+}
@@ -28,6 +28,9 @@ fn main() {
     let link_conditioner = None;
     let net_plugin = ServerNetworkingPlugin{
         link_conditioner,
+        // so examples/client.rs can find us on the LAN
+        #[cfg(feature = "use-udp")]
+        discovery: Some(DiscoveryConfig::default()),
         ..Default::default()
     };
 
@@ -38,6 +41,12 @@ fn main() {
         )))
         .add_plugins(MinimalPlugins)
         .add_plugin(LogPlugin)
+        // answers examples/client.rs's server info query
+        .insert_resource(ServerInfo {
+            name: "bevy_naia_laminar example".to_string(),
+            map: "pong".to_string(),
+            ..Default::default()
+        })
         .add_plugin(net_plugin)
         .add_startup_system(startup.system())
         .add_system(handle_packets.system())
@@ -0,0 +1,268 @@
+use std::net::SocketAddr;
+
+use laminar::{DeliveryGuarantee, OrderingGuarantee, Packet as LaminarPacket};
+
+use crate::protocol::{self, read_varint, write_varint, MessageKind};
+
+// Opt-in coalescing of small messages. Sends are queued until the flush system runs, then
+// messages to the same peer with the same guarantees (and stream) are packed into as few laminar
+// packets as fit in the mtu.
+//
+//  Batch: ([len: varint][kind: u8][payload])*
+//
+// len covers the kind byte and the payload. Messages too big to share a datagram still go out on
+// their own, in the order they were sent.
+
+/// Plugin option: batch outgoing messages per frame
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// largest batched payload in bytes, not counting laminar's headers.
+    /// keep it comfortably below laminar's fragment_size.
+    pub mtu: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self { mtu: 1200 }
+    }
+}
+
+// messages queued for one peer with one set of guarantees, in send order
+#[derive(Debug)]
+struct Group {
+    addr: SocketAddr,
+    delivery: DeliveryGuarantee,
+    ordering: OrderingGuarantee,
+    messages: Vec<(MessageKind, Vec<u8>)>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Batcher {
+    config: Option<BatchConfig>,
+    groups: Vec<Group>,
+}
+
+impl Batcher {
+    pub(crate) fn config(&self) -> Option<&BatchConfig> {
+        self.config.as_ref()
+    }
+
+    pub(crate) fn set_config(&mut self, config: Option<BatchConfig>) {
+        self.config = config;
+    }
+
+    /// queue a message for the next flush. hands back the wrapped packet to send right away if
+    /// batching is off.
+    pub(crate) fn push(&mut self, kind: MessageKind, packet: LaminarPacket) -> Option<LaminarPacket> {
+        if self.config.is_none() {
+            return Some(protocol::wrap(kind, packet));
+        }
+        let (addr, delivery, ordering) = (packet.addr(), packet.delivery_guarantee(), packet.order_guarantee());
+        let index = match self
+            .groups
+            .iter()
+            .position(|g| g.addr == addr && g.delivery == delivery && g.ordering == ordering)
+        {
+            Some(index) => index,
+            None => {
+                self.groups.push(Group {
+                    addr,
+                    delivery,
+                    ordering,
+                    messages: Vec::new(),
+                });
+                self.groups.len() - 1
+            }
+        };
+        self.groups[index].messages.push((kind, packet.payload().to_vec()));
+        None
+    }
+
+    /// everything queued since the last flush, packed into wrapped packets ready for laminar
+    pub(crate) fn flush(&mut self) -> Vec<LaminarPacket> {
+        // anything left over from before batching was turned off goes out one message each
+        let mtu = self.config.as_ref().map_or(0, |config| config.mtu);
+        let mut packets = Vec::new();
+        for mut group in self.groups.drain(..) {
+            let messages = std::mem::take(&mut group.messages);
+            let mut batch: Vec<(MessageKind, Vec<u8>)> = Vec::new();
+            let mut batch_len = 1;
+            for (kind, payload) in messages {
+                let len = entry_len(&payload);
+                if batch_len + len > mtu && !batch.is_empty() {
+                    packets.push(group.packet(std::mem::take(&mut batch)));
+                    batch_len = 1;
+                }
+                batch_len += len;
+                batch.push((kind, payload));
+            }
+            if !batch.is_empty() {
+                packets.push(group.packet(batch));
+            }
+        }
+        packets
+    }
+}
+
+impl Group {
+    // a lone message goes out as a normal message, so there's no batch overhead
+    fn packet(&self, mut messages: Vec<(MessageKind, Vec<u8>)>) -> LaminarPacket {
+        if messages.len() == 1 {
+            let (kind, payload) = messages.pop().unwrap();
+            let mut buf = Vec::with_capacity(1 + payload.len());
+            buf.push(kind as u8);
+            buf.extend_from_slice(&payload);
+            return protocol::build(self.addr, self.delivery, self.ordering, buf);
+        }
+        let mut buf = vec![MessageKind::Batch as u8];
+        for (kind, payload) in messages {
+            write_varint(&mut buf, payload.len() + 1);
+            buf.push(kind as u8);
+            buf.extend_from_slice(&payload);
+        }
+        protocol::build(self.addr, self.delivery, self.ordering, buf)
+    }
+}
+
+// bytes this message takes up inside a batch
+fn entry_len(payload: &[u8]) -> usize {
+    let mut len = Vec::new();
+    write_varint(&mut len, payload.len() + 1);
+    len.len() + 1 + payload.len()
+}
+
+/// like protocol::unwrap, but splits batches back into their messages. Empty for handshake
+/// packets and junk, and a malformed batch yields the messages before the damage.
+pub(crate) fn unbatch(packet: LaminarPacket) -> Vec<(MessageKind, LaminarPacket)> {
+    match protocol::unwrap(packet) {
+        Some((MessageKind::Batch, packet)) => {
+            let mut messages = Vec::new();
+            let mut body = packet.payload();
+            while !body.is_empty() {
+                let len = match read_varint(&mut body) {
+                    Some(len) if len > 0 && len <= body.len() => len,
+                    _ => break,
+                };
+                let (entry, rest) = body.split_at(len);
+                body = rest;
+                match protocol::unwrap(protocol::repack(&packet, entry.to_vec())) {
+                    // batches don't nest
+                    Some((MessageKind::Batch, _)) | None => {}
+                    Some(message) => messages.push(message),
+                }
+            }
+            messages
+        }
+        Some(message) => vec![message],
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    fn batcher(mtu: usize) -> Batcher {
+        let mut batcher = Batcher::default();
+        batcher.set_config(Some(BatchConfig { mtu }));
+        batcher
+    }
+
+    fn contents(messages: Vec<(MessageKind, LaminarPacket)>) -> Vec<(MessageKind, Vec<u8>)> {
+        messages.into_iter().map(|(kind, packet)| (kind, packet.payload().to_vec())).collect()
+    }
+
+    // a Batch packet holding these raw entry bytes
+    fn batch_of(body: &[u8]) -> LaminarPacket {
+        protocol::wrap(MessageKind::Batch, LaminarPacket::reliable_unordered(addr(1), body.to_vec()))
+    }
+
+    #[test]
+    fn unbatched_sends_go_straight_out() {
+        let mut batcher = Batcher::default();
+        let packet = batcher.push(MessageKind::User, LaminarPacket::unreliable(addr(1), vec![1, 2])).unwrap();
+        assert_eq!(packet.payload(), &[MessageKind::User as u8, 1, 2]);
+        assert!(batcher.flush().is_empty());
+    }
+
+    #[test]
+    fn flush_unbatch_roundtrip() {
+        let mut batcher = batcher(1200);
+        assert!(batcher.push(MessageKind::User, LaminarPacket::reliable_unordered(addr(1), vec![1])).is_none());
+        batcher.push(MessageKind::Channel, LaminarPacket::reliable_unordered(addr(1), vec![2, 3]));
+        batcher.push(MessageKind::User, LaminarPacket::reliable_unordered(addr(1), vec![]));
+        batcher.push(MessageKind::User, LaminarPacket::reliable_unordered(addr(2), vec![4]));
+        batcher.push(MessageKind::User, LaminarPacket::unreliable(addr(1), vec![5]));
+
+        let packets = batcher.flush();
+        assert_eq!(packets.len(), 3);
+        let batch = packets[0].clone();
+        assert_eq!((batch.addr(), batch.payload()[0]), (addr(1), MessageKind::Batch as u8));
+        let messages = unbatch(batch);
+        assert!(messages.iter().all(|(_, packet)| packet.addr() == addr(1)
+            && packet.delivery_guarantee() == DeliveryGuarantee::Reliable
+            && packet.order_guarantee() == OrderingGuarantee::None));
+        assert_eq!(contents(messages), vec![
+            (MessageKind::User, vec![1]),
+            (MessageKind::Channel, vec![2, 3]),
+            (MessageKind::User, vec![]),
+        ]);
+        assert_eq!(packets[1].addr(), addr(2));
+        assert_eq!(packets[2].delivery_guarantee(), DeliveryGuarantee::Unreliable);
+        assert!(batcher.flush().is_empty());
+    }
+
+    #[test]
+    fn batches_split_at_the_mtu() {
+        let mut batcher = batcher(32);
+        for n in 0..5u8 {
+            batcher.push(MessageKind::User, LaminarPacket::reliable_ordered(addr(1), vec![n; 10], None));
+        }
+        // and one that's too big for any batch
+        batcher.push(MessageKind::User, LaminarPacket::reliable_ordered(addr(1), vec![9; 100], None));
+
+        let packets = batcher.flush();
+        // each entry is 12 bytes, so two fit after the batch header
+        let sizes: Vec<usize> = packets.iter().map(|p| p.payload().len()).collect();
+        assert_eq!(sizes, vec![25, 25, 11, 101]);
+        let received: Vec<_> = packets.into_iter().flat_map(unbatch).collect();
+        let mut expected: Vec<_> = (0..5u8).map(|n| (MessageKind::User, vec![n; 10])).collect();
+        expected.push((MessageKind::User, vec![9; 100]));
+        assert_eq!(contents(received), expected);
+    }
+
+    #[test]
+    fn a_lone_message_skips_the_batch_header() {
+        let mut batcher = batcher(1200);
+        batcher.push(MessageKind::RpcRequest, LaminarPacket::reliable_ordered(addr(1), vec![7, 8], Some(3)));
+        let packets = batcher.flush();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].payload(), &[MessageKind::RpcRequest as u8, 7, 8]);
+        assert_eq!(packets[0].order_guarantee(), OrderingGuarantee::Ordered(Some(3)));
+        assert_eq!(contents(unbatch(packets[0].clone())), vec![(MessageKind::RpcRequest, vec![7, 8])]);
+    }
+
+    #[test]
+    fn malformed_batches_keep_what_came_before() {
+        let good = [2, MessageKind::User as u8, 1];
+        let expected = vec![(MessageKind::User, vec![1])];
+        // a varint that never ends, or overflows
+        let endless = [&good[..], &[0x80, 0x80]].concat();
+        let overflow = [&good[..], &[0xff; 11], &[0x01]].concat();
+        // a length running past the end, a zero length, and a cut off entry
+        let too_long = [&good[..], &[5, MessageKind::User as u8, 1]].concat();
+        let zero = [&good[..], &[0], &good[..]].concat();
+        for body in &[endless, overflow, too_long, zero] {
+            assert_eq!(contents(unbatch(batch_of(body))), expected, "{:?}", body);
+        }
+        // entries with unknown kinds and nested batches are skipped
+        let nested = [&[3, MessageKind::Batch as u8, 0, 0][..], &good, &[1, 0xee], &good].concat();
+        assert_eq!(contents(unbatch(batch_of(&nested))), vec![(MessageKind::User, vec![1]); 2]);
+        assert!(unbatch(batch_of(&[])).is_empty());
+        assert!(unbatch(LaminarPacket::unreliable(addr(1), vec![])).is_empty());
+    }
+}
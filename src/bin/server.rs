@@ -0,0 +1,175 @@
+// Headless server, configured from a TOML or RON file instead of a copy of examples/server.rs.
+//
+//  cargo run --features server-bin --bin bevy_naia_laminar-server -- server.toml
+//
+// Game logic comes in as dynamic plugins (built with bevy's DynamicPlugin derive), listed under
+// `plugins` in the config.
+
+use bevy::app::ScheduleRunnerSettings;
+use bevy::core::Timer;
+use bevy::log::{self, LogPlugin};
+use bevy::prelude::*;
+
+use serde::Deserialize;
+
+use bevy_naia_laminar::prelude::*;
+use bevy_naia_laminar::server::prelude::*;
+
+use std::{fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ServerConfig {
+    bind: SocketAddr,
+    /// defaults to the bind address with the port + 1
+    webrtc_listen: Option<SocketAddr>,
+    /// defaults to webrtc_listen
+    public_webrtc: Option<SocketAddr>,
+    tick_rate: f64,
+    idle_timeout_ms: u64,
+    heartbeat_interval_ms: Option<u64>,
+    max_packets_in_flight: u16,
+    max_peers: Option<usize>,
+    /// seconds between status lines, 0 turns them off
+    status_interval: f32,
+    ban_list: Option<PathBuf>,
+    link_conditioner: Option<ConditionerConfig>,
+    /// dynamic game logic plugins, loaded in order
+    plugins: Vec<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:14191".parse().unwrap(),
+            webrtc_listen: None,
+            public_webrtc: None,
+            tick_rate: 60.0,
+            idle_timeout_ms: 5000,
+            heartbeat_interval_ms: Some(1000),
+            max_packets_in_flight: 512,
+            max_peers: None,
+            status_interval: 30.0,
+            ban_list: None,
+            link_conditioner: None,
+            plugins: Vec::new(),
+        }
+    }
+}
+
+// applied to both directions
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConditionerConfig {
+    latency_ms: u64,
+    jitter_ms: u64,
+    loss: f32,
+    corruption: f32,
+}
+
+impl ServerConfig {
+    fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        if path.ends_with(".ron") {
+            ron::de::from_str(&text).map_err(|err| err.to_string())
+        } else {
+            toml::from_str(&text).map_err(|err| err.to_string())
+        }
+    }
+
+    fn network_settings(&self) -> NetworkSettings {
+        NetworkSettings {
+            laminar: LaminarSettings {
+                idle_connection_timeout_ms: self.idle_timeout_ms,
+                heartbeat_interval_ms: self.heartbeat_interval_ms,
+                max_packets_in_flight: self.max_packets_in_flight,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+struct StatusTimer(Timer);
+
+fn main() {
+    let path = std::env::args().nth(1).unwrap_or_else(|| "server.toml".to_string());
+    let config = match ServerConfig::load(&path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Can't load config {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+
+    let net_plugin = ServerNetworkingPlugin {
+        ban_list: config.ban_list.clone(),
+        ..Default::default()
+    };
+
+    let mut app = App::build();
+    app
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / config.tick_rate,
+        )))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin)
+        .insert_resource(config.network_settings())
+        .add_plugin(net_plugin)
+        .insert_resource(StatusTimer(Timer::from_seconds(config.status_interval, true)))
+        .add_startup_system(startup.system())
+        .add_system(log_status.system());
+
+    for plugin_path in &config.plugins {
+        log::info!("Loading plugin {:?}", plugin_path);
+        // the library has to stay loaded for as long as the app runs
+        let (library, plugin) = unsafe {
+            bevy::dynamic_plugin::dynamically_load_plugin(&plugin_path.to_string_lossy())
+        };
+        std::mem::forget(library);
+        plugin.build(&mut app);
+    }
+
+    app.insert_resource(config).run();
+}
+
+fn startup(config: Res<ServerConfig>, mut net: ResMut<NetworkResource>) {
+    net.set_max_peers(config.max_peers);
+    if let Some(conditioner) = &config.link_conditioner {
+        let profile = LinkProfile {
+            latency: Duration::from_millis(conditioner.latency_ms),
+            jitter: Duration::from_millis(conditioner.jitter_ms),
+            loss: conditioner.loss,
+            corruption: conditioner.corruption,
+            ..Default::default()
+        };
+        net.link_conditioner().set_profiles(LinkProfiles {
+            incoming: Some(profile),
+            outgoing: Some(profile),
+        });
+    }
+
+    log::info!("Listening on {}", config.bind);
+    net.listen_with_settings(config.bind, config.webrtc_listen, config.public_webrtc);
+}
+
+fn log_status(
+    time: Res<Time>,
+    config: Res<ServerConfig>,
+    mut timer: ResMut<StatusTimer>,
+    net: Res<NetworkResource>,
+) {
+    if config.status_interval <= 0.0 || !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let connected = net.peers().filter(|peer| peer.state() == ConnectionState::Connected).count();
+    let max = config.max_peers.map_or_else(|| "-".to_string(), |max| max.to_string());
+    log::info!(
+        "Status: {} peers ({} connected, {} other), max {}, tick {}",
+        net.num_peers(),
+        connected,
+        net.num_peers() - connected,
+        max,
+        net.tick(),
+    );
+}
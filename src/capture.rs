@@ -0,0 +1,499 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::log;
+use instant::Instant;
+use laminar::{DeliveryGuarantee, OrderingGuarantee, Packet as LaminarPacket};
+
+use crate::prelude::*;
+use crate::protocol::{self, MessageKind};
+use crate::PeerEventWriters;
+
+// Capture files record every message in and out, and peer status changes. Messages are kept as
+// they are on the wire, MessageKind header byte and all, so user payloads, channels, rpc,
+// transfers, snapshots, lobby and relay traffic are all there, incoming ones as a batch if they
+// arrived as one. Empty handshake packets aren't messages, and aren't recorded.
+//
+//  file:   [magic: 8 bytes] record*
+//  record: [kind: u8][frame: u32][time_us: u64][addr] body
+//  addr:   [4][ip: 4 bytes][port: u16] or [6][ip: 16 bytes][port: u16]
+//  body for packets: [delivery: u8][ordering: u8][stream_id: u8, 255 = none][len: u32][payload]
+//  body for status:  [state: u8]
+//
+// frame counts laminar_receiver runs since recording started, so a replay can release records
+// frame by frame instead of in real time. Replays feed incoming messages back through the
+// receiver's usual dispatch, and status records stand in for laminar's connection events.
+
+const MAGIC: &[u8; 8] = b"BNLCAP02";
+
+const KIND_INCOMING: u8 = 0;
+const KIND_OUTGOING: u8 = 1;
+const KIND_STATUS: u8 = 2;
+
+/// Plugin option: record this session to a file, or replay a recording instead of networking.
+#[derive(Debug, Clone)]
+pub enum CaptureMode {
+    Record(PathBuf),
+    /// `paced` replays in real time, otherwise one recorded frame is released per frame.
+    Replay { path: PathBuf, paced: bool },
+}
+
+#[derive(Debug, Clone)]
+pub enum CaptureEvent {
+    /// a message we received, with its header, from the peer's handle
+    Incoming(LaminarPacket),
+    /// a message we sent, with its header, to the peer's handle
+    Outgoing(LaminarPacket),
+    Status(PeerHandle, ConnectionState),
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    pub frame: u32,
+    /// since recording started
+    pub time: Duration,
+    pub event: CaptureEvent,
+}
+
+/// writes capture records to any writer, usually a file
+pub struct PacketRecorder {
+    writer: Box<dyn Write + Send + Sync>,
+    epoch: Instant,
+    frame: u32,
+}
+
+impl PacketRecorder {
+    pub fn new(mut writer: Box<dyn Write + Send + Sync>) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        Ok(Self {
+            writer,
+            epoch: Instant::now(),
+            frame: 0,
+        })
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub(crate) fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    pub(crate) fn record_incoming(&mut self, packet: &LaminarPacket) -> io::Result<()> {
+        self.write(&CaptureEvent::Incoming(packet.clone()))
+    }
+
+    pub(crate) fn record_outgoing(&mut self, packet: &LaminarPacket) -> io::Result<()> {
+        self.write(&CaptureEvent::Outgoing(packet.clone()))
+    }
+
+    pub(crate) fn record_status(&mut self, handle: PeerHandle, state: ConnectionState) -> io::Result<()> {
+        self.write(&CaptureEvent::Status(handle, state))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write(&mut self, event: &CaptureEvent) -> io::Result<()> {
+        let mut buf = Vec::new();
+        let (kind, addr) = match event {
+            CaptureEvent::Incoming(packet) => (KIND_INCOMING, packet.addr()),
+            CaptureEvent::Outgoing(packet) => (KIND_OUTGOING, packet.addr()),
+            CaptureEvent::Status(handle, _) => (KIND_STATUS, *handle),
+        };
+        buf.push(kind);
+        buf.extend_from_slice(&self.frame.to_le_bytes());
+        let time_us = (Instant::now() - self.epoch).as_micros() as u64;
+        buf.extend_from_slice(&time_us.to_le_bytes());
+        protocol::write_addr(&mut buf, addr);
+        match event {
+            CaptureEvent::Incoming(packet) | CaptureEvent::Outgoing(packet) => {
+                let (ordering, stream_id) = match packet.order_guarantee() {
+                    OrderingGuarantee::None => (0, None),
+                    OrderingGuarantee::Sequenced(stream_id) => (1, stream_id),
+                    OrderingGuarantee::Ordered(stream_id) => (2, stream_id),
+                };
+                let delivery = match packet.delivery_guarantee() {
+                    DeliveryGuarantee::Unreliable => 0,
+                    DeliveryGuarantee::Reliable => 1,
+                };
+                buf.push(delivery);
+                buf.push(ordering);
+                buf.push(stream_id.unwrap_or(u8::MAX));
+                buf.extend_from_slice(&(packet.payload().len() as u32).to_le_bytes());
+                buf.extend_from_slice(packet.payload());
+            }
+            CaptureEvent::Status(_, state) => buf.push(state_to_u8(*state)),
+        }
+        self.writer.write_all(&buf)
+    }
+}
+
+impl fmt::Debug for PacketRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketRecorder").field("frame", &self.frame).finish()
+    }
+}
+
+impl Drop for PacketRecorder {
+    fn drop(&mut self) {
+        self.flush().unwrap_or_default();
+    }
+}
+
+// shared so server Peers can record their sends too
+pub(crate) type SharedRecorder = Arc<Mutex<Option<PacketRecorder>>>;
+
+fn with_recorder(recorder: &SharedRecorder, f: impl FnOnce(&mut PacketRecorder) -> io::Result<()>) {
+    let mut guard = recorder.lock().unwrap();
+    if let Some(rec) = guard.as_mut() {
+        if let Err(err) = f(rec) {
+            log::error!("Error writing capture, recording stopped: {}", err);
+            *guard = None;
+        }
+    }
+}
+
+pub(crate) fn next_frame(recorder: &SharedRecorder) {
+    with_recorder(recorder, |rec| {
+        rec.next_frame();
+        Ok(())
+    });
+}
+
+/// a message we received, header and all, just before it's dispatched
+pub(crate) fn record_incoming(recorder: &SharedRecorder, packet: &LaminarPacket) {
+    with_recorder(recorder, |rec| rec.record_incoming(packet));
+}
+
+/// a message we're sending, recorded with the header it'll have on the wire
+pub(crate) fn record_outgoing(recorder: &SharedRecorder, kind: MessageKind, packet: &LaminarPacket) {
+    with_recorder(recorder, |rec| rec.record_outgoing(&protocol::wrap(kind, packet.clone())));
+}
+
+/// record a status change, then publish it as a PeerEvent::Status
+pub(crate) fn publish_status(recorder: &SharedRecorder, peer_events: &mut PeerEventWriters, handle: PeerHandle, state: ConnectionState) {
+    with_recorder(recorder, |rec| rec.record_status(handle, state));
+    peer_events.send(PeerEvent::Status(handle, state));
+}
+
+/// publish a state change, and the recorded PeerEvent::Status that goes with it
+pub(crate) fn publish_transition(recorder: &SharedRecorder, peer_events: &mut PeerEventWriters, change: ConnectionStateChanged) {
+    peer_events.send_transition(change);
+    publish_status(recorder, peer_events, change.handle, change.to);
+}
+
+/// reads capture records back, eg. to inspect a recording
+pub struct CaptureReader {
+    reader: Box<dyn Read + Send + Sync>,
+}
+
+impl CaptureReader {
+    pub fn new(mut reader: Box<dyn Read + Send + Sync>) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a capture file"));
+        }
+        Ok(Self { reader })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(Box::new(BufReader::new(File::open(path)?)))
+    }
+
+    /// next record, Ok(None) at the end of the file
+    pub fn next_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut kind = [0u8; 1];
+        match self.reader.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let frame = u32::from_le_bytes(self.read_array()?);
+        let time = Duration::from_micros(u64::from_le_bytes(self.read_array()?));
+        let addr = self.read_addr()?;
+        let event = match kind[0] {
+            KIND_INCOMING | KIND_OUTGOING => {
+                let [delivery, ordering, stream_id] = self.read_array::<3>()?;
+                let stream_id = if stream_id == u8::MAX { None } else { Some(stream_id) };
+                let delivery = match delivery {
+                    0 => DeliveryGuarantee::Unreliable,
+                    _ => DeliveryGuarantee::Reliable,
+                };
+                let ordering = match ordering {
+                    0 => OrderingGuarantee::None,
+                    1 => OrderingGuarantee::Sequenced(stream_id),
+                    _ => OrderingGuarantee::Ordered(stream_id),
+                };
+                let len = u32::from_le_bytes(self.read_array()?) as usize;
+                let mut payload = vec![0; len];
+                self.reader.read_exact(&mut payload)?;
+                let packet = protocol::build(addr, delivery, ordering, payload);
+                if kind[0] == KIND_INCOMING {
+                    CaptureEvent::Incoming(packet)
+                } else {
+                    CaptureEvent::Outgoing(packet)
+                }
+            }
+            KIND_STATUS => {
+                let [state] = self.read_array::<1>()?;
+                let state = state_from_u8(state)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad connection state"))?;
+                CaptureEvent::Status(addr, state)
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad record kind")),
+        };
+        Ok(Some(CaptureRecord { frame, time, event }))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_addr(&mut self) -> io::Result<SocketAddr> {
+        let [family] = self.read_array::<1>()?;
+        let ip = match family {
+            4 => IpAddr::V4(Ipv4Addr::from(self.read_array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(self.read_array::<16>()?)),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad address family")),
+        };
+        let port = u16::from_le_bytes(self.read_array()?);
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+/// feeds a recording back through laminar_receiver, in place of the network
+pub struct PacketReplayer {
+    reader: CaptureReader,
+    next: Option<CaptureRecord>,
+    paced: bool,
+    started: Instant,
+    frame: u32,
+    finished: bool,
+}
+
+impl PacketReplayer {
+    pub fn new(reader: CaptureReader, paced: bool) -> Self {
+        Self {
+            reader,
+            next: None,
+            paced,
+            started: Instant::now(),
+            // recorders count from 1, see next_frame
+            frame: 1,
+            finished: false,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// the incoming messages and status changes due this frame. outgoing records are skipped,
+    /// the replaying app's own systems will be producing those again.
+    pub(crate) fn due_events(&mut self) -> Vec<CaptureEvent> {
+        let mut events = Vec::new();
+        let elapsed = Instant::now() - self.started;
+        while !self.finished {
+            let record = match self.next.take() {
+                Some(record) => record,
+                None => match self.reader.next_record() {
+                    Ok(Some(record)) => record,
+                    Ok(None) => {
+                        log::info!("Replay finished");
+                        self.finished = true;
+                        break;
+                    }
+                    Err(err) => {
+                        log::error!("Error reading capture, stopping replay: {}", err);
+                        self.finished = true;
+                        break;
+                    }
+                },
+            };
+            let due = if self.paced {
+                record.time <= elapsed
+            } else {
+                record.frame <= self.frame
+            };
+            if !due {
+                self.next = Some(record);
+                break;
+            }
+            match record.event {
+                CaptureEvent::Outgoing(_) => {}
+                event => events.push(event),
+            }
+        }
+        self.frame += 1;
+        events
+    }
+}
+
+fn state_to_u8(state: ConnectionState) -> u8 {
+    match state {
+        ConnectionState::Uninitialized => 0,
+        ConnectionState::Connecting => 1,
+        ConnectionState::Connected => 2,
+        ConnectionState::Timeout => 3,
+        ConnectionState::Disconnected => 4,
+        ConnectionState::Disconnecting => 5,
+        ConnectionState::Suspended => 6,
+    }
+}
+
+fn state_from_u8(byte: u8) -> Option<ConnectionState> {
+    match byte {
+        0 => Some(ConnectionState::Uninitialized),
+        1 => Some(ConnectionState::Connecting),
+        2 => Some(ConnectionState::Connected),
+        3 => Some(ConnectionState::Timeout),
+        4 => Some(ConnectionState::Disconnected),
+        5 => Some(ConnectionState::Disconnecting),
+        6 => Some(ConnectionState::Suspended),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a writer we can read back from after the recorder's done with it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reader(bytes: Vec<u8>) -> io::Result<CaptureReader> {
+        CaptureReader::new(Box::new(io::Cursor::new(bytes)))
+    }
+
+    fn guarantees() -> Vec<(DeliveryGuarantee, OrderingGuarantee)> {
+        vec![
+            (DeliveryGuarantee::Unreliable, OrderingGuarantee::None),
+            (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(None)),
+            (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(Some(3))),
+            (DeliveryGuarantee::Reliable, OrderingGuarantee::None),
+            (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(None)),
+            (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(Some(4))),
+            (DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(None)),
+            (DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(Some(254))),
+        ]
+    }
+
+    fn addrs() -> Vec<SocketAddr> {
+        vec![
+            ([192, 168, 1, 20], 7777).into(),
+            "[2001:db8::1]:65535".parse().unwrap(),
+        ]
+    }
+
+    // everything in `events`, recorded one frame each
+    fn record(events: &[CaptureEvent]) -> Vec<u8> {
+        let buf = SharedBuf::default();
+        let mut recorder = PacketRecorder::new(Box::new(buf.clone())).unwrap();
+        for event in events {
+            recorder.next_frame();
+            match event {
+                CaptureEvent::Incoming(packet) => recorder.record_incoming(packet).unwrap(),
+                CaptureEvent::Outgoing(packet) => recorder.record_outgoing(packet).unwrap(),
+                CaptureEvent::Status(handle, state) => recorder.record_status(*handle, *state).unwrap(),
+            }
+        }
+        drop(recorder);
+        let bytes = buf.0.lock().unwrap().clone();
+        bytes
+    }
+
+    fn assert_same_packet(a: &LaminarPacket, b: &LaminarPacket) {
+        assert_eq!(a.addr(), b.addr());
+        assert_eq!(a.payload(), b.payload());
+        assert_eq!(a.delivery_guarantee(), b.delivery_guarantee());
+        assert_eq!(a.order_guarantee(), b.order_guarantee());
+    }
+
+    #[test]
+    fn records_roundtrip() {
+        let mut events = Vec::new();
+        for addr in addrs() {
+            for (delivery, ordering) in guarantees() {
+                let payload = vec![MessageKind::Channel as u8, 1, 2, 3];
+                events.push(CaptureEvent::Incoming(protocol::build(addr, delivery, ordering, payload.clone())));
+                events.push(CaptureEvent::Outgoing(protocol::build(addr, delivery, ordering, payload)));
+            }
+            events.push(CaptureEvent::Status(addr, ConnectionState::Suspended));
+        }
+        let mut reader = reader(record(&events)).unwrap();
+        for (i, event) in events.iter().enumerate() {
+            let record = reader.next_record().unwrap().unwrap();
+            assert_eq!(record.frame, i as u32 + 1);
+            match (event, &record.event) {
+                (CaptureEvent::Incoming(a), CaptureEvent::Incoming(b)) => assert_same_packet(a, b),
+                (CaptureEvent::Outgoing(a), CaptureEvent::Outgoing(b)) => assert_same_packet(a, b),
+                (CaptureEvent::Status(a, a_state), CaptureEvent::Status(b, b_state)) => {
+                    assert_eq!((a, a_state), (b, b_state));
+                },
+                (expected, got) => panic!("expected {:?}, got {:?}", expected, got),
+            }
+        }
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn every_state_roundtrips() {
+        let addr = addrs()[0];
+        let states = [
+            ConnectionState::Uninitialized,
+            ConnectionState::Connecting,
+            ConnectionState::Connected,
+            ConnectionState::Timeout,
+            ConnectionState::Disconnected,
+            ConnectionState::Disconnecting,
+            ConnectionState::Suspended,
+        ];
+        for state in states.iter() {
+            let mut reader = reader(record(&[CaptureEvent::Status(addr, *state)])).unwrap();
+            match reader.next_record().unwrap().unwrap().event {
+                CaptureEvent::Status(handle, got) => assert_eq!((handle, got), (addr, *state)),
+                other => panic!("expected a status, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let packet = protocol::build(addrs()[1], DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(Some(1)), vec![0; 10]);
+        let first = record(&[CaptureEvent::Incoming(packet.clone())]);
+        let both = record(&[CaptureEvent::Incoming(packet.clone()), CaptureEvent::Incoming(packet)]);
+        // anywhere in the second record
+        for len in first.len() + 1..both.len() {
+            let mut reader = reader(both[..len].to_vec()).unwrap();
+            assert!(reader.next_record().unwrap().is_some());
+            assert!(reader.next_record().is_err(), "truncated to {}", len);
+        }
+        assert!(reader(first[..MAGIC.len() - 1].to_vec()).is_err());
+        assert!(reader(b"NOTACAP!".to_vec()).is_err());
+    }
+}
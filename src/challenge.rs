@@ -0,0 +1,334 @@
+use std::convert::TryInto;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use hmac::{Hmac, Mac, NewMac};
+#[cfg(not(target_arch = "wasm32"))]
+use instant::Instant;
+#[cfg(not(target_arch = "wasm32"))]
+use sha2::Sha256;
+
+// Stateless connection challenge, done with raw datagrams underneath laminar, since laminar
+// itself sets up a connection for any new address. The server drops everything from an address
+// until it has echoed back a cookie: an HMAC of its address and a timestamp, under a key that
+// never leaves the server. So a spoofed source can't get past it, and until it does, the server
+// remembers nothing about it.
+//
+//  Hello:     [magic][0][padding up to HELLO_LEN]
+//  Challenge: [magic][1][timestamp: u64][mac: 32 bytes]
+//  Response:  [magic][2][timestamp: u64][mac: 32 bytes]
+//
+// Hellos are padded to at least the size of a challenge, so answering one is never an amplifier.
+// The client repeats its hello until laminar traffic starts flowing, to cope with loss.
+
+const MAGIC: &[u8; 7] = b"BNLCHAL";
+
+const HELLO: u8 = 0;
+const CHALLENGE: u8 = 1;
+const RESPONSE: u8 = 2;
+
+const MAC_LEN: usize = 32;
+const COOKIE_LEN: usize = MAGIC.len() + 1 + 8 + MAC_LEN;
+const HELLO_LEN: usize = COOKIE_LEN;
+
+// seconds a cookie stays valid
+#[cfg(not(target_arch = "wasm32"))]
+const COOKIE_LIFETIME: u64 = 10;
+
+// verified addresses that send nothing for this long have to pass a new challenge, so ones that
+// never make a laminar connection don't stay verified forever. well over laminar's idle timeout,
+// which forgets them anyway once a connection does time out.
+#[cfg(not(target_arch = "wasm32"))]
+const VERIFIED_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Datagram {
+    Hello,
+    Challenge(Cookie),
+    Response(Cookie),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Cookie {
+    timestamp: u64,
+    mac: [u8; MAC_LEN],
+}
+
+/// Some if this is one of our challenge datagrams rather than laminar traffic
+pub(crate) fn parse(payload: &[u8]) -> Option<Datagram> {
+    let rest = payload.strip_prefix(&MAGIC[..])?;
+    let (&kind, body) = rest.split_first()?;
+    let cookie = || -> Option<Cookie> {
+        Some(Cookie {
+            timestamp: u64::from_le_bytes(body.get(0..8)?.try_into().ok()?),
+            mac: body.get(8..8 + MAC_LEN)?.try_into().ok()?,
+        })
+    };
+    match kind {
+        // too short to be answered, but still ours
+        HELLO => Some(Datagram::Hello),
+        CHALLENGE => Some(Datagram::Challenge(cookie()?)),
+        RESPONSE => Some(Datagram::Response(cookie()?)),
+        _ => None,
+    }
+}
+
+pub(crate) fn hello() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HELLO_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.push(HELLO);
+    buf.resize(HELLO_LEN, 0);
+    buf
+}
+
+pub(crate) fn response(cookie: Cookie) -> Vec<u8> {
+    encode(RESPONSE, cookie)
+}
+
+fn encode(kind: u8, cookie: Cookie) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(COOKIE_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.push(kind);
+    buf.extend_from_slice(&cookie.timestamp.to_le_bytes());
+    buf.extend_from_slice(&cookie.mac);
+    buf
+}
+
+/// Server side: the cookie key, and the addresses that have answered a challenge.
+/// Shared between NetworkResource and the datagram socket.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Challenges {
+    state: Arc<Mutex<ChallengeState>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+struct ChallengeState {
+    key: [u8; 32],
+    epoch: Instant,
+    // when we last heard from each verified address
+    verified: HashMap<SocketAddr, Instant>,
+    last_sweep: Instant,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for Challenges {
+    fn default() -> Self {
+        let mut key = [0; 32];
+        getrandom::getrandom(&mut key).expect("no randomness for the challenge key");
+        let now = Instant::now();
+        Self {
+            state: Arc::new(Mutex::new(ChallengeState {
+                key,
+                epoch: now,
+                verified: HashMap::new(),
+                last_sweep: now,
+            })),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Challenges {
+    /// decide what to do with a datagram from `addr`. true passes it on to laminar, otherwise
+    /// it's dropped, after queueing any reply in `reply`.
+    pub(crate) fn admit(&self, addr: SocketAddr, payload: &[u8], now: Instant, reply: &mut Option<Vec<u8>>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.sweep(now);
+        match parse(payload) {
+            Some(Datagram::Hello) if payload.len() >= HELLO_LEN => {
+                let timestamp = state.timestamp(now);
+                let cookie = Cookie {
+                    timestamp,
+                    mac: state.mac(addr, timestamp),
+                };
+                *reply = Some(encode(CHALLENGE, cookie));
+                false
+            }
+            Some(Datagram::Response(cookie)) => {
+                if state.check(addr, cookie, now) {
+                    state.verified.insert(addr, now);
+                }
+                false
+            }
+            Some(_) => false,
+            None => match state.verified.get_mut(&addr) {
+                Some(seen) => {
+                    *seen = now;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// the peer is gone, it has to pass a new challenge to come back
+    pub(crate) fn forget(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().verified.remove(&addr);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ChallengeState {
+    fn timestamp(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_secs()
+    }
+
+    // at most once a second, drop addresses that have gone quiet
+    fn sweep(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_sweep) < Duration::from_secs(1) {
+            return;
+        }
+        self.last_sweep = now;
+        self.verified.retain(|_, seen| now.saturating_duration_since(*seen) < VERIFIED_IDLE);
+    }
+
+    fn hmac(&self, addr: SocketAddr, timestamp: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac takes any key length");
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_le_bytes());
+        mac.update(&timestamp.to_le_bytes());
+        mac
+    }
+
+    fn mac(&self, addr: SocketAddr, timestamp: u64) -> [u8; MAC_LEN] {
+        let mut out = [0; MAC_LEN];
+        out.copy_from_slice(&self.hmac(addr, timestamp).finalize().into_bytes());
+        out
+    }
+
+    fn check(&self, addr: SocketAddr, cookie: Cookie, now: Instant) -> bool {
+        let now = self.timestamp(now);
+        if cookie.timestamp > now || now - cookie.timestamp > COOKIE_LIFETIME {
+            return false;
+        }
+        // constant time comparison
+        self.hmac(addr, cookie.timestamp).verify(&cookie.mac).is_ok()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([203, 0, 113, 7], port).into()
+    }
+
+    // the cookie the server hands `addr` for a hello at `now`
+    fn challenge(challenges: &Challenges, addr: SocketAddr, now: Instant) -> Cookie {
+        let mut reply = None;
+        assert!(!challenges.admit(addr, &hello(), now, &mut reply));
+        match parse(&reply.expect("hello wasn't answered")) {
+            Some(Datagram::Challenge(cookie)) => cookie,
+            other => panic!("expected a challenge, got {:?}", other),
+        }
+    }
+
+    fn respond(challenges: &Challenges, addr: SocketAddr, cookie: Cookie, now: Instant) {
+        let mut reply = None;
+        assert!(!challenges.admit(addr, &response(cookie), now, &mut reply));
+        assert!(reply.is_none());
+    }
+
+    fn laminar(challenges: &Challenges, addr: SocketAddr, now: Instant) -> bool {
+        challenges.admit(addr, b"laminar traffic", now, &mut None)
+    }
+
+    #[test]
+    fn valid_cookie_passes() {
+        let challenges = Challenges::default();
+        let now = Instant::now();
+        assert!(!laminar(&challenges, addr(1), now));
+        let cookie = challenge(&challenges, addr(1), now);
+        respond(&challenges, addr(1), cookie, now + Duration::from_secs(1));
+        assert!(laminar(&challenges, addr(1), now + Duration::from_secs(1)));
+        assert!(!laminar(&challenges, addr(2), now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn cookie_from_another_address_is_rejected() {
+        let challenges = Challenges::default();
+        let now = Instant::now();
+        let cookie = challenge(&challenges, addr(1), now);
+        respond(&challenges, addr(2), cookie, now);
+        assert!(!laminar(&challenges, addr(2), now));
+        respond(&challenges, ([203, 0, 113, 8], 1).into(), cookie, now);
+        assert!(!laminar(&challenges, ([203, 0, 113, 8], 1).into(), now));
+    }
+
+    #[test]
+    fn expired_cookie_is_rejected() {
+        let challenges = Challenges::default();
+        let now = Instant::now();
+        let cookie = challenge(&challenges, addr(1), now);
+        let later = now + Duration::from_secs(COOKIE_LIFETIME + 2);
+        respond(&challenges, addr(1), cookie, later);
+        assert!(!laminar(&challenges, addr(1), later));
+    }
+
+    #[test]
+    fn forged_cookie_is_rejected() {
+        let challenges = Challenges::default();
+        let now = Instant::now();
+        let mut cookie = challenge(&challenges, addr(1), now);
+        cookie.mac[0] ^= 1;
+        respond(&challenges, addr(1), cookie, now);
+        assert!(!laminar(&challenges, addr(1), now));
+    }
+
+    #[test]
+    fn hello_is_as_big_as_challenge() {
+        let challenges = Challenges::default();
+        let mut reply = None;
+        challenges.admit(addr(1), &hello(), Instant::now(), &mut reply);
+        assert_eq!(hello().len(), reply.unwrap().len());
+        assert_eq!(hello().len(), HELLO_LEN);
+    }
+
+    #[test]
+    fn short_hello_is_not_answered() {
+        let challenges = Challenges::default();
+        let mut reply = None;
+        let hello = hello();
+        assert!(!challenges.admit(addr(1), &hello[..HELLO_LEN - 1], Instant::now(), &mut reply));
+        assert!(reply.is_none());
+    }
+
+    #[test]
+    fn quiet_addresses_expire() {
+        let challenges = Challenges::default();
+        let now = Instant::now();
+        let cookie = challenge(&challenges, addr(1), now);
+        respond(&challenges, addr(1), cookie, now);
+
+        // traffic keeps it verified
+        let later = now + VERIFIED_IDLE / 2;
+        assert!(laminar(&challenges, addr(1), later));
+        assert!(laminar(&challenges, addr(1), later + VERIFIED_IDLE / 2 + Duration::from_secs(1)));
+
+        let much_later = later + VERIFIED_IDLE * 2;
+        assert!(!laminar(&challenges, addr(1), much_later));
+    }
+
+    #[test]
+    fn forgotten_addresses_need_a_new_challenge() {
+        let challenges = Challenges::default();
+        let now = Instant::now();
+        let cookie = challenge(&challenges, addr(1), now);
+        respond(&challenges, addr(1), cookie, now);
+        challenges.forget(addr(1));
+        assert!(!laminar(&challenges, addr(1), now));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{convert::TryInto, net::SocketAddr};
+
+use laminar::{OrderingGuarantee, Packet as LaminarPacket};
+
+use crate::PeerHandle;
+use crate::transfer::crc32;
+
+// Named channels, declared once on both plugins (in the same order, so ids match), instead of
+// picking delivery guarantees and magic stream ids at every send site.
+//
+//  Channel:  [channel_id: u8][payload]
+//  Channels: [fingerprint: u32]
+//
+// The server sends its fingerprint with every welcome, and a client whose registry doesn't
+// match disconnects rather than misreading every channel message.
+//
+// Automatic stream ids count down from 254, to stay clear of the low ids raw sends tend to use.
+// Raw sends on a channel's stream are refused with ChannelError::ReservedStream, they'd hold up or
+// knock out its messages.
+
+// laminar's default ordering and sequencing stream, used by unnamed sends
+const DEFAULT_STREAM: u8 = u8::MAX;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ChannelKind {
+    Unreliable,
+    UnreliableSequenced,
+    ReliableUnordered,
+    ReliableOrdered,
+    ReliableSequenced,
+}
+
+impl ChannelKind {
+    fn needs_stream(self) -> bool {
+        !matches!(self, ChannelKind::Unreliable | ChannelKind::ReliableUnordered)
+    }
+}
+
+/// Index into a ChannelRegistry. Ids are assigned in registration order.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct ChannelId(pub u8);
+
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    pub name: String,
+    pub kind: ChannelKind,
+    /// laminar stream for sequenced/ordered channels, None for the others
+    pub stream_id: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChannelError {
+    /// no channel with this id is registered
+    UnknownChannel(ChannelId),
+    /// the connection went away, nothing was sent
+    Disconnected,
+    /// a raw send used this stream id, which belongs to a named channel. nothing was sent
+    ReservedStream(u8),
+}
+
+/// Bevy event, a message that arrived on a named channel
+#[derive(Debug, Clone)]
+pub struct ChannelMessage {
+    pub peer: PeerHandle,
+    pub channel: ChannelId,
+    pub payload: Vec<u8>,
+}
+
+/// The channels both sides agree on. Every sequenced or ordered channel gets its own laminar
+/// stream, and registering a stream id that's already taken panics.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelRegistry {
+    channels: Vec<ChannelConfig>,
+}
+
+impl ChannelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// builder version of `register`
+    pub fn with(mut self, name: &str, kind: ChannelKind) -> Self {
+        self.register(name, kind);
+        self
+    }
+
+    /// builder version of `register_with_stream`
+    pub fn with_stream(mut self, name: &str, kind: ChannelKind, stream_id: u8) -> Self {
+        self.register_with_stream(name, kind, stream_id);
+        self
+    }
+
+    /// add a channel, picking the highest free stream id if it needs one
+    pub fn register(&mut self, name: &str, kind: ChannelKind) -> ChannelId {
+        let stream_id = if kind.needs_stream() {
+            let free = (0..DEFAULT_STREAM).rev().find(|id| !self.stream_taken(*id));
+            Some(free.expect("ran out of laminar stream ids for channels"))
+        } else {
+            None
+        };
+        self.push(name, kind, stream_id)
+    }
+
+    /// add a sequenced or ordered channel on a specific laminar stream.
+    /// register these before any channels with automatic stream ids.
+    pub fn register_with_stream(&mut self, name: &str, kind: ChannelKind, stream_id: u8) -> ChannelId {
+        assert!(kind.needs_stream(), "channel '{}' is {:?}, which doesn't use a stream", name, kind);
+        assert!(stream_id != DEFAULT_STREAM, "stream id {} is laminar's default stream", DEFAULT_STREAM);
+        if let Some(other) = self.channels.iter().find(|c| c.stream_id == Some(stream_id)) {
+            panic!("channel '{}' wants stream id {}, already used by '{}'", name, stream_id, other.name);
+        }
+        self.push(name, kind, Some(stream_id))
+    }
+
+    fn push(&mut self, name: &str, kind: ChannelKind, stream_id: Option<u8>) -> ChannelId {
+        assert!(self.id(name).is_none(), "channel '{}' registered twice", name);
+        assert!(self.channels.len() < u8::MAX as usize + 1, "too many channels");
+        self.channels.push(ChannelConfig {
+            name: name.to_string(),
+            kind,
+            stream_id,
+        });
+        ChannelId((self.channels.len() - 1) as u8)
+    }
+
+    fn stream_taken(&self, stream_id: u8) -> bool {
+        self.channels.iter().any(|c| c.stream_id == Some(stream_id))
+    }
+
+    pub fn id(&self, name: &str) -> Option<ChannelId> {
+        self.channels
+            .iter()
+            .position(|c| c.name == name)
+            .map(|index| ChannelId(index as u8))
+    }
+
+    pub fn get(&self, channel: ChannelId) -> Option<&ChannelConfig> {
+        self.channels.get(channel.0 as usize)
+    }
+
+    pub fn name(&self, channel: ChannelId) -> Option<&str> {
+        self.get(channel).map(|c| c.name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ChannelId, &ChannelConfig)> {
+        self.channels
+            .iter()
+            .enumerate()
+            .map(|(index, config)| (ChannelId(index as u8), config))
+    }
+
+    /// the packet to send `payload` on `channel`, with the channel id header
+    pub(crate) fn packet(&self, channel: ChannelId, addr: SocketAddr, payload: &[u8]) -> Result<LaminarPacket, ChannelError> {
+        let config = self.get(channel).ok_or(ChannelError::UnknownChannel(channel))?;
+        let mut buf = Vec::with_capacity(1 + payload.len());
+        buf.push(channel.0);
+        buf.extend_from_slice(payload);
+        Ok(match config.kind {
+            ChannelKind::Unreliable => LaminarPacket::unreliable(addr, buf),
+            ChannelKind::UnreliableSequenced => LaminarPacket::unreliable_sequenced(addr, buf, config.stream_id),
+            ChannelKind::ReliableUnordered => LaminarPacket::reliable_unordered(addr, buf),
+            ChannelKind::ReliableOrdered => LaminarPacket::reliable_ordered(addr, buf, config.stream_id),
+            ChannelKind::ReliableSequenced => LaminarPacket::reliable_sequenced(addr, buf, config.stream_id),
+        })
+    }
+
+    /// raw sends can't use a stream that belongs to a channel
+    pub(crate) fn check_raw(&self, packet: &LaminarPacket) -> Result<(), ChannelError> {
+        match packet.order_guarantee() {
+            OrderingGuarantee::Sequenced(Some(stream_id)) | OrderingGuarantee::Ordered(Some(stream_id))
+                if self.stream_taken(stream_id) =>
+            {
+                Err(ChannelError::ReservedStream(stream_id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// changes whenever a name, kind, stream id or the order of channels does
+    pub fn fingerprint(&self) -> u32 {
+        let mut buf = Vec::new();
+        for config in &self.channels {
+            buf.extend_from_slice(config.name.as_bytes());
+            buf.push(0);
+            buf.push(config.kind as u8);
+            buf.push(config.stream_id.unwrap_or(DEFAULT_STREAM));
+        }
+        crc32(&buf)
+    }
+
+    /// the Channels message announcing our fingerprint
+    pub(crate) fn announcement(&self) -> Vec<u8> {
+        self.fingerprint().to_le_bytes().to_vec()
+    }
+
+    /// false if the other side's Channels message doesn't match our registry
+    pub(crate) fn matches(&self, announcement: &[u8]) -> bool {
+        announcement.try_into().ok().map(u32::from_le_bytes) == Some(self.fingerprint())
+    }
+
+    /// split a received Channel message back into a ChannelMessage
+    pub(crate) fn message(&self, peer: PeerHandle, bytes: &[u8]) -> Option<ChannelMessage> {
+        let (&channel, payload) = bytes.split_first()?;
+        let channel = ChannelId(channel);
+        self.get(channel)?;
+        Some(ChannelMessage {
+            peer,
+            channel,
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        ([127, 0, 0, 1], 7777).into()
+    }
+
+    fn stream(registry: &ChannelRegistry, name: &str) -> Option<u8> {
+        registry.get(registry.id(name).unwrap()).unwrap().stream_id
+    }
+
+    #[test]
+    fn stream_ids_count_down_from_254() {
+        let registry = ChannelRegistry::new()
+            .with("chat", ChannelKind::ReliableOrdered)
+            .with("events", ChannelKind::ReliableUnordered)
+            .with("movement", ChannelKind::UnreliableSequenced)
+            .with("state", ChannelKind::ReliableSequenced);
+        assert_eq!(stream(&registry, "chat"), Some(254));
+        assert_eq!(stream(&registry, "events"), None);
+        assert_eq!(stream(&registry, "movement"), Some(253));
+        assert_eq!(stream(&registry, "state"), Some(252));
+        assert_eq!(registry.id("movement"), Some(ChannelId(2)));
+    }
+
+    #[test]
+    fn automatic_ids_skip_taken_streams() {
+        let registry = ChannelRegistry::new()
+            .with_stream("fixed", ChannelKind::ReliableOrdered, 254)
+            .with("auto", ChannelKind::ReliableOrdered);
+        assert_eq!(stream(&registry, "auto"), Some(253));
+    }
+
+    #[test]
+    #[should_panic(expected = "ran out of laminar stream ids")]
+    fn running_out_of_stream_ids_panics() {
+        let mut registry = ChannelRegistry::new();
+        for index in 0..DEFAULT_STREAM {
+            registry.register(&format!("channel {}", index), ChannelKind::ReliableOrdered);
+        }
+        assert_eq!(stream(&registry, "channel 254"), Some(0));
+        registry.register("one too many", ChannelKind::ReliableOrdered);
+    }
+
+    #[test]
+    #[should_panic(expected = "already used by 'chat'")]
+    fn clashing_stream_ids_panic() {
+        ChannelRegistry::new()
+            .with_stream("chat", ChannelKind::ReliableOrdered, 3)
+            .with_stream("movement", ChannelKind::UnreliableSequenced, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "registered twice")]
+    fn clashing_names_panic() {
+        ChannelRegistry::new()
+            .with("chat", ChannelKind::ReliableOrdered)
+            .with("chat", ChannelKind::Unreliable);
+    }
+
+    #[test]
+    #[should_panic(expected = "laminar's default stream")]
+    fn the_default_stream_is_not_for_channels() {
+        ChannelRegistry::new().with_stream("chat", ChannelKind::ReliableOrdered, DEFAULT_STREAM);
+    }
+
+    #[test]
+    fn raw_sends_on_channel_streams_are_refused() {
+        let registry = ChannelRegistry::new()
+            .with_stream("chat", ChannelKind::ReliableOrdered, 3)
+            .with("movement", ChannelKind::UnreliableSequenced);
+        let on = |stream_id| LaminarPacket::reliable_ordered(addr(), vec![1], Some(stream_id));
+        assert_eq!(registry.check_raw(&on(3)), Err(ChannelError::ReservedStream(3)));
+        assert_eq!(
+            registry.check_raw(&LaminarPacket::unreliable_sequenced(addr(), vec![1], Some(254))),
+            Err(ChannelError::ReservedStream(254))
+        );
+        assert_eq!(registry.check_raw(&on(4)), Ok(()));
+        assert_eq!(registry.check_raw(&LaminarPacket::reliable_ordered(addr(), vec![1], None)), Ok(()));
+        assert_eq!(registry.check_raw(&LaminarPacket::unreliable(addr(), vec![1])), Ok(()));
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let registry = ChannelRegistry::new().with("chat", ChannelKind::ReliableOrdered);
+        let chat = registry.id("chat").unwrap();
+        let packet = registry.packet(chat, addr(), b"hi").unwrap();
+        assert_eq!(packet.order_guarantee(), OrderingGuarantee::Ordered(Some(254)));
+        let message = registry.message(addr(), packet.payload()).unwrap();
+        assert_eq!((message.channel, message.payload.as_slice()), (chat, &b"hi"[..]));
+        assert_eq!(registry.packet(ChannelId(1), addr(), b"hi").err(), Some(ChannelError::UnknownChannel(ChannelId(1))));
+        assert!(registry.message(addr(), &[1, 0]).is_none());
+        assert!(registry.message(addr(), &[]).is_none());
+    }
+
+    #[test]
+    fn fingerprints_must_match() {
+        let registry = ChannelRegistry::new()
+            .with("chat", ChannelKind::ReliableOrdered)
+            .with("movement", ChannelKind::UnreliableSequenced);
+        let same = registry.clone();
+        assert!(same.matches(&registry.announcement()));
+
+        let reordered = ChannelRegistry::new()
+            .with("movement", ChannelKind::UnreliableSequenced)
+            .with("chat", ChannelKind::ReliableOrdered);
+        let other_kind = ChannelRegistry::new()
+            .with("chat", ChannelKind::ReliableSequenced)
+            .with("movement", ChannelKind::UnreliableSequenced);
+        let other_stream = ChannelRegistry::new()
+            .with_stream("chat", ChannelKind::ReliableOrdered, 9)
+            .with("movement", ChannelKind::UnreliableSequenced);
+        let missing = ChannelRegistry::new().with("chat", ChannelKind::ReliableOrdered);
+        for other in &[reordered, other_kind, other_stream, missing] {
+            assert!(!other.matches(&registry.announcement()));
+            assert!(!registry.matches(&other.announcement()));
+        }
+
+        let announcement = registry.announcement();
+        assert!(!registry.matches(&announcement[..3]));
+        assert!(!registry.matches(&[announcement.as_slice(), &[0]].concat()));
+        assert!(!registry.matches(&[]));
+    }
+}
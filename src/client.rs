@@ -1,10 +1,14 @@
 use bevy::{
     log,
-    app::{AppBuilder, EventWriter, Plugin},
+    app::{AppBuilder, CoreStage, EventWriter, Plugin},
     ecs::prelude::*,
 };
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use instant::Instant;
 
 // "use" with Laminar/Naia prefix as needed, since both have Packets and similar concepts.
@@ -34,9 +38,28 @@ pub mod prelude {
     pub use super::NetworkResource;
     pub use super::ClientNetworkingPlugin;
     pub use laminar::{DeliveryGuarantee, OrderingGuarantee};
+    pub use crate::clock::ServerClock;
 }
 
 use crate::prelude::*;
+use crate::PeerEventWriters;
+use crate::clock::ServerClock;
+use crate::protocol::{self, MessageKind};
+use crate::rpc::{self, RpcTracker};
+use crate::transfer::{self, Transfers};
+use crate::snapshot::SnapshotDecoder;
+use crate::capture::{self, CaptureEvent, SharedRecorder};
+use crate::conditioner::{Direction, LinkConditioner};
+use crate::batch::{self, Batcher};
+use crate::challenge;
+use crate::session::{self, SessionMessage};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::discovery::{DiscoverySearch, DEFAULT_DISCOVERY_PORT};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::query::ServerQueries;
+
+// how often to repeat our challenge hello until the server lets us through
+const CHALLENGE_HELLO_INTERVAL: Duration = Duration::from_millis(500);
 
 // If we want to allow connections to multiple laminar servers, we'll have to expose PeerConnections.
 // for now we just support connecting to 1 server, and expose everything through NetworkResource functions
@@ -47,7 +70,12 @@ struct PeerConnection {
     laminar_vconnection: LaminarVirtualConnection,
     laminar_messenger: LaminarConnectionMessengerForNaia,
     laminar_event_receiver: Receiver<ReceiveEvent>,
-    connection_state: ConnectionState,
+    connection_state: ConnectionStateMachine,
+    // we keep saying hello to the server's challenge layer until laminar traffic comes back
+    challenge_passed: bool,
+    last_hello: Instant,
+    // our laminar hello: empty, or a resume hello with our session token
+    hello: Vec<u8>,
     // housekeeping: Housekeeping,
 }
 
@@ -56,6 +84,8 @@ impl PeerConnection {
         config: LaminarConfig,
         mut naia_socket: Box<dyn NaiaClientSocketTrait>,
         server_socket_address: &SocketAddr,
+        conditioner: LinkConditioner,
+        hello: Vec<u8>,
     ) -> Self {
 
         let naia_message_sender = naia_socket.get_sender();
@@ -66,6 +96,7 @@ impl PeerConnection {
             config,
             naia_message_sender,
             laminar_event_sender,
+            conditioner,
         };
         
         let laminar_vconnection = LaminarVirtualConnection::create_connection(
@@ -80,14 +111,33 @@ impl PeerConnection {
             laminar_vconnection,
             laminar_messenger,
             laminar_event_receiver,
-            connection_state: ConnectionState::Connecting,
+            connection_state: ConnectionStateMachine::default(),
+            challenge_passed: false,
+            last_hello: Instant::now(),
+            hello,
             // housekeeping: Housekeeping::default(),
         };
-        // send an empty packet, which the server will respond to, to setup the connection
-        pc.send(pc.reliable_unordered_packet(vec![]));
+        pc.send_challenge_hello(Instant::now());
+        // say hello, which the server will respond to, to setup the connection
+        pc.send_hello();
         pc
     }
 
+    fn send_hello(&mut self) {
+        self.send(self.reliable_unordered_packet(self.hello.clone()));
+    }
+
+    // raw datagrams for the server's connection challenge, underneath laminar
+    fn send_raw(&mut self, payload: &[u8]) {
+        let addr = self.server_addr;
+        LaminarConnectionMessenger::send_packet(&mut self.laminar_messenger, &addr, payload);
+    }
+
+    fn send_challenge_hello(&mut self, time: Instant) {
+        self.last_hello = time;
+        self.send_raw(&challenge::hello());
+    }
+
     // wrap the laminar packet constructors, but provide our server addr automatically as the dst
     pub fn unreliable_packet(&self, payload: Vec<u8>) -> LaminarPacket {
         LaminarPacket::unreliable(*self.server_addr(), payload)
@@ -106,11 +156,11 @@ impl PeerConnection {
     }
 
     pub fn state(&self) -> ConnectionState {
-        self.connection_state
+        self.connection_state.state()
     }
 
-    fn set_state(&mut self, new_state: ConnectionState) {
-        self.connection_state = new_state;
+    fn transition(&mut self, to: ConnectionState, cause: TransitionCause) -> Option<ConnectionStateChanged> {
+        self.connection_state.transition(self.server_addr, to, cause)
     }
 
     /// gets laminar event receiver
@@ -129,13 +179,17 @@ impl PeerConnection {
     }
 
     /// recv incoming packets from naia, and hand on to laminar
-    pub fn poll(&mut self, time: Instant) {
+    pub fn receive(&mut self, time: Instant) {
+        let conditioner = self.laminar_messenger.conditioner.clone();
         loop {
             match self.naia_socket.receive() {
                 Ok(event) => match event {
                     Some(packet) => {
                         // log::info!("process_incoming: {:?}", String::from_utf8_lossy(packet.payload()));
-                        self.laminar_vconnection.process_packet(&mut self.laminar_messenger, packet.payload(), time);
+                        let conditioned = conditioner.condition(Direction::Incoming, self.server_addr, packet.payload().to_vec(), time);
+                        if let Some(payload) = conditioned {
+                            self.process_datagram(&payload, time);
+                        }
                     },
                     None => {
                         break;
@@ -146,9 +200,37 @@ impl PeerConnection {
                 }
             }
         }
-        // TODO should perhaps separate the following call?
-        //      maybe poll_recv in preupdate and then vconnection update in post?
+        // delayed, reordered or duplicated datagrams that are due now
+        while let Some((_, payload)) = conditioner.release(Direction::Incoming, time) {
+            self.process_datagram(&payload, time);
+        }
+    }
+
+    fn process_datagram(&mut self, payload: &[u8], time: Instant) {
+        match challenge::parse(payload) {
+            Some(challenge::Datagram::Challenge(cookie)) => {
+                // echo the cookie, then say hello to laminar again now that it'll get through
+                self.send_raw(&challenge::response(cookie));
+                self.send_hello();
+            },
+            Some(_) => {},
+            None => {
+                self.challenge_passed = true;
+                self.laminar_vconnection.process_packet(&mut self.laminar_messenger, payload, time);
+            },
+        }
+    }
+
+    /// let laminar resend, ack and heartbeat, and send anything the conditioner has released
+    pub fn update(&mut self, time: Instant) {
+        if !self.challenge_passed && time - self.last_hello >= CHALLENGE_HELLO_INTERVAL {
+            self.send_challenge_hello(time);
+        }
         self.laminar_vconnection.update(&mut self.laminar_messenger, time);
+        let conditioner = self.laminar_messenger.conditioner.clone();
+        while let Some((_, payload)) = conditioner.release(Direction::Outgoing, time) {
+            self.laminar_messenger.send_to_naia(payload);
+        }
     }
 }
 
@@ -163,17 +245,74 @@ unsafe impl Sync for PeerConnection {}
 #[derive(Default)]
 pub struct ClientNetworkingPlugin {
     pub link_conditioner: Option<LinkConditionerConfig>,
+    pub capture: Option<CaptureMode>,
+    /// named channels, must be declared identically on client and server
+    pub channels: ChannelRegistry,
+    /// coalesce small messages into fewer datagrams, off by default
+    pub batching: Option<BatchConfig>,
+    /// keep a bevy State<ConnectionState> in step with connection_state(), so
+    /// SystemSet::on_enter(ConnectionState::Connected) and friends work. off by default
+    pub connection_state: bool,
 }
 
 impl Plugin for ClientNetworkingPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        let net_resource = NetworkResource::new(
-            self.link_conditioner.clone(),
+        // a NetworkSettings resource inserted before the plugin wins over link_conditioner
+        let settings = app.world().get_resource::<NetworkSettings>().cloned().unwrap_or_else(|| NetworkSettings {
+            link_conditioner: self.link_conditioner.as_ref().map(Into::into),
+            ..Default::default()
+        });
+
+        let mut net_resource = NetworkResource::new(
+            settings.link_conditioner_config(),
         );
+        net_resource.settings = settings.clone();
+        net_resource.set_channels(self.channels.clone());
+        net_resource.set_batching(self.batching.clone());
+        match &self.capture {
+            Some(CaptureMode::Record(path)) => match PacketRecorder::create(path) {
+                Ok(recorder) => net_resource.start_recording(recorder),
+                Err(err) => log::error!("Can't record to {:?}: {}", path, err),
+            },
+            Some(CaptureMode::Replay { path, paced }) => match CaptureReader::open(path) {
+                Ok(reader) => net_resource.start_replay(PacketReplayer::new(reader, *paced)),
+                Err(err) => log::error!("Can't replay {:?}: {}", path, err),
+            },
+            None => {},
+        }
         app
         .add_event::<PeerEvent>()
+        .add_event::<PeerConnected>()
+        .add_event::<PeerDisconnected>()
+        .add_event::<PeerTimedOut>()
+        .add_event::<ConnectionStateChanged>()
+        .add_event::<MessageReceived>()
+        .add_event::<ChannelMessage>()
+        .add_event::<RpcRequest>()
+        .add_event::<RpcResponse>()
+        .add_event::<TransferProgress>()
+        .add_event::<TransferCompleted>()
+        .add_event::<TransferFailed>()
+        .add_event::<SnapshotReceived>()
         .insert_resource(net_resource)
-        .add_system(laminar_poller.system())
+        .insert_resource(settings)
+        .init_resource::<ServerClock>()
+        .add_system_to_stage(CoreStage::PreUpdate, apply_network_settings.system().before(NetworkSystem::Receive))
+        .add_system_to_stage(CoreStage::PreUpdate, laminar_receiver.system().label(NetworkSystem::Receive))
+        .add_system_to_stage(CoreStage::PostUpdate, laminar_flusher.system().label(NetworkSystem::Flush))
+        ;
+        if self.connection_state {
+            app
+            .add_state(ConnectionState::Uninitialized)
+            .add_system_to_stage(CoreStage::PreUpdate, sync_connection_state.system().after(NetworkSystem::Receive))
+            ;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        app
+        .add_event::<ServerDiscovered>()
+        .add_event::<ServerInfoReceived>()
+        .add_system_to_stage(CoreStage::PreUpdate, discovery_receiver.system())
+        .add_system_to_stage(CoreStage::PreUpdate, query_receiver.system())
         ;
     }
 }
@@ -184,6 +323,15 @@ struct LaminarConnectionMessengerForNaia {
     config: LaminarConfig,
     naia_message_sender: NaiaMessageSender,
     laminar_event_sender: Sender<ReceiveEvent>,
+    conditioner: LinkConditioner,
+}
+
+impl LaminarConnectionMessengerForNaia {
+    fn send_to_naia(&mut self, payload: Vec<u8>) {
+        self.naia_message_sender
+            .send(NaiaPacket::new(payload))
+            .expect("send packet error");
+    }
 }
 
 impl LaminarConnectionMessenger<ReceiveEvent> for LaminarConnectionMessengerForNaia {
@@ -198,11 +346,17 @@ impl LaminarConnectionMessenger<ReceiveEvent> for LaminarConnectionMessengerForN
     }
 
     // sends packet
-    fn send_packet(&mut self, _address: &SocketAddr, payload: &[u8]) {
+    fn send_packet(&mut self, address: &SocketAddr, payload: &[u8]) {
         // log::info!("PacketMessenger::send_packet {}", payload.len());
-        self.naia_message_sender
-            .send(NaiaPacket::new(payload.to_vec()))
-            .expect("send packet error");
+        let now = Instant::now();
+        let conditioned = self.conditioner.condition(Direction::Outgoing, *address, payload.to_vec(), now);
+        if let Some(payload) = conditioned {
+            self.send_to_naia(payload);
+        }
+        // anything the conditioner didn't delay, or delayed datagrams that are now due
+        while let Some((_, payload)) = self.conditioner.release(Direction::Outgoing, now) {
+            self.send_to_naia(payload);
+        }
     }
 }
 
@@ -210,7 +364,29 @@ impl LaminarConnectionMessenger<ReceiveEvent> for LaminarConnectionMessengerForN
 #[derive(Default)]
 pub struct NetworkResource {
     connection: Option<PeerConnection>,
-    link_conditioner: Option<LinkConditionerConfig>,
+    conditioner: LinkConditioner,
+    rpc: RpcTracker,
+    transfers: Transfers,
+    snapshots: SnapshotDecoder,
+    recorder: SharedRecorder,
+    replayer: Option<PacketReplayer>,
+    replay_state: Option<ConnectionState>,
+    channels: ChannelRegistry,
+    batcher: Batcher,
+    // state changes made outside laminar_receiver, published on its next run
+    transitions: Vec<ConnectionStateChanged>,
+    // last NetworkSettings applied
+    settings: NetworkSettings,
+    // lobby messages for the lobby plugin, cleared every frame in case there isn't one
+    pub(crate) lobby_inbox: Vec<(PeerHandle, Vec<u8>)>,
+    // same for the relay plugins, whole packets since relayed ones keep their guarantees
+    pub(crate) relay_inbox: Vec<LaminarPacket>,
+    // the server that gave us a session token, and the token
+    session: Option<(SocketAddr, SessionToken)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    discovery: Option<DiscoverySearch>,
+    #[cfg(not(target_arch = "wasm32"))]
+    queries: ServerQueries,
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -226,8 +402,28 @@ impl NetworkResource {
     pub fn new(link_conditioner: Option<LinkConditionerConfig>) -> Self
     {
         Self {
-            link_conditioner,
+            conditioner: LinkConditioner::from_naia(link_conditioner.as_ref()),
             connection: None,
+            rpc: RpcTracker::default(),
+            transfers: Transfers::default(),
+            snapshots: SnapshotDecoder::default(),
+            recorder: Arc::new(Mutex::new(None)),
+            replayer: None,
+            replay_state: None,
+            channels: ChannelRegistry::default(),
+            batcher: Batcher::default(),
+            transitions: Vec::new(),
+            settings: NetworkSettings {
+                link_conditioner: link_conditioner.as_ref().map(Into::into),
+                ..Default::default()
+            },
+            lobby_inbox: Vec::new(),
+            relay_inbox: Vec::new(),
+            session: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            discovery: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            queries: ServerQueries::default(),
         }
     }
 
@@ -237,7 +433,9 @@ impl NetworkResource {
     }
 
     pub fn connection_state(&self) -> ConnectionState {
-        if self.initialized() {
+        if let Some(state) = self.replay_state {
+            state
+        } else if self.initialized() {
             self.connection().state()
         } else {
             ConnectionState::Uninitialized
@@ -256,45 +454,266 @@ impl NetworkResource {
         self.connection().server_addr()
     }
 
-    /// send a LaminarPacket
+    /// send a LaminarPacket. sends on a named channel's stream are logged and dropped, use send_on
+    /// for those
     pub fn send(&mut self, packet: LaminarPacket) {
+        match self.channels.check_raw(&packet) {
+            Ok(()) => self.send_internal(MessageKind::User, packet),
+            Err(err) => log::error!("Dropped a send to {}: {:?}", packet.addr(), err),
+        }
+    }
+
+    /// send a payload on a named channel, with that channel's guarantees
+    pub fn send_on(&mut self, channel: ChannelId, payload: Vec<u8>) -> Result<(), ChannelError> {
         assert!(self.initialized(), "not initialized!");
-        self.connection_mut().send(packet);
+        let packet = self.channels.packet(channel, *self.server_addr(), &payload)?;
+        self.send_internal(MessageKind::Channel, packet);
+        Ok(())
+    }
+
+    pub fn channels(&self) -> &ChannelRegistry {
+        &self.channels
     }
 
-    /// calls laminar's manual_poll - do per tick
+    /// the ClientNetworkingPlugin sets this from its `channels`
+    pub fn set_channels(&mut self, channels: ChannelRegistry) {
+        self.channels = channels;
+    }
+
+    /// coalesce small messages into fewer datagrams when flushing.
+    /// None sends every message as soon as it's sent.
+    pub fn set_batching(&mut self, config: Option<BatchConfig>) {
+        self.batcher.set_config(config);
+    }
+
+    pub fn batching(&self) -> Option<BatchConfig> {
+        self.batcher.config().cloned()
+    }
+
+    pub(crate) fn send_internal(&mut self, kind: MessageKind, packet: LaminarPacket) {
+        if self.replaying() {
+            return;
+        }
+        assert!(self.initialized(), "not initialized!");
+        capture::record_outgoing(&self.recorder, kind, &packet);
+        if let Some(packet) = self.batcher.push(kind, packet) {
+            self.connection_mut().send(packet);
+        }
+    }
+
+    // straight to laminar, skipping the batcher
+    fn send_now(&mut self, kind: MessageKind, packet: LaminarPacket) {
+        if self.replaying() {
+            return;
+        }
+        capture::record_outgoing(&self.recorder, kind, &packet);
+        self.connection_mut().send(protocol::wrap(kind, packet));
+    }
+
+    /// receive and update in one go. the plugin's systems do these separately, in
+    /// PreUpdate and PostUpdate, so there's no need to call this yourself.
     pub fn poll(&mut self) {
         assert!(self.initialized(), "not initialized!");
         let now = Instant::now();
-        self.connection_mut().poll(now);
+        self.connection_mut().receive(now);
+        self.connection_mut().update(now);
+    }
+
+    /// send an rpc request to the server. the returned handle will show up in an RpcResponse
+    /// event, with either the server's reply or an RpcError. Err if the method name is too long.
+    pub fn request(&mut self, method: &str, payload: Vec<u8>, timeout: Duration) -> Result<RpcHandle, RpcError> {
+        assert!(self.initialized(), "not initialized!");
+        rpc::check_method(method)?;
+        let server_addr = *self.server_addr();
+        let handle = self.rpc.start(server_addr, timeout, Instant::now());
+        let packet = LaminarPacket::reliable_unordered(
+            server_addr,
+            rpc::encode_request(handle.id(), method, &payload),
+        );
+        self.send_internal(MessageKind::RpcRequest, packet);
+        Ok(handle)
+    }
+
+    /// reply to an RpcRequest the server sent us
+    pub fn respond(&mut self, handle: RpcHandle, payload: Vec<u8>) {
+        let packet = LaminarPacket::reliable_unordered(
+            handle.peer(),
+            rpc::encode_response(handle.id(), &payload),
+        );
+        self.send_internal(MessageKind::RpcResponse, packet);
+    }
+
+    /// start sending a blob to the server in chunks. progress and the outcome arrive as
+    /// TransferProgress, TransferCompleted and TransferFailed events.
+    pub fn send_blob(&mut self, name: &str, data: Vec<u8>) -> Result<TransferHandle, TransferError> {
+        assert!(self.initialized(), "not initialized!");
+        let server_addr = *self.server_addr();
+        self.transfers.start(server_addr, name, data, Instant::now())
+    }
+
+    /// abort a transfer in either direction, both sides get a TransferFailed event
+    pub fn cancel_transfer(&mut self, handle: TransferHandle) {
+        self.transfers.cancel(handle);
+    }
+
+    /// chunk size, window, timeouts and size limit for blob transfers
+    pub fn transfer_config_mut(&mut self) -> &mut TransferConfig {
+        &mut self.transfers.config
+    }
+
+    /// adjust simulated latency, jitter, loss and corruption while running
+    pub fn link_conditioner(&self) -> &LinkConditioner {
+        &self.conditioner
+    }
+
+    /// record every message in and out, and connection status changes, until stop_recording is called
+    pub fn start_recording(&mut self, recorder: PacketRecorder) {
+        *self.recorder.lock().unwrap() = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) {
+        *self.recorder.lock().unwrap() = None;
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recorder.lock().unwrap().is_some()
+    }
+
+    /// feed a recording through laminar_receiver instead of the network. don't call connect(),
+    /// sends are silently dropped while replaying.
+    pub fn start_replay(&mut self, replayer: PacketReplayer) {
+        self.replayer = Some(replayer);
+        self.replay_state = Some(ConnectionState::Uninitialized);
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.replayer.is_some()
     }
 
     pub fn connect_with_defaults(&mut self, socket_address: SocketAddr) {
         self.connect(socket_address, LaminarConfig::default());
     }
 
+    /// broadcast for servers on the LAN, native only. Each one that answers in the next couple of
+    /// seconds shows up as a ServerDiscovered event. Doesn't need connect() first.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn discover_servers(&mut self) -> std::io::Result<()> {
+        self.discover_servers_on(DEFAULT_DISCOVERY_PORT)
+    }
+
+    /// discover_servers() for servers answering on a different port
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn discover_servers_on(&mut self, port: u16) -> std::io::Result<()> {
+        self.discovery = Some(DiscoverySearch::start(port)?);
+        Ok(())
+    }
+
+    /// true until the last discover_servers() stops listening for answers
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn discovering(&self) -> bool {
+        self.discovery.is_some()
+    }
+
+    /// ask a server for its ServerInfo and player counts without connecting, native only. The
+    /// answer shows up as a ServerInfoReceived event with the round trip time, and servers that
+    /// don't answer within a couple of seconds are given up on. Doesn't need connect() first.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn query_server(&mut self, addr: SocketAddr) -> std::io::Result<()> {
+        self.queries.start(addr, Instant::now())
+    }
+
+    /// true until `addr` answers query_server(), or we give up on it
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn querying(&self, addr: SocketAddr) -> bool {
+        self.queries.is_pending(addr)
+    }
+
+    /// connect() with the laminar config from settings()
+    pub fn connect_with_settings(&mut self, socket_address: SocketAddr) {
+        let config = self.settings.laminar_config();
+        self.connect(socket_address, config);
+    }
+
+    /// the NetworkSettings in use, see apply_settings
+    pub fn settings(&self) -> &NetworkSettings {
+        &self.settings
+    }
+
+    /// switch to new settings. the conditioner's incoming profile changes right away if it
+    /// differs, the laminar config is used by the next connect_with_settings().
+    /// apply_network_settings does this whenever the NetworkSettings resource changes.
+    pub fn apply_settings(&mut self, settings: NetworkSettings) {
+        if settings.link_conditioner != self.settings.link_conditioner {
+            let profile = settings.link_conditioner_config().map(|config| LinkProfile::from_naia(&config));
+            self.conditioner.set_incoming(profile);
+        }
+        self.settings = settings;
+    }
+
+    /// the token for resuming our session, once a server with a session grace period has given
+    /// us one. connect() to the same server while it's valid, even from a new address, and we
+    /// carry on as the same peer. otherwise we connect as a new peer and get a new token.
+    pub fn session_token(&self) -> Option<SessionToken> {
+        self.session.map(|(_, token)| token)
+    }
+
+    /// resume a session from a token kept elsewhere, eg. across a restart, on the next connect()
+    /// to `server`
+    pub fn set_session_token(&mut self, server: SocketAddr, token: SessionToken) {
+        self.session = Some((server, token));
+    }
+
+    /// connect() as a new peer next time
+    pub fn forget_session(&mut self) {
+        self.session = None;
+    }
+
     /// connect to server. sets initialized() to true.
     pub fn connect(&mut self, socket_address: SocketAddr, config: LaminarConfig) {
-        let naia_socket = {
-            let socket = NaiaSocket::connect(socket_address);
-
-            if let Some(ref conditioner) = self.link_conditioner {
-                socket.with_link_conditioner(conditioner)
-            } else {
-                socket
-            }
+        // tokens are only good on the server that gave them out
+        let hello = match self.session {
+            Some((server, token)) if server == socket_address => session::resume_hello(token),
+            _ => {
+                self.session = None;
+                Vec::new()
+            },
         };
+        // no naia link conditioner, LaminarConnectionMessengerForNaia does the conditioning
+        let naia_socket = NaiaSocket::connect(socket_address);
         self.connection = Some(
             PeerConnection::new(
                 config,
                 naia_socket,
                 &socket_address,
+                self.conditioner.clone(),
+                hello,
             )
         );
 
-        // send an initial empty packet to make sure the connection gets marked as connected
-        let hello_packet = LaminarPacket::reliable_unordered(socket_address, vec![]);
-        self.send(hello_packet);
+        // send another hello to make sure the connection gets marked as connected
+        self.connection_mut().send_hello();
+        if let Some(change) = self.connection_mut().transition(ConnectionState::Connecting, TransitionCause::Handshake) {
+            self.transitions.push(change);
+        }
+    }
+
+    /// tell the server we're leaving. we go to Disconnecting, then Disconnected on the next
+    /// frame once the goodbye has been sent. connect() again to reconnect.
+    pub fn disconnect(&mut self) -> bool {
+        if self.replaying() || !self.initialized() {
+            return false;
+        }
+        match self.connection_mut().transition(ConnectionState::Disconnecting, TransitionCause::LocalDisconnect) {
+            Some(change) => {
+                let goodbye = LaminarPacket::unreliable(*self.server_addr(), vec![]);
+                self.send_internal(MessageKind::Goodbye, goodbye);
+                self.transitions.push(change);
+                // the server drops us for good after a goodbye
+                self.session = None;
+                true
+            },
+            None => false,
+        }
     }
 
     fn connection(&self) -> &PeerConnection {
@@ -306,39 +725,439 @@ impl NetworkResource {
     }
 }
 
-fn laminar_poller(
+// PreUpdate, before laminar_receiver: pick up changes to the NetworkSettings resource
+fn apply_network_settings(settings: Res<NetworkSettings>, mut net: ResMut<NetworkResource>) {
+    if settings.is_changed() && *settings != net.settings {
+        net.apply_settings(settings.clone());
+    }
+}
+
+// PreUpdate, after laminar_receiver: move State<ConnectionState> to where the connection is now.
+// the state driver applies it in Update, so anything in between in the same frame is skipped.
+fn sync_connection_state(net: Res<NetworkResource>, mut state: ResMut<State<ConnectionState>>) {
+    let current = net.connection_state();
+    if *state.current() != current {
+        state.overwrite_set(current).unwrap_or_default();
+    }
+}
+
+// PreUpdate: publish answers to discover_servers()
+#[cfg(not(target_arch = "wasm32"))]
+fn discovery_receiver(mut net: ResMut<NetworkResource>, mut discovered: EventWriter<ServerDiscovered>) {
+    let search = match net.discovery.as_mut() {
+        Some(search) => search,
+        None => return,
+    };
+    for server in search.poll() {
+        discovered.send(server);
+    }
+    if search.expired(Instant::now()) {
+        net.discovery = None;
+    }
+}
+
+// PreUpdate: publish answers to query_server(), and resend queries that haven't had one yet
+#[cfg(not(target_arch = "wasm32"))]
+fn query_receiver(mut net: ResMut<NetworkResource>, mut received: EventWriter<ServerInfoReceived>) {
+    if net.queries.is_empty() {
+        return;
+    }
+    for answer in net.queries.poll(Instant::now()) {
+        received.send(answer);
+    }
+}
+
+// PreUpdate: receive, and publish everything that arrived as bevy events
+fn laminar_receiver(
     mut net: ResMut<NetworkResource>,
-    mut peer_events: EventWriter<PeerEvent>,
+    mut clock: ResMut<ServerClock>,
+    mut peer_events: PeerEventWriters,
+    mut channel_messages: EventWriter<ChannelMessage>,
+    mut rpc_requests: EventWriter<RpcRequest>,
+    mut rpc_responses: EventWriter<RpcResponse>,
+    mut transfer_progress: EventWriter<TransferProgress>,
+    mut transfer_completed: EventWriter<TransferCompleted>,
+    mut transfer_failed: EventWriter<TransferFailed>,
+    mut snapshot_events: EventWriter<SnapshotReceived>,
 ){
+    let net = &mut *net;
+    capture::next_frame(&net.recorder);
+    let now = Instant::now();
+
+    if let Some(replayer) = net.replayer.as_mut() {
+        for event in replayer.due_events() {
+            match event {
+                // recorded statuses already cover any state changes these asked for
+                CaptureEvent::Incoming(packet) => {
+                    dispatch(net, packet, now, &mut clock, &mut peer_events, &mut channel_messages, &mut rpc_requests, &mut rpc_responses, &mut snapshot_events);
+                },
+                CaptureEvent::Status(handle, state) => {
+                    net.replay_state = Some(state);
+                    capture::publish_status(&net.recorder, &mut peer_events, handle, state);
+                },
+                CaptureEvent::Outgoing(_) => {},
+            }
+        }
+        for response in net.rpc.expired(now) {
+            rpc_responses.send(response);
+        }
+        transfer::publish_events(&mut net.transfers, &mut transfer_progress, &mut transfer_completed, &mut transfer_failed);
+        return;
+    }
+
     if !net.initialized() {
         return;
     }
 
-    net.poll();
+    for change in std::mem::take(&mut net.transitions) {
+        capture::publish_transition(&net.recorder, &mut peer_events, change);
+    }
+    // we're done with this connection until connect() is called again
+    if net.connection_state() == ConnectionState::Disconnected {
+        return;
+    }
+
+    net.connection_mut().receive(now);
 
     let event_receiver = net.event_receiver().clone();
-
-    let conn = net.connection_mut();
+    // laminar connects on any traffic from the server, a rejection included, so its connect event
+    // is held until the end of this pass in case the rejection is in here too
+    let mut established = false;
 
     // publish laminar socket events to bevy events - we won't expose the event_receiver.
     while let Ok(event) = event_receiver.try_recv() {
         match event {
-            LaminarSocketEvent::Connect(addr) => {
-                conn.set_state(ConnectionState::Connected);
-                peer_events.send(PeerEvent::Status(addr, conn.state()));
+            LaminarSocketEvent::Connect(_) => {
+                clock.reset();
+                net.snapshots.reset();
+                established = true;
             },
             LaminarSocketEvent::Disconnect(addr) => {
-                conn.set_state(ConnectionState::Disconnected);
-                peer_events.send(PeerEvent::Status(addr, conn.state()));
+                let conn = net.connection.as_mut().unwrap();
+                let cause = match conn.state() {
+                    ConnectionState::Timeout => TransitionCause::Timeout,
+                    ConnectionState::Disconnecting => conn.connection_state.cause().unwrap_or(TransitionCause::Dropped),
+                    _ => TransitionCause::Dropped,
+                };
+                if let Some(change) = conn.transition(ConnectionState::Disconnected, cause) {
+                    capture::publish_transition(&net.recorder, &mut peer_events, change);
+                }
+                for response in net.rpc.drop_peer(addr) {
+                    rpc_responses.send(response);
+                }
+                net.transfers.drop_peer(addr);
             },
             LaminarSocketEvent::Timeout(addr) => {
-                conn.set_state(ConnectionState::Timeout);
-                peer_events.send(PeerEvent::Status(addr, conn.state()));
+                if let Some(change) = net.connection_mut().transition(ConnectionState::Timeout, TransitionCause::Timeout) {
+                    capture::publish_transition(&net.recorder, &mut peer_events, change);
+                }
+                for response in net.rpc.drop_peer(addr) {
+                    rpc_responses.send(response);
+                }
+                net.transfers.drop_peer(addr);
             },
             LaminarSocketEvent::Packet(packet) => {
-                assert_eq!(conn.state(), ConnectionState::Connected);
+                let conn = net.connection.as_mut().unwrap();
+                let (admission, change) = admit(&mut conn.connection_state, conn.server_addr, packet.payload(), established);
+                if let Some(change) = change {
+                    capture::publish_transition(&net.recorder, &mut peer_events, change);
+                }
+                match admission {
+                    Admission::Rejected => {
+                        log::warn!("Server {} is full", packet.addr());
+                        net.session = None;
+                        continue;
+                    },
+                    Admission::Dropped => {
+                        log::debug!("Dropping packet from server while {:?}", conn.state());
+                        continue;
+                    },
+                    Admission::Dispatch => {},
+                }
+                capture::record_incoming(&net.recorder, &packet);
+                let leaving = dispatch(net, packet, now, &mut clock, &mut peer_events, &mut channel_messages, &mut rpc_requests, &mut rpc_responses, &mut snapshot_events);
+                if let Some(cause) = leaving {
+                    if let Some(change) = net.connection_mut().transition(ConnectionState::Disconnecting, cause) {
+                        capture::publish_transition(&net.recorder, &mut peer_events, change);
+                    }
+                }
+            },
+        }
+    }
+
+    // no rejection came with laminar's connect event, so we're in
+    let conn = net.connection.as_mut().unwrap();
+    if established {
+        if let Some(change) = conn.transition(ConnectionState::Connected, TransitionCause::Established) {
+            capture::publish_transition(&net.recorder, &mut peer_events, change);
+        }
+    }
+
+    // our goodbye went out last frame, or the server said goodbye, so close up.
+    // laminar has no close, we just stop polling the connection.
+    if conn.state() == ConnectionState::Disconnecting {
+        let cause = conn.connection_state.cause().unwrap_or(TransitionCause::LocalDisconnect);
+        if let Some(change) = conn.transition(ConnectionState::Disconnected, cause) {
+            capture::publish_transition(&net.recorder, &mut peer_events, change);
+        }
+        let addr = *net.server_addr();
+        for response in net.rpc.drop_peer(addr) {
+            rpc_responses.send(response);
+        }
+        net.transfers.drop_peer(addr);
+    }
+
+    for response in net.rpc.expired(now) {
+        rpc_responses.send(response);
+    }
+    transfer::publish_events(&mut net.transfers, &mut transfer_progress, &mut transfer_completed, &mut transfer_failed);
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Admission {
+    Dispatch,
+    Dropped,
+    Rejected,
+}
+
+// what a packet from the server means for the connection, before it's dispatched. the server
+// turning us away is checked first, whatever laminar has said. otherwise we're connected once
+// laminar says so, or a real message beats its connect event.
+fn admit(
+    state: &mut ConnectionStateMachine,
+    server: SocketAddr,
+    payload: &[u8],
+    established: bool,
+) -> (Admission, Option<ConnectionStateChanged>) {
+    if state.state() == ConnectionState::Connecting {
+        if protocol::is_rejection(payload) {
+            let change = state.transition(server, ConnectionState::Disconnecting, TransitionCause::Rejected);
+            return (Admission::Rejected, change);
+        }
+        if established || !payload.is_empty() {
+            let change = state.transition(server, ConnectionState::Connected, TransitionCause::Established);
+            return (Admission::Dispatch, change);
+        }
+    }
+    match state.state() {
+        ConnectionState::Connected => (Admission::Dispatch, None),
+        _ => (Admission::Dropped, None),
+    }
+}
+
+// hand a message (or a batch of them) from the server to whatever it's for. replays come through
+// here too. returns why we should leave, if the message means we should.
+fn dispatch(
+    net: &mut NetworkResource,
+    packet: LaminarPacket,
+    now: Instant,
+    clock: &mut ServerClock,
+    peer_events: &mut PeerEventWriters,
+    channel_messages: &mut EventWriter<ChannelMessage>,
+    rpc_requests: &mut EventWriter<RpcRequest>,
+    rpc_responses: &mut EventWriter<RpcResponse>,
+    snapshot_events: &mut EventWriter<SnapshotReceived>,
+) -> Option<TransitionCause> {
+    let mut leaving = None;
+    for message in batch::unbatch(packet) {
+        match message {
+            (MessageKind::User, packet) => {
                 peer_events.send(PeerEvent::Packet(packet));
             },
+            (MessageKind::Channel, packet) => {
+                match net.channels.message(packet.addr(), packet.payload()) {
+                    Some(message) => channel_messages.send(message),
+                    None => log::warn!("Message on unknown channel from server"),
+                }
+            },
+            (MessageKind::ClockPong, packet) => {
+                clock.handle_pong(packet.payload(), now);
+            },
+            (MessageKind::RpcRequest, packet) => {
+                match rpc::decode_request(packet.addr(), packet.payload()) {
+                    Some(request) => rpc_requests.send(request),
+                    None => log::warn!("Malformed rpc request from server"),
+                }
+            },
+            (MessageKind::RpcResponse, packet) => {
+                match rpc::decode_response(packet.addr(), packet.payload()) {
+                    Some((handle, payload)) => {
+                        // late responses for requests that already timed out are dropped
+                        if net.rpc.complete(handle) {
+                            rpc_responses.send(RpcResponse { handle, result: Ok(payload) });
+                        }
+                    },
+                    None => log::warn!("Malformed rpc response from server"),
+                }
+            },
+            (MessageKind::Snapshot, packet) => {
+                match net.snapshots.decode(packet.payload()) {
+                    Some((ack, snapshot)) => {
+                        net.send_now(MessageKind::SnapshotAck, LaminarPacket::unreliable(packet.addr(), ack));
+                        if let Some(snapshot) = snapshot {
+                            snapshot_events.send(snapshot);
+                        }
+                    },
+                    None => log::debug!("Dropped undecodable snapshot"),
+                }
+            },
+            (MessageKind::Lobby, packet) => {
+                net.lobby_inbox.push((packet.addr(), packet.payload().to_vec()));
+            },
+            (MessageKind::Relay, packet) => {
+                net.relay_inbox.push(packet);
+            },
+            (MessageKind::Session, packet) => {
+                match session::decode(packet.payload()) {
+                    Some(SessionMessage::Token(token)) => net.session = Some((packet.addr(), token)),
+                    _ => log::warn!("Unexpected session message from server"),
+                }
+            },
+            (MessageKind::Channels, packet) => {
+                // every channel message would be misread, so don't stay
+                if !net.channels.matches(packet.payload()) {
+                    log::error!("Server's channels don't match ours, disconnecting. Declare them identically on both plugins");
+                    net.session = None;
+                    net.send_now(MessageKind::Goodbye, LaminarPacket::unreliable(packet.addr(), vec![]));
+                    leaving = Some(TransitionCause::LocalDisconnect);
+                }
+            },
+            (MessageKind::Goodbye, _) => {
+                net.session = None;
+                leaving = leaving.or(Some(TransitionCause::RemoteDisconnect));
+            },
+            (kind, packet) if kind.is_transfer() => {
+                net.transfers.handle_message(kind, packet.addr(), packet.payload(), now);
+            },
+            (kind, _) => {
+                log::warn!("Unexpected {:?} message from server", kind);
+            },
+        }
+    }
+    leaving
+}
+
+// PostUpdate: queue our own housekeeping traffic, then let laminar send everything from this frame
+fn laminar_flusher(
+    mut net: ResMut<NetworkResource>,
+    mut clock: ResMut<ServerClock>,
+){
+    net.lobby_inbox.clear();
+    net.relay_inbox.clear();
+    if net.replaying() || !net.initialized() || net.connection_state() == ConnectionState::Disconnected {
+        return;
+    }
+
+    let now = Instant::now();
+    net.transfers.update(now);
+    let outbox: Vec<_> = net.transfers.outbox.drain(..).collect();
+    for (kind, packet) in outbox {
+        net.send_internal(kind, packet);
+    }
+
+    if net.connection_state() == ConnectionState::Connected {
+        if let Some(ping) = clock.ping_due(now) {
+            let packet = LaminarPacket::unreliable(*net.server_addr(), ping);
+            net.send_internal(MessageKind::ClockPing, packet);
         }
     }
-}
\ No newline at end of file
+
+    for packet in net.batcher.flush() {
+        net.connection_mut().send(packet);
+    }
+    net.connection_mut().update(now);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Event {
+        Connect,
+        Packet(Vec<u8>),
+    }
+
+    fn server() -> SocketAddr {
+        ([127, 0, 0, 1], 7777).into()
+    }
+
+    // one receive pass the way laminar_receiver does it, the admissions and every change published
+    fn pass(state: &mut ConnectionStateMachine, events: Vec<Event>) -> (Vec<Admission>, Vec<(ConnectionState, TransitionCause)>) {
+        let mut established = false;
+        let mut admissions = Vec::new();
+        let mut changes = Vec::new();
+        for event in events {
+            match event {
+                Event::Connect => established = true,
+                Event::Packet(payload) => {
+                    let (admission, change) = admit(state, server(), &payload, established);
+                    admissions.push(admission);
+                    changes.extend(change);
+                },
+            }
+        }
+        if established {
+            changes.extend(state.transition(server(), ConnectionState::Connected, TransitionCause::Established));
+        }
+        (admissions, changes.into_iter().map(|change| (change.to, change.cause)).collect())
+    }
+
+    fn connecting() -> ConnectionStateMachine {
+        let mut state = ConnectionStateMachine::default();
+        state.transition(server(), ConnectionState::Connecting, TransitionCause::Handshake);
+        state
+    }
+
+    #[test]
+    fn rejection_wins_over_an_earlier_connect() {
+        let mut state = connecting();
+        let (admissions, changes) = pass(&mut state, vec![Event::Connect, Event::Packet(protocol::rejection())]);
+        assert_eq!(admissions, vec![Admission::Rejected]);
+        assert_eq!(changes, vec![(ConnectionState::Disconnecting, TransitionCause::Rejected)]);
+        assert_eq!(state.cause(), Some(TransitionCause::Rejected));
+    }
+
+    #[test]
+    fn rejection_before_connect() {
+        let mut state = connecting();
+        let (admissions, changes) = pass(&mut state, vec![Event::Packet(protocol::rejection()), Event::Connect]);
+        assert_eq!(admissions, vec![Admission::Rejected]);
+        assert_eq!(changes, vec![(ConnectionState::Disconnecting, TransitionCause::Rejected)]);
+    }
+
+    #[test]
+    fn connect_alone_connects_at_the_end_of_the_pass() {
+        let mut state = connecting();
+        let (admissions, changes) = pass(&mut state, vec![Event::Connect]);
+        assert!(admissions.is_empty());
+        assert_eq!(changes, vec![(ConnectionState::Connected, TransitionCause::Established)]);
+    }
+
+    #[test]
+    fn welcome_after_connect_connects() {
+        let mut state = connecting();
+        let (admissions, changes) = pass(&mut state, vec![Event::Connect, Event::Packet(vec![]), Event::Packet(vec![0, 1])]);
+        assert_eq!(admissions, vec![Admission::Dispatch, Admission::Dispatch]);
+        assert_eq!(changes, vec![(ConnectionState::Connected, TransitionCause::Established)]);
+    }
+
+    #[test]
+    fn real_messages_beat_connect() {
+        let mut state = connecting();
+        // an empty welcome on its own doesn't say we're in
+        let (admissions, changes) = pass(&mut state, vec![Event::Packet(vec![])]);
+        assert_eq!(admissions, vec![Admission::Dropped]);
+        assert!(changes.is_empty());
+        let (admissions, changes) = pass(&mut state, vec![Event::Packet(vec![0, 1]), Event::Connect]);
+        assert_eq!(admissions, vec![Admission::Dispatch]);
+        assert_eq!(changes, vec![(ConnectionState::Connected, TransitionCause::Established)]);
+    }
+
+    #[test]
+    fn goodbyes_once_connected_are_dispatched() {
+        let mut state = connecting();
+        pass(&mut state, vec![Event::Connect]);
+        let (admissions, changes) = pass(&mut state, vec![Event::Packet(protocol::rejection())]);
+        assert_eq!(admissions, vec![Admission::Dispatch]);
+        assert!(changes.is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+use std::{collections::VecDeque, convert::TryInto, time::Duration};
+
+use bevy::log;
+use instant::Instant;
+
+// number of ping/pong samples we keep for estimating the offset and tick rate
+const MAX_SAMPLES: usize = 16;
+
+// The client sends a ClockPing with its local time, the server answers with a ClockPong echoing
+// that time, plus its own time and tick. Times are microseconds since each side's epoch.
+//
+//  ClockPing: [client_time: u64]
+//  ClockPong: [client_time: u64][server_time: u64][server_tick: u64]
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    rtt_us: u64,
+    // server_time - client_time, at the moment the server stamped the pong
+    offset_us: i64,
+    server_time_us: u64,
+    server_tick: u64,
+}
+
+/// Client resource, estimating the server's clock and tick from periodic pings over the
+/// existing laminar connection. Nothing is known until the first pong arrives after connecting.
+#[derive(Debug)]
+pub struct ServerClock {
+    /// how often to ping the server while connected
+    pub ping_interval: Duration,
+    epoch: Instant,
+    last_ping: Option<Instant>,
+    samples: VecDeque<ClockSample>,
+    smoothed_rtt_us: Option<f64>,
+}
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(1000))
+    }
+}
+
+impl ServerClock {
+    pub fn new(ping_interval: Duration) -> Self {
+        Self {
+            ping_interval,
+            epoch: Instant::now(),
+            last_ping: None,
+            samples: VecDeque::with_capacity(MAX_SAMPLES),
+            smoothed_rtt_us: None,
+        }
+    }
+
+    /// true once at least one pong has been received
+    pub fn synced(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// smoothed round trip time to the server
+    pub fn rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt_us
+            .map(|us| Duration::from_micros(us as u64))
+    }
+
+    /// estimated (server clock - local clock) in microseconds.
+    /// taken from the sample with the lowest rtt, since it has the least queuing noise.
+    pub fn offset_micros(&self) -> Option<i64> {
+        self.samples
+            .iter()
+            .min_by_key(|s| s.rtt_us)
+            .map(|s| s.offset_us)
+    }
+
+    /// estimated server clock right now, as time since the server started
+    pub fn server_time(&self) -> Option<Duration> {
+        self.server_time_at(Instant::now())
+    }
+
+    /// estimated server clock at a local instant
+    pub fn server_time_at(&self, at: Instant) -> Option<Duration> {
+        let offset = self.offset_micros()?;
+        let server_us = self.local_micros(at) as i64 + offset;
+        Some(Duration::from_micros(server_us.max(0) as u64))
+    }
+
+    /// server ticks per second, needs at least two samples
+    pub fn ticks_per_second(&self) -> Option<f64> {
+        let oldest = self.samples.front()?;
+        let newest = self.samples.back()?;
+        if newest.server_time_us <= oldest.server_time_us {
+            return None;
+        }
+        let ticks = newest.server_tick.saturating_sub(oldest.server_tick) as f64;
+        let secs = (newest.server_time_us - oldest.server_time_us) as f64 / 1_000_000.0;
+        Some(ticks / secs)
+    }
+
+    /// estimated server tick right now
+    pub fn server_tick(&self) -> Option<u64> {
+        let newest = self.samples.back()?;
+        let now_us = self.server_time()?.as_micros() as u64;
+        let elapsed = now_us.saturating_sub(newest.server_time_us) as f64 / 1_000_000.0;
+        let rate = self.ticks_per_second().unwrap_or(0.0);
+        Some(newest.server_tick + (elapsed * rate) as u64)
+    }
+
+    /// forget everything, eg. when (re)connecting
+    pub fn reset(&mut self) {
+        self.last_ping = None;
+        self.samples.clear();
+        self.smoothed_rtt_us = None;
+    }
+
+    fn local_micros(&self, at: Instant) -> u64 {
+        since(self.epoch, at).as_micros() as u64
+    }
+
+    /// returns a ping payload if it's time to send one
+    pub(crate) fn ping_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if let Some(last) = self.last_ping {
+            if since(last, now) < self.ping_interval {
+                return None;
+            }
+        }
+        self.last_ping = Some(now);
+        Some(self.local_micros(now).to_le_bytes().to_vec())
+    }
+
+    pub(crate) fn handle_pong(&mut self, payload: &[u8], now: Instant) {
+        let (client_time_us, server_time_us, server_tick) = match decode_pong(payload) {
+            Some(pong) => pong,
+            None => {
+                log::warn!("Malformed clock pong, len {}", payload.len());
+                return;
+            }
+        };
+        let now_us = self.local_micros(now);
+        if client_time_us > now_us {
+            // from before a reset, or junk
+            return;
+        }
+        let rtt_us = now_us - client_time_us;
+        // assume the server stamped the pong half way through the round trip
+        let offset_us = server_time_us as i64 - (client_time_us + rtt_us / 2) as i64;
+
+        self.smoothed_rtt_us = Some(match self.smoothed_rtt_us {
+            Some(srtt) => srtt * 0.875 + rtt_us as f64 * 0.125,
+            None => rtt_us as f64,
+        });
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockSample {
+            rtt_us,
+            offset_us,
+            server_time_us,
+            server_tick,
+        });
+    }
+}
+
+// Instant subtraction that won't panic if `later` is actually earlier
+fn since(earlier: Instant, later: Instant) -> Duration {
+    if later > earlier {
+        later - earlier
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+fn read_u64(bytes: &[u8], index: usize) -> Option<u64> {
+    let slice = bytes.get(index * 8..index * 8 + 8)?;
+    Some(u64::from_le_bytes(slice.try_into().ok()?))
+}
+
+fn decode_pong(payload: &[u8]) -> Option<(u64, u64, u64)> {
+    Some((read_u64(payload, 0)?, read_u64(payload, 1)?, read_u64(payload, 2)?))
+}
+
+/// server side: build the pong for a ping payload
+pub(crate) fn pong_payload(ping: &[u8], server_time: Duration, server_tick: u64) -> Option<Vec<u8>> {
+    let client_time_us = read_u64(ping, 0)?;
+    let mut payload = Vec::with_capacity(24);
+    payload.extend_from_slice(&client_time_us.to_le_bytes());
+    payload.extend_from_slice(&(server_time.as_micros() as u64).to_le_bytes());
+    payload.extend_from_slice(&server_tick.to_le_bytes());
+    Some(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the server's clock runs this far ahead of ours, at this many ticks per second
+    const OFFSET_US: i64 = 5_000_000;
+    const TICK_RATE: u64 = 60;
+    const MS: u64 = 1000;
+
+    fn clock() -> ServerClock {
+        ServerClock::new(Duration::from_secs(1))
+    }
+
+    fn local(clock: &ServerClock, us: u64) -> Instant {
+        clock.epoch + Duration::from_micros(us)
+    }
+
+    // a ping sent `sent_us` after our epoch, taking `up_us` to reach the server and `down_us` back
+    fn exchange(clock: &mut ServerClock, sent_us: u64, up_us: u64, down_us: u64) {
+        let ping = clock.ping_due(local(clock, sent_us)).unwrap();
+        let server_us = ((sent_us + up_us) as i64 + OFFSET_US) as u64;
+        let tick = server_us * TICK_RATE / 1_000_000;
+        let pong = pong_payload(&ping, Duration::from_micros(server_us), tick).unwrap();
+        clock.handle_pong(&pong, local(clock, sent_us + up_us + down_us));
+    }
+
+    // one second apart, (up, down) in ms
+    fn exchanges(clock: &mut ServerClock, first_second: u64, delays: &[(u64, u64)]) {
+        for (n, (up, down)) in delays.iter().enumerate() {
+            exchange(clock, (first_second + n as u64) * 1_000_000, up * MS, down * MS);
+        }
+    }
+
+    #[test]
+    fn nothing_known_until_the_first_pong() {
+        let mut clock = clock();
+        assert!(!clock.synced());
+        assert_eq!((clock.rtt(), clock.offset_micros(), clock.ticks_per_second()), (None, None, None));
+        exchange(&mut clock, 0, 20 * MS, 20 * MS);
+        assert!(clock.synced());
+        assert_eq!(clock.offset_micros(), Some(OFFSET_US));
+        // one sample isn't enough for a rate
+        assert_eq!(clock.ticks_per_second(), None);
+    }
+
+    #[test]
+    fn offset_comes_from_the_quickest_exchange() {
+        let mut clock = clock();
+        // asymmetric delays are off by half the difference
+        exchanges(&mut clock, 0, &[(60, 20)]);
+        assert_eq!(clock.offset_micros(), Some(OFFSET_US + 20_000));
+        exchanges(&mut clock, 1, &[(25, 45), (90, 10), (30, 45)]);
+        assert_eq!(clock.offset_micros(), Some(OFFSET_US - 10_000));
+        // slower exchanges don't displace a quick one, whatever their own error
+        exchanges(&mut clock, 4, &[(21, 21), (150, 10), (10, 150), (70, 70)]);
+        assert_eq!(clock.offset_micros(), Some(OFFSET_US));
+        let at = local(&clock, 10_000_000);
+        assert_eq!(clock.server_time_at(at), Some(Duration::from_micros(10_000_000 + OFFSET_US as u64)));
+    }
+
+    #[test]
+    fn old_samples_age_out() {
+        let mut clock = clock();
+        exchanges(&mut clock, 0, &[(5, 5)]);
+        let jitter: Vec<_> = (0..MAX_SAMPLES as u64).map(|n| (20 + n % 3, 20 + n % 5)).collect();
+        exchanges(&mut clock, 1, &jitter[..MAX_SAMPLES - 1]);
+        assert_eq!(clock.offset_micros(), Some(OFFSET_US));
+        // the quick one's gone, the best of the rest is (20, 20)
+        exchanges(&mut clock, MAX_SAMPLES as u64, &jitter[MAX_SAMPLES - 1..]);
+        assert_eq!(clock.samples.len(), MAX_SAMPLES);
+        assert_eq!(clock.offset_micros(), Some(OFFSET_US));
+        assert_eq!(clock.samples.iter().map(|s| s.rtt_us).min(), Some(40 * MS));
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut clock = clock();
+        exchanges(&mut clock, 0, &[(30, 30); 10]);
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(60)));
+        // an eighth of the way to each new sample
+        exchanges(&mut clock, 10, &[(10, 10)]);
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(55)));
+        exchanges(&mut clock, 11, &[(10, 10); 40]);
+        let rtt = clock.rtt().unwrap().as_micros() as i64;
+        assert!((rtt - 20_000).abs() < 1000, "rtt {}us", rtt);
+    }
+
+    #[test]
+    fn rtt_settles_through_jitter() {
+        let mut clock = clock();
+        let jitter: Vec<_> = (0..60).map(|n| if n % 2 == 0 { (15, 25) } else { (50, 30) }).collect();
+        exchanges(&mut clock, 0, &jitter);
+        // alternating 40ms and 80ms
+        let rtt = clock.rtt().unwrap().as_micros() as i64;
+        assert!((rtt - 60_000).abs() < 3000, "rtt {}us", rtt);
+    }
+
+    #[test]
+    fn tick_rate_converges() {
+        let mut clock = clock();
+        let jitter: Vec<_> = (0..40u64).map(|n| (10 + n * 7 % 30, 10 + n * 11 % 40)).collect();
+        exchanges(&mut clock, 0, &jitter[..2]);
+        let early = clock.ticks_per_second().unwrap();
+        assert!((early - TICK_RATE as f64).abs() < 2.0, "{} ticks/s", early);
+        exchanges(&mut clock, 2, &jitter[2..]);
+        let rate = clock.ticks_per_second().unwrap();
+        assert!((rate - TICK_RATE as f64).abs() < 0.1, "{} ticks/s", rate);
+    }
+
+    #[test]
+    fn bad_pongs_are_ignored() {
+        let mut clock = clock();
+        let ping = clock.ping_due(local(&clock, 1_000_000)).unwrap();
+        let pong = pong_payload(&ping, Duration::from_secs(6), 360).unwrap();
+        clock.handle_pong(&pong[..23], local(&clock, 1_040_000));
+        // answered before we asked, eg. from before a reset
+        clock.handle_pong(&pong, local(&clock, 900_000));
+        assert!(!clock.synced());
+        assert_eq!(pong_payload(&ping[..7], Duration::from_secs(6), 360), None);
+
+        clock.handle_pong(&pong, local(&clock, 1_040_000));
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(40)));
+        clock.reset();
+        assert!(!clock.synced());
+        assert_eq!(clock.rtt(), None);
+    }
+
+    #[test]
+    fn pings_wait_for_the_interval() {
+        let mut clock = clock();
+        assert!(clock.ping_due(local(&clock, 0)).is_some());
+        assert!(clock.ping_due(local(&clock, 999_999)).is_none());
+        assert_eq!(clock.ping_due(local(&clock, 1_000_000)), Some(1_000_000u64.to_le_bytes().to_vec()));
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BinaryHeap, HashMap, HashSet},
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use instant::Instant;
+use naia_client_socket::LinkConditionerConfig;
+
+use crate::PeerHandle;
+
+// Simulates bad networks between laminar and naia, so it sees every datagram in both directions.
+// Unlike naia's own link conditioner it can be changed while running, can condition outgoing
+// traffic too, and can give individual peers their own profile.
+
+/// How to mess with datagrams travelling in one direction
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkProfile {
+    /// fixed delay added to every datagram
+    pub latency: Duration,
+    /// up to this much extra delay is randomly added or subtracted
+    pub jitter: Duration,
+    /// chance a datagram is dropped, 0.0 - 1.0
+    pub loss: f32,
+    /// chance a datagram has one bit flipped, 0.0 - 1.0
+    pub corruption: f32,
+    /// chance a datagram is held back by up to `reorder_window` extra, so later ones overtake it
+    pub reorder: f32,
+    pub reorder_window: Duration,
+    /// chance a datagram is delivered twice, each copy with its own delay
+    pub duplicate: f32,
+    /// bursty loss, applied on top of `loss`
+    pub burst_loss: Option<BurstLoss>,
+}
+
+/// Gilbert-Elliott loss model: the link flips between a good and a bad state, each with its own
+/// loss rate, which gives bursts of loss rather than evenly spread drops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BurstLoss {
+    /// chance per datagram of going from good to bad
+    pub enter_bad: f32,
+    /// chance per datagram of going from bad back to good
+    pub leave_bad: f32,
+    /// loss rate while good
+    pub loss_good: f32,
+    /// loss rate while bad
+    pub loss_bad: f32,
+}
+
+impl Default for BurstLoss {
+    fn default() -> Self {
+        // on average bursts of 4 datagrams, every ~100
+        Self {
+            enter_bad: 0.01,
+            leave_bad: 0.25,
+            loss_good: 0.0,
+            loss_bad: 1.0,
+        }
+    }
+}
+
+impl LinkProfile {
+    /// naia's conditioner only does incoming traffic, so this is an incoming profile
+    pub fn from_naia(config: &LinkConditionerConfig) -> Self {
+        Self {
+            latency: Duration::from_millis(config.incoming_latency as u64),
+            jitter: Duration::from_millis(config.incoming_jitter as u64),
+            loss: config.incoming_loss,
+            corruption: config.incoming_corruption,
+            ..Default::default()
+        }
+    }
+}
+
+/// Profiles for both directions. None leaves that direction alone.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkProfiles {
+    pub incoming: Option<LinkProfile>,
+    pub outgoing: Option<LinkProfile>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub(crate) enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+// delayed datagrams, ordered by release time then arrival
+type DelayQueue = BinaryHeap<Reverse<(Instant, u64, SocketAddr, Vec<u8>)>>;
+
+#[derive(Debug)]
+struct ConditionerState {
+    default: LinkProfiles,
+    per_peer: HashMap<PeerHandle, LinkProfiles>,
+    rng: XorShift,
+    seq: u64,
+    // links currently in the bad state of their BurstLoss model
+    bad_links: HashSet<(Direction, SocketAddr)>,
+    incoming: DelayQueue,
+    outgoing: DelayQueue,
+}
+
+/// Handle to the conditioning layer, from NetworkResource::link_conditioner().
+/// Cheap to clone, and changes apply immediately to traffic that hasn't been conditioned yet.
+#[derive(Debug, Clone)]
+pub struct LinkConditioner {
+    state: Arc<Mutex<ConditionerState>>,
+}
+
+impl Default for LinkConditioner {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ConditionerState {
+                default: LinkProfiles::default(),
+                per_peer: HashMap::new(),
+                rng: XorShift::from_entropy(),
+                seq: 0,
+                bad_links: HashSet::new(),
+                incoming: BinaryHeap::new(),
+                outgoing: BinaryHeap::new(),
+            })),
+        }
+    }
+}
+
+impl LinkConditioner {
+    pub(crate) fn from_naia(config: Option<&LinkConditionerConfig>) -> Self {
+        let conditioner = Self::default();
+        if let Some(config) = config {
+            conditioner.set_incoming(Some(LinkProfile::from_naia(config)));
+        }
+        conditioner
+    }
+
+    /// profiles for every peer without its own
+    pub fn profiles(&self) -> LinkProfiles {
+        self.state.lock().unwrap().default
+    }
+
+    pub fn set_profiles(&self, profiles: LinkProfiles) {
+        self.state.lock().unwrap().default = profiles;
+    }
+
+    pub fn set_incoming(&self, profile: Option<LinkProfile>) {
+        self.state.lock().unwrap().default.incoming = profile;
+    }
+
+    pub fn set_outgoing(&self, profile: Option<LinkProfile>) {
+        self.state.lock().unwrap().default.outgoing = profile;
+    }
+
+    /// give one peer its own profiles, replacing the defaults for it
+    pub fn set_peer_profiles(&self, handle: PeerHandle, profiles: LinkProfiles) {
+        self.state.lock().unwrap().per_peer.insert(handle, profiles);
+    }
+
+    /// go back to the default profiles for this peer
+    pub fn clear_peer_profiles(&self, handle: PeerHandle) {
+        self.state.lock().unwrap().per_peer.remove(&handle);
+    }
+
+    /// fixed seed, for reproducible runs
+    pub fn set_seed(&self, seed: u64) {
+        self.state.lock().unwrap().rng = XorShift::new(seed);
+    }
+
+    /// Some(payload) to pass it on right now. None if it was dropped, or queued for later,
+    /// so callers should check `release` afterwards.
+    pub(crate) fn condition(
+        &self,
+        direction: Direction,
+        addr: SocketAddr,
+        payload: Vec<u8>,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let profiles = state.per_peer.get(&addr).copied().unwrap_or(state.default);
+        let profile = match direction {
+            Direction::Incoming => profiles.incoming,
+            Direction::Outgoing => profiles.outgoing,
+        };
+        let profile = match profile {
+            Some(profile) => profile,
+            None => return Some(payload),
+        };
+
+        let burst_lost = state.burst_lost(direction, addr, profile.burst_loss);
+        if burst_lost || state.rng.chance(profile.loss) {
+            return None;
+        }
+
+        let copies = if state.rng.chance(profile.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut payload = payload.clone();
+            if !payload.is_empty() && state.rng.chance(profile.corruption) {
+                let bit = state.rng.below(payload.len() as u64 * 8) as usize;
+                payload[bit / 8] ^= 1 << (bit % 8);
+            }
+            let mut delay = state.rng.delay(profile.latency, profile.jitter);
+            if state.rng.chance(profile.reorder) {
+                delay += profile.reorder_window.mul_f32(state.rng.next_f32());
+            }
+            state.seq += 1;
+            let entry = Reverse((now + delay, state.seq, addr, payload));
+            match direction {
+                Direction::Incoming => state.incoming.push(entry),
+                Direction::Outgoing => state.outgoing.push(entry),
+            }
+        }
+        None
+    }
+
+    /// next queued datagram whose delay is up
+    pub(crate) fn release(&self, direction: Direction, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+        let mut state = self.state.lock().unwrap();
+        let queue = match direction {
+            Direction::Incoming => &mut state.incoming,
+            Direction::Outgoing => &mut state.outgoing,
+        };
+        match queue.peek() {
+            Some(Reverse((release_at, ..))) if *release_at <= now => {
+                let Reverse((_, _, addr, payload)) = queue.pop().unwrap();
+                Some((addr, payload))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl ConditionerState {
+    // step the Gilbert-Elliott model for this link, and roll for loss in its new state
+    fn burst_lost(&mut self, direction: Direction, addr: SocketAddr, model: Option<BurstLoss>) -> bool {
+        let model = match model {
+            Some(model) => model,
+            None => return false,
+        };
+        let key = (direction, addr);
+        let bad = if self.bad_links.contains(&key) {
+            !self.rng.chance(model.leave_bad)
+        } else {
+            self.rng.chance(model.enter_bad)
+        };
+        if bad {
+            self.bad_links.insert(key);
+        } else {
+            self.bad_links.remove(&key);
+        }
+        self.rng.chance(if bad { model.loss_bad } else { model.loss_good })
+    }
+}
+
+// small xorshift prng, so we don't need rand (and its wasm setup) just for this
+#[derive(Debug)]
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // zero is a fixed point of xorshift
+        Self(seed.max(1))
+    }
+
+    // std's RandomState is randomly keyed per process (where the platform allows)
+    fn from_entropy() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0x9E37_79B9_7F4A_7C15);
+        Self::new(hasher.finish())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// uniform in 0.0..1.0
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.next_f32() < probability
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// latency +/- up to jitter, never negative
+    fn delay(&mut self, latency: Duration, jitter: Duration) -> Duration {
+        if jitter == Duration::from_secs(0) {
+            return latency;
+        }
+        let offset = jitter.mul_f32(self.next_f32());
+        if self.next_u64() & 1 == 0 {
+            latency + offset
+        } else {
+            latency.checked_sub(offset).unwrap_or_default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryInto, io};
+
+    use crossbeam_channel::{unbounded, Receiver, Sender};
+    use laminar::{
+        Config, ConnectionManager, DatagramSocket, Packet, SocketEvent, VirtualConnection,
+    };
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    fn seeded(profile: LinkProfile, seed: u64) -> LinkConditioner {
+        let conditioner = LinkConditioner::default();
+        conditioner.set_seed(seed);
+        conditioner.set_outgoing(Some(profile));
+        conditioner
+    }
+
+    // everything the conditioner lets through for these payloads, in delivery order
+    fn run(conditioner: &LinkConditioner, count: u8, step: Duration) -> Vec<u8> {
+        let start = Instant::now();
+        let mut delivered = Vec::new();
+        for i in 0..count {
+            let now = start + step * i as u32;
+            if let Some(payload) = conditioner.condition(Direction::Outgoing, addr(1), vec![i], now) {
+                delivered.push(payload[0]);
+            }
+            while let Some((_, payload)) = conditioner.release(Direction::Outgoing, now) {
+                delivered.push(payload[0]);
+            }
+        }
+        let end = start + Duration::from_secs(60);
+        while let Some((_, payload)) = conditioner.release(Direction::Outgoing, end) {
+            delivered.push(payload[0]);
+        }
+        delivered
+    }
+
+    #[test]
+    fn no_profile_passes_through() {
+        let conditioner = LinkConditioner::default();
+        let now = Instant::now();
+        assert_eq!(conditioner.condition(Direction::Outgoing, addr(1), vec![1], now), Some(vec![1]));
+        assert_eq!(conditioner.condition(Direction::Incoming, addr(1), vec![2], now), Some(vec![2]));
+    }
+
+    #[test]
+    fn same_seed_same_result() {
+        let profile = LinkProfile {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            loss: 0.1,
+            reorder: 0.2,
+            reorder_window: Duration::from_millis(50),
+            duplicate: 0.1,
+            ..Default::default()
+        };
+        let step = Duration::from_millis(5);
+        assert_eq!(run(&seeded(profile, 7), 200, step), run(&seeded(profile, 7), 200, step));
+    }
+
+    #[test]
+    fn duplicates_and_reorders() {
+        let profile = LinkProfile {
+            reorder: 0.3,
+            reorder_window: Duration::from_millis(50),
+            duplicate: 0.3,
+            ..Default::default()
+        };
+        let delivered = run(&seeded(profile, 1), 200, Duration::from_millis(5));
+        assert!(delivered.len() > 200, "nothing was duplicated");
+        assert!(delivered.windows(2).any(|pair| pair[0] > pair[1]), "nothing was reordered");
+        for i in 0..200 {
+            assert!(delivered.contains(&i), "{} was lost", i);
+        }
+    }
+
+    #[test]
+    fn burst_loss_drops_runs() {
+        let profile = LinkProfile {
+            burst_loss: Some(BurstLoss { enter_bad: 0.05, ..Default::default() }),
+            ..Default::default()
+        };
+        let delivered = run(&seeded(profile, 3), 250, Duration::from_millis(5));
+        assert!(delivered.len() < 250);
+        let longest_gap = delivered.windows(2).map(|pair| pair[1] - pair[0] - 1).max().unwrap();
+        assert!(longest_gap >= 2, "no burst, longest gap {}", longest_gap);
+    }
+
+    #[test]
+    fn per_peer_profiles() {
+        let conditioner = LinkConditioner::default();
+        conditioner.set_outgoing(Some(LinkProfile { loss: 1.0, ..Default::default() }));
+        conditioner.set_peer_profiles(addr(2), LinkProfiles::default());
+        let now = Instant::now();
+        assert_eq!(conditioner.condition(Direction::Outgoing, addr(1), vec![1], now), None);
+        assert_eq!(conditioner.condition(Direction::Outgoing, addr(2), vec![1], now), Some(vec![1]));
+        conditioner.clear_peer_profiles(addr(2));
+        assert_eq!(conditioner.condition(Direction::Outgoing, addr(2), vec![1], now), None);
+    }
+
+    // a laminar socket wired straight to another one through a conditioner, on a fake clock
+    struct TestSocket {
+        addr: SocketAddr,
+        conditioner: LinkConditioner,
+        clock: Arc<Mutex<Instant>>,
+        inbox: Receiver<(SocketAddr, Vec<u8>)>,
+        other: Sender<(SocketAddr, Vec<u8>)>,
+    }
+
+    impl TestSocket {
+        fn flush(&self, now: Instant) {
+            while let Some((_, payload)) = self.conditioner.release(Direction::Outgoing, now) {
+                self.other.send((self.addr, payload)).unwrap();
+            }
+        }
+    }
+
+    impl DatagramSocket for TestSocket {
+        fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+            let now = *self.clock.lock().unwrap();
+            if let Some(payload) = self.conditioner.condition(Direction::Outgoing, *addr, payload.to_vec(), now) {
+                self.other.send((self.addr, payload)).unwrap();
+            }
+            self.flush(now);
+            Ok(payload.len())
+        }
+
+        fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+            self.flush(*self.clock.lock().unwrap());
+            match self.inbox.try_recv() {
+                Ok((from, payload)) => {
+                    buffer[..payload.len()].copy_from_slice(&payload);
+                    Ok((&buffer[..payload.len()], from))
+                }
+                Err(_) => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.addr)
+        }
+
+        fn is_blocking_mode(&self) -> bool {
+            false
+        }
+    }
+
+    type Manager = ConnectionManager<TestSocket, VirtualConnection>;
+
+    fn bad_link(seed: u64) -> (Manager, Manager, Arc<Mutex<Instant>>) {
+        let profile = LinkProfile {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            loss: 0.02,
+            reorder: 0.2,
+            reorder_window: Duration::from_millis(60),
+            duplicate: 0.1,
+            burst_loss: Some(BurstLoss { enter_bad: 0.03, ..Default::default() }),
+            ..Default::default()
+        };
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let (to_a, a_inbox) = unbounded();
+        let (to_b, b_inbox) = unbounded();
+        let config = Config {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let a = ConnectionManager::new(TestSocket {
+            addr: addr(1),
+            conditioner: seeded(profile, seed),
+            clock: clock.clone(),
+            inbox: a_inbox,
+            other: to_b,
+        }, config.clone());
+        let b = ConnectionManager::new(TestSocket {
+            addr: addr(2),
+            conditioner: seeded(profile, seed + 1),
+            clock: clock.clone(),
+            inbox: b_inbox,
+            other: to_a,
+        }, config);
+        (a, b, clock)
+    }
+
+    // sends `count` numbered payloads from a to b with `packet`, one per 10ms step, and returns
+    // what b's user would see. both ends also send an empty unreliable packet every step, like a
+    // game would, which keeps acks flowing so laminar notices and resends losses.
+    fn deliver(seed: u64, count: u32, packet: fn(SocketAddr, Vec<u8>) -> Packet) -> Vec<u32> {
+        let (mut a, mut b, clock) = bad_link(seed);
+        let mut received = Vec::new();
+        for step in 0..3000u32 {
+            let now = {
+                let mut clock = clock.lock().unwrap();
+                *clock += Duration::from_millis(10);
+                *clock
+            };
+            if step < count {
+                a.event_sender().send(packet(addr(2), step.to_le_bytes().to_vec())).unwrap();
+            }
+            a.event_sender().send(Packet::unreliable(addr(2), vec![])).unwrap();
+            b.event_sender().send(Packet::unreliable(addr(1), vec![])).unwrap();
+            a.manual_poll(now);
+            b.manual_poll(now);
+            while a.event_receiver().try_recv().is_ok() {}
+            while let Ok(event) = b.event_receiver().try_recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    if !packet.payload().is_empty() {
+                        received.push(u32::from_le_bytes(packet.payload().try_into().unwrap()));
+                    }
+                }
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn ordered_survives_bad_link() {
+        for seed in 1..4 {
+            let received = deliver(seed, 300, |addr, payload| Packet::reliable_ordered(addr, payload, Some(1)));
+            assert_eq!(received, (0..300).collect::<Vec<_>>(), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn sequenced_survives_bad_link() {
+        for seed in 1..4 {
+            let received = deliver(seed, 300, |addr, payload| Packet::unreliable_sequenced(addr, payload, Some(1)));
+            assert!(!received.is_empty(), "seed {}", seed);
+            assert!(received.len() < 300, "seed {}: nothing was lost", seed);
+            assert!(received.windows(2).all(|pair| pair[0] < pair[1]), "seed {}: {:?}", seed, received);
+        }
+    }
+}
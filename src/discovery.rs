@@ -0,0 +1,244 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use instant::Instant;
+
+use crate::protocol::{read_str, truncate, write_str};
+
+// LAN discovery for native UDP builds. Clients broadcast a query on the discovery port, and any
+// server listening for them answers with its name, player count and game port. This is a plain
+// UDP socket of its own, separate from naia and laminar, so nobody needs a connection to ask.
+//
+//  Query: [magic][0][padding up to QUERY_LEN]
+//  Reply: [magic][1][name][players: u16][max_players: u16, 0 for no limit][port: u16]
+//
+// The server's address is wherever the reply came from, with the advertised port, since servers
+// usually listen on 0.0.0.0 and don't know which of their addresses the client can reach.
+// Names are capped so a reply is never bigger than the query that prompted it.
+
+const MAGIC: &[u8; 7] = b"BNLDISC";
+
+const QUERY: u8 = 0;
+const REPLY: u8 = 1;
+
+const QUERY_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 32;
+
+// how long a discover_servers() call listens for replies
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Plugin option (server): answer LAN discovery broadcasts
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    pub port: u16,
+    /// shown to players, cut down to 32 bytes
+    pub name: String,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_DISCOVERY_PORT,
+            name: "bevy_naia_laminar server".to_string(),
+        }
+    }
+}
+
+/// the port discover_servers() broadcasts to
+pub const DEFAULT_DISCOVERY_PORT: u16 = 14190;
+
+/// Bevy event (client), a server answered our discovery broadcast
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerDiscovered {
+    /// connect here
+    pub addr: SocketAddr,
+    pub name: String,
+    pub players: u16,
+    /// None for no limit
+    pub max_players: Option<u16>,
+}
+
+fn query() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(QUERY_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.push(QUERY);
+    buf.resize(QUERY_LEN, 0);
+    buf
+}
+
+fn is_query(payload: &[u8]) -> bool {
+    payload.len() >= QUERY_LEN && payload.strip_prefix(&MAGIC[..]).and_then(|rest| rest.first()) == Some(&QUERY)
+}
+
+fn reply(name: &str, players: usize, max_players: Option<usize>, port: u16) -> Vec<u8> {
+    let clamp = |n: usize| n.min(u16::MAX as usize) as u16;
+    let mut buf = Vec::with_capacity(QUERY_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.push(REPLY);
+    write_str(&mut buf, truncate(name, MAX_NAME_LEN));
+    buf.extend_from_slice(&clamp(players).to_le_bytes());
+    buf.extend_from_slice(&max_players.map_or(0, clamp).to_le_bytes());
+    buf.extend_from_slice(&port.to_le_bytes());
+    buf
+}
+
+fn parse_reply(from: SocketAddr, payload: &[u8]) -> Option<ServerDiscovered> {
+    let mut body = payload.strip_prefix(&MAGIC[..])?.strip_prefix(&[REPLY])?;
+    let name = read_str(&mut body)?;
+    let mut u16s = body.chunks_exact(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    let (players, max_players, port) = (u16s.next()?, u16s.next()?, u16s.next()?);
+    Some(ServerDiscovered {
+        addr: SocketAddr::new(from.ip(), port),
+        name,
+        players,
+        max_players: if max_players == 0 { None } else { Some(max_players) },
+    })
+}
+
+/// Server side: the socket listening for discovery broadcasts
+#[derive(Debug)]
+pub(crate) struct DiscoveryResponder {
+    socket: UdpSocket,
+    name: String,
+    // where the game is
+    port: u16,
+}
+
+impl DiscoveryResponder {
+    pub(crate) fn bind(config: &DiscoveryConfig, port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            name: config.name.clone(),
+            port,
+        })
+    }
+
+    /// answer every query that's arrived since the last call
+    pub(crate) fn respond(&self, players: usize, max_players: Option<usize>) {
+        let mut buf = [0; 1500];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => {
+                    if is_query(&buf[..len]) {
+                        let reply = reply(&self.name, players, max_players, self.port);
+                        self.socket.send_to(&reply, from).ok();
+                    }
+                }
+                // WouldBlock when we're done, or eg. icmp errors from earlier replies, which
+                // aren't worth spinning on
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Client side: one discover_servers() call, listening for replies until it expires
+#[derive(Debug)]
+pub(crate) struct DiscoverySearch {
+    socket: UdpSocket,
+    until: Instant,
+    seen: HashSet<SocketAddr>,
+}
+
+impl DiscoverySearch {
+    pub(crate) fn start(port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        socket.send_to(&query(), (Ipv4Addr::BROADCAST, port))?;
+        Ok(Self {
+            socket,
+            until: Instant::now() + DISCOVERY_WINDOW,
+            seen: HashSet::new(),
+        })
+    }
+
+    pub(crate) fn expired(&self, now: Instant) -> bool {
+        now >= self.until
+    }
+
+    /// servers we've heard from since the last call, each reported once per search
+    pub(crate) fn poll(&mut self) -> Vec<ServerDiscovered> {
+        let mut found = Vec::new();
+        let mut buf = [0; 1500];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => {
+                    if let Some(server) = parse_reply(from, &buf[..len]) {
+                        if self.seen.insert(server.addr) {
+                            found.push(server);
+                        }
+                    }
+                }
+                Err(_) => return found,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from() -> SocketAddr {
+        ([192, 168, 1, 20], 50000).into()
+    }
+
+    #[test]
+    fn query_is_recognised() {
+        assert!(is_query(&query()));
+        assert!(!is_query(&query()[..QUERY_LEN - 1]));
+        assert!(!is_query(&reply("server", 1, None, 7777)));
+        let mut other = query();
+        other[0] = b'X';
+        assert!(!is_query(&other));
+    }
+
+    #[test]
+    fn reply_roundtrip() {
+        let server = parse_reply(from(), &reply("my server", 3, Some(8), 7777)).unwrap();
+        assert_eq!(server, ServerDiscovered {
+            addr: ([192, 168, 1, 20], 7777).into(),
+            name: "my server".to_string(),
+            players: 3,
+            max_players: Some(8),
+        });
+        let server = parse_reply(from(), &reply("", 0, None, 7777)).unwrap();
+        assert_eq!(server.max_players, None);
+    }
+
+    #[test]
+    fn counts_are_clamped() {
+        let server = parse_reply(from(), &reply("big", 100_000, Some(70_000), 7777)).unwrap();
+        assert_eq!(server.players, u16::MAX);
+        assert_eq!(server.max_players, Some(u16::MAX));
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_char_boundary() {
+        let name = "é".repeat(MAX_NAME_LEN);
+        let payload = reply(&name, 1, None, 7777);
+        assert!(payload.len() <= QUERY_LEN);
+        let server = parse_reply(from(), &payload).unwrap();
+        assert_eq!(server.name, "é".repeat(MAX_NAME_LEN / 2));
+        assert_eq!(truncate("aé", 2), "a");
+    }
+
+    #[test]
+    fn bad_replies_are_ignored() {
+        let payload = reply("server", 1, None, 7777);
+        for len in 0..payload.len() {
+            assert_eq!(parse_reply(from(), &payload[..len]), None, "truncated to {}", len);
+        }
+        assert_eq!(parse_reply(from(), &query()), None);
+        let mut other = payload.clone();
+        other[0] = b'X';
+        assert_eq!(parse_reply(from(), &other), None);
+    }
+}
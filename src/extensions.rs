@@ -0,0 +1,127 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+// Typed storage for whatever the game wants to hang off a peer: player ids, names, auth info.
+// One value per type, so wrap things in newtypes to keep them apart. Everything in it is dropped
+// along with the Peer, so it can't go stale after a disconnect.
+
+/// A small type map, at most one value of each type
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// store a value, handing back the previous one of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    /// the stored value, inserting one from `f` first if there isn't one
+    pub fn get_or_insert_with<T: Send + Sync + 'static>(&mut self, f: impl FnOnce() -> T) -> &mut T {
+        self.map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()))
+            .downcast_mut()
+            .unwrap()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+// the values needn't be Debug, so just say how many there are
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct PlayerName(String);
+
+    #[derive(Debug, PartialEq)]
+    struct Score(u32);
+
+    #[test]
+    fn one_value_per_type() {
+        let mut extensions = Extensions::default();
+        assert!(extensions.is_empty());
+        assert_eq!(extensions.insert(PlayerName("rj".into())), None);
+        assert_eq!(extensions.insert(Score(3)), None);
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions.get::<PlayerName>(), Some(&PlayerName("rj".into())));
+        assert_eq!(extensions.get::<Score>(), Some(&Score(3)));
+        assert_eq!(extensions.get::<u32>(), None);
+        assert!(!extensions.contains::<u32>());
+    }
+
+    #[test]
+    fn insert_replaces() {
+        let mut extensions = Extensions::default();
+        extensions.insert(Score(3));
+        assert_eq!(extensions.insert(Score(4)), Some(Score(3)));
+        assert_eq!(extensions.get::<Score>(), Some(&Score(4)));
+        assert_eq!(extensions.len(), 1);
+    }
+
+    #[test]
+    fn get_mut_and_get_or_insert_with() {
+        let mut extensions = Extensions::default();
+        extensions.get_or_insert_with(|| Score(1)).0 += 1;
+        extensions.get_or_insert_with(|| Score(100)).0 += 1;
+        assert_eq!(extensions.get::<Score>(), Some(&Score(3)));
+        extensions.get_mut::<Score>().unwrap().0 = 10;
+        assert_eq!(extensions.get::<Score>(), Some(&Score(10)));
+        assert!(extensions.get_mut::<PlayerName>().is_none());
+    }
+
+    #[test]
+    fn remove_by_type() {
+        let mut extensions = Extensions::default();
+        extensions.insert(PlayerName("rj".into()));
+        extensions.insert(Score(3));
+        assert_eq!(extensions.remove::<Score>(), Some(Score(3)));
+        assert_eq!(extensions.remove::<Score>(), None);
+        assert!(extensions.contains::<PlayerName>());
+        extensions.clear();
+        assert!(extensions.is_empty());
+        assert_eq!(format!("{:?}", extensions), "Extensions { len: 0 }");
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    fmt,
+    fs,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use instant::Instant;
+
+// Address filtering for the server, checked on every datagram before laminar sees it, so a
+// blocked address never gets a connection or a Peer.
+//
+// Ban list files are one rule per line, # starts a comment:
+//
+//  deny 203.0.113.7
+//  deny 198.51.100.0/24 3600     <- timed ban, in seconds
+//  allow 10.0.0.0/8
+//
+// Denies always win. If there are any allow rules, only addresses they match get through.
+
+/// A single address or a CIDR range, eg. "192.168.0.0/16" or "2001:db8::/32"
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// None if the prefix is too long for the address family
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let addr = normalize(addr);
+        if prefix > max_prefix(addr) {
+            return None;
+        }
+        // store the network address, so equal ranges compare equal
+        let addr = match addr {
+            IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & v4_mask(prefix)).into()),
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & v6_mask(prefix)).into()),
+        };
+        Some(Self { addr, prefix })
+    }
+
+    /// just this one address
+    pub fn single(addr: IpAddr) -> Self {
+        let addr = normalize(addr);
+        Self { addr, prefix: max_prefix(addr) }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, normalize(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => u32::from(ip) & v4_mask(self.prefix) == u32::from(net),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => u128::from(ip) & v6_mask(self.prefix) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        Self::single(addr)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || FilterError::InvalidRange(s.to_string());
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+                let prefix = prefix.parse::<u8>().map_err(|_| invalid())?;
+                Self::new(addr, prefix).ok_or_else(invalid)
+            }
+            None => s.parse::<IpAddr>().map(Self::single).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == max_prefix(self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FilterError {
+    InvalidRange(String),
+    /// a ban list line we couldn't make sense of, numbered from 1
+    InvalidLine(usize, String),
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::InvalidRange(range) => write!(f, "invalid address or range '{}'", range),
+            FilterError::InvalidLine(line, text) => write!(f, "line {}: can't parse '{}'", line, text),
+        }
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Default)]
+struct FilterState {
+    allow: Vec<IpRange>,
+    // None never expires
+    deny: Vec<(IpRange, Option<Instant>)>,
+}
+
+impl FilterState {
+    fn parse(text: &str) -> Result<Self, FilterError> {
+        let now = Instant::now();
+        let mut state = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || FilterError::InvalidLine(index + 1, line.to_string());
+            let mut words = line.split_whitespace();
+            let (rule, range) = match (words.next(), words.next()) {
+                (Some(rule), Some(range)) => (rule, range.parse::<IpRange>().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            };
+            let seconds = match words.next() {
+                Some(seconds) => Some(seconds.parse::<u64>().map_err(|_| invalid())?),
+                None => None,
+            };
+            match (rule, seconds) {
+                ("allow", None) => state.allow.push(range),
+                ("deny", seconds) => {
+                    let until = seconds.map(|seconds| now + Duration::from_secs(seconds));
+                    state.deny.push((range, until));
+                }
+                _ => return Err(invalid()),
+            }
+            if words.next().is_some() {
+                return Err(invalid());
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// Handle to the server's allow and deny lists, from NetworkResource::address_filter().
+/// Cheap to clone, and changes apply to the very next datagram.
+#[derive(Debug, Clone, Default)]
+pub struct AddressFilter {
+    state: Arc<Mutex<FilterState>>,
+}
+
+impl AddressFilter {
+    /// ban an address or range until unbanned
+    pub fn ban(&self, range: impl Into<IpRange>) {
+        self.deny(range.into(), None);
+    }
+
+    /// ban an address or range for a while
+    pub fn ban_for(&self, range: impl Into<IpRange>, duration: Duration) {
+        self.deny(range.into(), Some(Instant::now() + duration));
+    }
+
+    fn deny(&self, range: IpRange, until: Option<Instant>) {
+        let mut state = self.state.lock().unwrap();
+        state.deny.retain(|(r, _)| *r != range);
+        state.deny.push((range, until));
+    }
+
+    /// lift a ban added for exactly this address or range
+    pub fn unban(&self, range: impl Into<IpRange>) -> bool {
+        let range = range.into();
+        let mut state = self.state.lock().unwrap();
+        let before = state.deny.len();
+        state.deny.retain(|(r, _)| *r != range);
+        state.deny.len() != before
+    }
+
+    /// once anything is allowed, everything else is blocked
+    pub fn allow(&self, range: impl Into<IpRange>) {
+        let range = range.into();
+        let mut state = self.state.lock().unwrap();
+        if !state.allow.contains(&range) {
+            state.allow.push(range);
+        }
+    }
+
+    pub fn remove_allowed(&self, range: impl Into<IpRange>) -> bool {
+        let range = range.into();
+        let mut state = self.state.lock().unwrap();
+        let before = state.allow.len();
+        state.allow.retain(|r| *r != range);
+        state.allow.len() != before
+    }
+
+    /// current bans, with the time left on timed ones
+    pub fn bans(&self) -> Vec<(IpRange, Option<Duration>)> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.deny.retain(|(_, until)| until.map_or(true, |until| until > now));
+        state
+            .deny
+            .iter()
+            .map(|(range, until)| (*range, until.map(|until| until - now)))
+            .collect()
+    }
+
+    pub fn allowed(&self) -> Vec<IpRange> {
+        self.state.lock().unwrap().allow.clone()
+    }
+
+    /// drop every rule
+    pub fn clear(&self) {
+        *self.state.lock().unwrap() = FilterState::default();
+    }
+
+    /// replace every rule with the ones in a ban list file. on error nothing changes.
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        let state = FilterState::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        *self.state.lock().unwrap() = state;
+        Ok(())
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.deny.retain(|(_, until)| until.map_or(true, |until| until > now));
+        if state.deny.iter().any(|(range, _)| range.contains(ip)) {
+            return false;
+        }
+        state.allow.is_empty() || state.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+// treat ipv4-mapped ipv6 addresses (::ffff:a.b.c.d) as the ipv4 address they are
+fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from(((hi as u32) << 16) | lo as u32))
+            }
+            _ => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(range("192.168.1.77/16"), range("192.168.0.0/16"));
+        assert_eq!(range("192.168.1.77/16").to_string(), "192.168.0.0/16");
+        assert_eq!(range("10.0.0.1").to_string(), "10.0.0.1");
+        assert_eq!(range("10.0.0.1/32"), range("10.0.0.1"));
+        assert_eq!(range("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("2001:db8::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/x".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let lan = range("192.168.0.0/16");
+        assert!(lan.contains(ip("192.168.0.1")));
+        assert!(lan.contains(ip("192.168.255.255")));
+        assert!(!lan.contains(ip("192.169.0.1")));
+        assert!(!lan.contains(ip("2001:db8::1")));
+
+        let v6 = range("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn zero_and_full_prefixes() {
+        let everything = range("0.0.0.0/0");
+        assert!(everything.contains(ip("1.2.3.4")));
+        assert!(everything.contains(ip("255.255.255.255")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+
+        let single = range("10.1.2.3/32");
+        assert!(single.contains(ip("10.1.2.3")));
+        assert!(!single.contains(ip("10.1.2.4")));
+        assert!(range("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!range("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6_is_ipv4() {
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert_eq!(range("::ffff:10.1.2.3"), range("10.1.2.3"));
+        assert!(range("::ffff:10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!range("::/0").contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn denies_win_over_allows() {
+        let filter = AddressFilter::default();
+        assert!(filter.is_allowed(ip("1.2.3.4")));
+        filter.allow(range("10.0.0.0/8"));
+        assert!(!filter.is_allowed(ip("1.2.3.4")));
+        assert!(filter.is_allowed(ip("10.0.0.1")));
+        filter.ban(ip("10.0.0.1"));
+        assert!(!filter.is_allowed(ip("10.0.0.1")));
+        assert!(filter.unban(ip("10.0.0.1")));
+        assert!(filter.is_allowed(ip("10.0.0.1")));
+        assert!(filter.remove_allowed(range("10.0.0.0/8")));
+        assert!(filter.is_allowed(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn timed_bans_expire() {
+        let filter = AddressFilter::default();
+        filter.ban_for(ip("1.2.3.4"), Duration::from_secs(0));
+        assert!(filter.is_allowed(ip("1.2.3.4")));
+        assert!(filter.bans().is_empty());
+        filter.ban_for(ip("1.2.3.4"), Duration::from_secs(60));
+        assert!(!filter.is_allowed(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn parses_ban_lists() {
+        let state = FilterState::parse("# comment\n\ndeny 203.0.113.7\ndeny 198.51.100.0/24 3600 # timed\nallow 10.0.0.0/8\n").unwrap();
+        assert_eq!(state.allow, vec![range("10.0.0.0/8")]);
+        assert_eq!(state.deny.len(), 2);
+        assert!(state.deny[0].1.is_none());
+        assert!(state.deny[1].1.is_some());
+
+        assert_eq!(FilterState::parse("deny").unwrap_err(), FilterError::InvalidLine(1, "deny".into()));
+        assert!(FilterState::parse("\nallow 10.0.0.0/8 60").is_err());
+        assert!(FilterState::parse("block 10.0.0.1").is_err());
+        assert!(FilterState::parse("deny 10.0.0.1 60 extra").is_err());
+    }
+}
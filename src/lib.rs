@@ -1,24 +1,183 @@
-use std::net::SocketAddr;
+use std::{collections::HashSet, net::SocketAddr};
+
+use bevy::{
+    log,
+    app::EventWriter,
+    ecs::{prelude::*, system::SystemParam},
+};
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod server;
 
 pub mod client;
 
+pub mod clock;
+
+pub mod rpc;
+
+pub mod transfer;
+
+pub mod snapshot;
+
+pub mod capture;
+
+pub mod conditioner;
+
+pub mod channel;
+
+pub mod batch;
+
+pub mod filter;
+
+pub mod ratelimit;
+
+pub mod settings;
+
+pub mod extensions;
+
+pub mod lobby;
+
+pub mod relay;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod discovery;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod query;
+
+pub mod session;
+
+mod protocol;
+
+mod challenge;
+
 // for our connection tracking. we are hiding laminars connection events and exposing our
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum DisconnectReason {
     Timedout,
+    // laminar dropped the connection without timing out first
+    Closed,
     // another peer connection from same src addr replaced us
     // Replaced,
 }
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+// non_exhaustive, so adding states isn't a breaking change for matches outside the crate
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
 pub enum ConnectionState {
     Uninitialized,
     Connecting,
     Connected,
+    // we or the other side asked to disconnect, waiting for it to finish
+    Disconnecting,
     Timeout,
     Disconnected,
+    // server only: the connection dropped, but the peer can resume its session for a while
+    Suspended,
+}
+
+impl ConnectionState {
+    /// the transitions the state machine allows, anything else is ignored
+    pub fn can_become(self, to: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, to),
+            (Uninitialized, Connecting)
+                | (Connecting, Connected)
+                | (Connecting, Disconnecting)
+                | (Connecting, Timeout)
+                | (Connecting, Disconnected)
+                | (Connected, Disconnecting)
+                | (Connected, Timeout)
+                | (Connected, Disconnected)
+                | (Disconnecting, Disconnected)
+                | (Timeout, Disconnected)
+                | (Disconnected, Connecting)
+                | (Connected, Suspended)
+                | (Timeout, Suspended)
+                | (Suspended, Connected)
+                | (Suspended, Disconnected)
+        )
+    }
+}
+
+/// What caused a connection to change state
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum TransitionCause {
+    /// a new peer said hello, or we started connecting
+    Handshake,
+    /// laminar saw traffic both ways, or a packet beat laminar's connect event
+    Established,
+    /// disconnect() was called on this side
+    LocalDisconnect,
+    /// the other side said goodbye
+    RemoteDisconnect,
+    /// nothing heard from the other side for laminar's idle timeout
+    Timeout,
+    /// laminar dropped the connection
+    Dropped,
+    /// a suspended peer came back with its session token
+    Resumed,
+    /// client only: the server was full
+    Rejected,
+}
+
+/// Bevy event, sent for every connection state change, on both client and server
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStateChanged {
+    pub handle: PeerHandle,
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+    pub cause: TransitionCause,
+}
+
+/// Connection state for one peer (or the server, on the client). Every state change on either
+/// side goes through `transition`, so events arriving out of order can't corrupt it.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStateMachine {
+    state: ConnectionState,
+    cause: Option<TransitionCause>,
+}
+
+impl Default for ConnectionStateMachine {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Uninitialized,
+            cause: None,
+        }
+    }
+}
+
+impl ConnectionStateMachine {
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// why we got into the current state
+    pub fn cause(&self) -> Option<TransitionCause> {
+        self.cause
+    }
+
+    /// the change to publish, or None if we're already there or it isn't allowed.
+    /// disallowed transitions are late or duplicate events, so they're logged and dropped.
+    pub(crate) fn transition(&mut self, handle: PeerHandle, to: ConnectionState, cause: TransitionCause) -> Option<ConnectionStateChanged> {
+        if to == self.state {
+            return None;
+        }
+        if !self.state.can_become(to) {
+            log::debug!("Ignoring {:?} -> {:?} ({:?}) for {}", self.state, to, cause, handle);
+            return None;
+        }
+        let from = self.state;
+        self.state = to;
+        self.cause = Some(cause);
+        Some(ConnectionStateChanged { handle, from, to, cause })
+    }
+
+    // replays reproduce recorded states as they were, without validating them
+    pub(crate) fn force(&mut self, state: ConnectionState) {
+        self.state = state;
+    }
 }
 
 // opaque handle to this peer that our api exposes, but it's just the socketaddr for now.
@@ -35,14 +194,133 @@ pub type PeerHandle = SocketAddr;
 //     state: ConnectionState,
 // }
 
+// kept for existing users, the events below are sent alongside it
 #[derive(Debug)]
 pub enum PeerEvent {
     Status(PeerHandle, ConnectionState),
     Packet(laminar::Packet),
 }
 
+/// Bevy event, a peer finished connecting (or on the client, we connected to the server)
+#[derive(Debug, Clone, Copy)]
+pub struct PeerConnected {
+    pub handle: PeerHandle,
+}
+
+/// Bevy event, a peer is gone. Timeouts also send a PeerTimedOut just before this.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerDisconnected {
+    pub handle: PeerHandle,
+    pub reason: DisconnectReason,
+}
+
+/// Bevy event, a peer stopped responding. Its PeerDisconnected follows.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerTimedOut {
+    pub handle: PeerHandle,
+}
+
+/// Bevy event, a user payload from a peer
+#[derive(Debug, Clone)]
+pub struct MessageReceived {
+    pub handle: PeerHandle,
+    pub packet: laminar::Packet,
+}
+
+/// Labels for the networking systems, to order your own systems against.
+/// Receive runs in PreUpdate, publishing this frame's events before Update reads them.
+/// Flush runs in PostUpdate, so anything sent during Update goes out the same frame.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, SystemLabel)]
+pub enum NetworkSystem {
+    Receive,
+    Flush,
+}
+
+// every peer event writer, so the pollers publish a PeerEvent and its split event in one go
+#[derive(SystemParam)]
+pub(crate) struct PeerEventWriters<'a> {
+    legacy: EventWriter<'a, PeerEvent>,
+    connected: EventWriter<'a, PeerConnected>,
+    disconnected: EventWriter<'a, PeerDisconnected>,
+    timed_out: EventWriter<'a, PeerTimedOut>,
+    messages: EventWriter<'a, MessageReceived>,
+    transitions: EventWriter<'a, ConnectionStateChanged>,
+    // peers we've seen time out, so their disconnect gets the right reason
+    timed_out_peers: Local<'a, HashSet<PeerHandle>>,
+    // suspended peers, who don't get a second PeerConnected when they resume
+    suspended_peers: Local<'a, HashSet<PeerHandle>>,
+}
+
+impl<'a> PeerEventWriters<'a> {
+    pub(crate) fn send_transition(&mut self, change: ConnectionStateChanged) {
+        self.transitions.send(change);
+    }
+
+    pub(crate) fn send(&mut self, event: PeerEvent) {
+        match &event {
+            PeerEvent::Status(handle, ConnectionState::Connected) => {
+                self.timed_out_peers.remove(handle);
+                if !self.suspended_peers.remove(handle) {
+                    self.connected.send(PeerConnected { handle: *handle });
+                }
+            },
+            PeerEvent::Status(handle, ConnectionState::Suspended) => {
+                self.suspended_peers.insert(*handle);
+            },
+            PeerEvent::Status(handle, ConnectionState::Timeout) => {
+                self.timed_out_peers.insert(*handle);
+                self.timed_out.send(PeerTimedOut { handle: *handle });
+            },
+            PeerEvent::Status(handle, ConnectionState::Disconnected) => {
+                self.suspended_peers.remove(handle);
+                let reason = if self.timed_out_peers.remove(handle) {
+                    DisconnectReason::Timedout
+                } else {
+                    DisconnectReason::Closed
+                };
+                self.disconnected.send(PeerDisconnected { handle: *handle, reason });
+            },
+            PeerEvent::Status(..) => {},
+            PeerEvent::Packet(packet) => {
+                self.messages.send(MessageReceived { handle: packet.addr(), packet: packet.clone() });
+            },
+        }
+        self.legacy.send(event);
+    }
+}
+
 pub mod prelude {
     pub use super::{DisconnectReason, ConnectionState, PeerHandle, PeerEvent};
+    pub use super::{PeerConnected, PeerDisconnected, PeerTimedOut, MessageReceived};
+    pub use super::NetworkSystem;
+    pub use super::{ConnectionStateChanged, ConnectionStateMachine, TransitionCause};
+    pub use super::rpc::{RpcHandle, RpcError, RpcRequest, RpcResponse};
+    pub use super::transfer::{
+        TransferConfig, TransferDirection, TransferError, TransferHandle,
+        TransferProgress, TransferCompleted, TransferFailed,
+    };
+    pub use super::snapshot::{SnapshotConfig, SnapshotReceived};
+    pub use super::capture::{CaptureEvent, CaptureMode, CaptureReader, CaptureRecord, PacketRecorder, PacketReplayer};
+    pub use super::conditioner::{BurstLoss, LinkConditioner, LinkProfile, LinkProfiles};
+    pub use super::channel::{ChannelConfig, ChannelError, ChannelId, ChannelKind, ChannelMessage, ChannelRegistry};
+    pub use super::batch::BatchConfig;
+    pub use super::filter::{AddressFilter, FilterError, IpRange};
+    pub use super::ratelimit::{RateLimitConfig, RateLimitExceeded};
+    pub use super::settings::{ConditionerSettings, LaminarSettings, NetworkSettings};
+    pub use super::extensions::Extensions;
+    pub use super::session::SessionToken;
+    pub use super::lobby::{
+        LobbyClient, LobbyClientPlugin, LobbyError, LobbyEvent, LobbyId, LobbyInfo, LobbyMember, LobbySettings,
+    };
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use super::lobby::{LobbyServer, LobbyServerPlugin, LobbyStarted};
+    pub use super::relay::{RelayClient, RelayClientPlugin, RelayError, RelayEvent, RelayRole};
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use super::relay::{RelayServer, RelayServerPlugin};
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use super::discovery::{DiscoveryConfig, ServerDiscovered, DEFAULT_DISCOVERY_PORT};
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use super::query::{ServerInfo, ServerInfoReceived};
 
     pub trait GetPeerHandleFromLaminarPacket {
         fn peer_handle(&self) -> PeerHandle;
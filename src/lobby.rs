@@ -0,0 +1,1079 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    fmt,
+    net::SocketAddr,
+};
+
+use bevy::{
+    log,
+    app::{AppBuilder, CoreStage, EventWriter, Plugin},
+    ecs::prelude::*,
+};
+
+use laminar::Packet as LaminarPacket;
+
+use crate::client;
+use crate::protocol::{read_addr, read_str, read_varint, write_addr, write_str, write_varint, MessageKind};
+use crate::{ConnectionState, NetworkSystem, PeerHandle};
+
+// Lobbies, run by a server with LobbyServerPlugin and used by clients with LobbyClientPlugin.
+// Clients create, list and join lobbies, ready up, and when the host starts the game every member
+// is sent the address of the game server to connect to. That can be the lobby server itself, or
+// anything else, eg. a server the host is running.
+//
+// Requests and replies are MessageKind::Lobby messages, reliable and ordered:
+//
+//  Create:  [0][settings][player_name]         Joined:  [17][member id: u32][lobby]
+//  List:    [1]                                List:    [16][count: varint][lobby]*
+//  Join:    [2][lobby id: u32][player_name]    Changed: [18][lobby]
+//  Leave:   [3]                                Left:    [19]
+//  Ready:   [4][ready: u8]                     Started: [20][lobby id: u32][addr]
+//  Start:   [5][0, or 1 + addr]                Error:   [21][error: u8]
+//
+//  settings: [name][max_players: u8][count: varint]([key][value])*
+//  lobby:    [id: u32][settings][count: varint]([member id: u32][name][ready: u8][host: u8])*
+//
+// strings are length prefixed utf8.
+//
+// The server keeps lobbies small enough that any one of them fits in a laminar packet with room
+// to spare, and cuts lobby lists short rather than sending more than MAX_LIST_LEN.
+
+// most properties a lobby can have
+const MAX_PROPERTIES: usize = 16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct LobbyId(pub u32);
+
+impl fmt::Display for LobbyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbySettings {
+    pub name: String,
+    pub max_players: u8,
+    /// anything else the game wants to show in lobby lists, eg. map or mode
+    pub properties: BTreeMap<String, String>,
+}
+
+impl Default for LobbySettings {
+    fn default() -> Self {
+        Self {
+            name: "Lobby".to_string(),
+            max_players: 8,
+            properties: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbyMember {
+    /// unique within the lobby
+    pub id: u32,
+    pub name: String,
+    pub ready: bool,
+    pub host: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbyInfo {
+    pub id: LobbyId,
+    pub settings: LobbySettings,
+    pub members: Vec<LobbyMember>,
+}
+
+impl LobbyInfo {
+    pub fn host(&self) -> Option<&LobbyMember> {
+        self.members.iter().find(|member| member.host)
+    }
+
+    pub fn member(&self, id: u32) -> Option<&LobbyMember> {
+        self.members.iter().find(|member| member.id == id)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.members.len() >= self.settings.max_players as usize
+    }
+
+    /// everyone but the host has readied up, the host starting counts as them being ready
+    pub fn all_ready(&self) -> bool {
+        self.members.iter().all(|member| member.ready || member.host)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LobbyError {
+    NotFound,
+    Full,
+    AlreadyInLobby,
+    NotInLobby,
+    NotHost,
+    /// someone hasn't readied up
+    NotReady,
+    /// the host didn't give a game server, and the lobby server doesn't have one
+    NoGameServer,
+    /// empty or overlong names, max_players of 0 or over 64, or more than 16 properties or ones
+    /// over 64 bytes
+    InvalidSettings,
+}
+
+impl LobbyError {
+    fn from_u8(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => LobbyError::NotFound,
+            1 => LobbyError::Full,
+            2 => LobbyError::AlreadyInLobby,
+            3 => LobbyError::NotInLobby,
+            4 => LobbyError::NotHost,
+            5 => LobbyError::NotReady,
+            6 => LobbyError::NoGameServer,
+            7 => LobbyError::InvalidSettings,
+            _ => return None,
+        })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn to_u8(self) -> u8 {
+        match self {
+            LobbyError::NotFound => 0,
+            LobbyError::Full => 1,
+            LobbyError::AlreadyInLobby => 2,
+            LobbyError::NotInLobby => 3,
+            LobbyError::NotHost => 4,
+            LobbyError::NotReady => 5,
+            LobbyError::NoGameServer => 6,
+            LobbyError::InvalidSettings => 7,
+        }
+    }
+}
+
+impl fmt::Display for LobbyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            LobbyError::NotFound => "no such lobby",
+            LobbyError::Full => "lobby is full",
+            LobbyError::AlreadyInLobby => "already in a lobby",
+            LobbyError::NotInLobby => "not in a lobby",
+            LobbyError::NotHost => "only the host can do that",
+            LobbyError::NotReady => "not everyone is ready",
+            LobbyError::NoGameServer => "no game server to start on",
+            LobbyError::InvalidSettings => "invalid lobby settings",
+        };
+        f.write_str(text)
+    }
+}
+
+impl std::error::Error for LobbyError {}
+
+/// Bevy event (client), the lobby server's answers and news
+#[derive(Debug, Clone, PartialEq)]
+pub enum LobbyEvent {
+    List(Vec<LobbyInfo>),
+    /// we created or joined this lobby
+    Joined(LobbyInfo),
+    /// someone joined, left or changed their ready state
+    Changed(LobbyInfo),
+    Left,
+    /// the host started the game, time to connect to `game_server`
+    Started { lobby: LobbyId, game_server: SocketAddr },
+    Error(LobbyError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum LobbyRequest {
+    Create { settings: LobbySettings, player_name: String },
+    List,
+    Join { lobby: LobbyId, player_name: String },
+    Leave,
+    Ready(bool),
+    Start(Option<SocketAddr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum LobbyReply {
+    List(Vec<LobbyInfo>),
+    Joined { me: u32, lobby: LobbyInfo },
+    Changed(LobbyInfo),
+    Left,
+    Started { lobby: LobbyId, game_server: SocketAddr },
+    Error(LobbyError),
+}
+
+const CREATE: u8 = 0;
+const LIST: u8 = 1;
+const JOIN: u8 = 2;
+const LEAVE: u8 = 3;
+const READY: u8 = 4;
+const START: u8 = 5;
+
+const REPLY_LIST: u8 = 16;
+const REPLY_JOINED: u8 = 17;
+const REPLY_CHANGED: u8 = 18;
+const REPLY_LEFT: u8 = 19;
+const REPLY_STARTED: u8 = 20;
+const REPLY_ERROR: u8 = 21;
+
+impl LobbyRequest {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            LobbyRequest::Create { settings, player_name } => {
+                buf.push(CREATE);
+                write_settings(&mut buf, settings);
+                write_str(&mut buf, player_name);
+            }
+            LobbyRequest::List => buf.push(LIST),
+            LobbyRequest::Join { lobby, player_name } => {
+                buf.push(JOIN);
+                buf.extend_from_slice(&lobby.0.to_le_bytes());
+                write_str(&mut buf, player_name);
+            }
+            LobbyRequest::Leave => buf.push(LEAVE),
+            LobbyRequest::Ready(ready) => {
+                buf.push(READY);
+                buf.push(*ready as u8);
+            }
+            LobbyRequest::Start(game_server) => {
+                buf.push(START);
+                match game_server {
+                    Some(addr) => {
+                        buf.push(1);
+                        write_addr(&mut buf, *addr);
+                    }
+                    None => buf.push(0),
+                }
+            }
+        }
+        buf
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let bytes = &mut bytes;
+        Some(match read_u8(bytes)? {
+            CREATE => LobbyRequest::Create {
+                settings: read_settings(bytes)?,
+                player_name: read_str(bytes)?,
+            },
+            LIST => LobbyRequest::List,
+            JOIN => LobbyRequest::Join {
+                lobby: LobbyId(read_u32(bytes)?),
+                player_name: read_str(bytes)?,
+            },
+            LEAVE => LobbyRequest::Leave,
+            READY => LobbyRequest::Ready(read_u8(bytes)? != 0),
+            START => match read_u8(bytes)? {
+                0 => LobbyRequest::Start(None),
+                _ => LobbyRequest::Start(Some(read_addr(bytes)?)),
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl LobbyReply {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            LobbyReply::List(lobbies) => {
+                buf.push(REPLY_LIST);
+                write_varint(&mut buf, lobbies.len());
+                for lobby in lobbies {
+                    write_lobby(&mut buf, lobby);
+                }
+            }
+            LobbyReply::Joined { me, lobby } => {
+                buf.push(REPLY_JOINED);
+                buf.extend_from_slice(&me.to_le_bytes());
+                write_lobby(&mut buf, lobby);
+            }
+            LobbyReply::Changed(lobby) => {
+                buf.push(REPLY_CHANGED);
+                write_lobby(&mut buf, lobby);
+            }
+            LobbyReply::Left => buf.push(REPLY_LEFT),
+            LobbyReply::Started { lobby, game_server } => {
+                buf.push(REPLY_STARTED);
+                buf.extend_from_slice(&lobby.0.to_le_bytes());
+                write_addr(&mut buf, *game_server);
+            }
+            LobbyReply::Error(error) => {
+                buf.push(REPLY_ERROR);
+                buf.push(error.to_u8());
+            }
+        }
+        buf
+    }
+
+    fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let bytes = &mut bytes;
+        Some(match read_u8(bytes)? {
+            REPLY_LIST => {
+                let count = read_varint(bytes)?;
+                // each lobby is at least a few bytes, don't trust the count for the allocation
+                let mut lobbies = Vec::with_capacity(count.min(bytes.len()));
+                for _ in 0..count {
+                    lobbies.push(read_lobby(bytes)?);
+                }
+                LobbyReply::List(lobbies)
+            }
+            REPLY_JOINED => LobbyReply::Joined {
+                me: read_u32(bytes)?,
+                lobby: read_lobby(bytes)?,
+            },
+            REPLY_CHANGED => LobbyReply::Changed(read_lobby(bytes)?),
+            REPLY_LEFT => LobbyReply::Left,
+            REPLY_STARTED => LobbyReply::Started {
+                lobby: LobbyId(read_u32(bytes)?),
+                game_server: read_addr(bytes)?,
+            },
+            REPLY_ERROR => LobbyReply::Error(LobbyError::from_u8(read_u8(bytes)?)?),
+            _ => return None,
+        })
+    }
+}
+
+fn write_settings(buf: &mut Vec<u8>, settings: &LobbySettings) {
+    write_str(buf, &settings.name);
+    buf.push(settings.max_players);
+    write_varint(buf, settings.properties.len());
+    for (key, value) in &settings.properties {
+        write_str(buf, key);
+        write_str(buf, value);
+    }
+}
+
+fn read_settings(bytes: &mut &[u8]) -> Option<LobbySettings> {
+    let name = read_str(bytes)?;
+    let max_players = read_u8(bytes)?;
+    let count = read_varint(bytes)?;
+    if count > MAX_PROPERTIES {
+        return None;
+    }
+    let mut properties = BTreeMap::new();
+    for _ in 0..count {
+        properties.insert(read_str(bytes)?, read_str(bytes)?);
+    }
+    Some(LobbySettings { name, max_players, properties })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn write_lobby(buf: &mut Vec<u8>, lobby: &LobbyInfo) {
+    buf.extend_from_slice(&lobby.id.0.to_le_bytes());
+    write_settings(buf, &lobby.settings);
+    write_varint(buf, lobby.members.len());
+    for member in &lobby.members {
+        buf.extend_from_slice(&member.id.to_le_bytes());
+        write_str(buf, &member.name);
+        buf.push(member.ready as u8);
+        buf.push(member.host as u8);
+    }
+}
+
+fn read_lobby(bytes: &mut &[u8]) -> Option<LobbyInfo> {
+    let id = LobbyId(read_u32(bytes)?);
+    let settings = read_settings(bytes)?;
+    let count = read_varint(bytes)?;
+    let mut members = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        members.push(LobbyMember {
+            id: read_u32(bytes)?,
+            name: read_str(bytes)?,
+            ready: read_u8(bytes)? != 0,
+            host: read_u8(bytes)? != 0,
+        });
+    }
+    Some(LobbyInfo { id, settings, members })
+}
+
+fn read_u8(bytes: &mut &[u8]) -> Option<u8> {
+    let (&byte, rest) = bytes.split_first()?;
+    *bytes = rest;
+    Some(byte)
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Option<u32> {
+    let value = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+    *bytes = &bytes[4..];
+    Some(value)
+}
+
+// reliable and ordered, on laminar's default stream
+fn lobby_packet(addr: SocketAddr, payload: Vec<u8>) -> LaminarPacket {
+    LaminarPacket::reliable_ordered(addr, payload, None)
+}
+
+/// Bevy resource (client), added by LobbyClientPlugin. Requests are sent at the end of the frame,
+/// or once we're connected, and answered with LobbyEvents.
+#[derive(Debug, Default)]
+pub struct LobbyClient {
+    current: Option<LobbyInfo>,
+    me: Option<u32>,
+    lobbies: Vec<LobbyInfo>,
+    outbox: Vec<LobbyRequest>,
+}
+
+impl LobbyClient {
+    /// create a lobby and join it as its host
+    pub fn create(&mut self, settings: LobbySettings, player_name: &str) {
+        self.outbox.push(LobbyRequest::Create { settings, player_name: player_name.to_string() });
+    }
+
+    /// ask for the open lobbies, answered with LobbyEvent::List
+    pub fn list(&mut self) {
+        self.outbox.push(LobbyRequest::List);
+    }
+
+    pub fn join(&mut self, lobby: LobbyId, player_name: &str) {
+        self.outbox.push(LobbyRequest::Join { lobby, player_name: player_name.to_string() });
+    }
+
+    pub fn leave(&mut self) {
+        self.outbox.push(LobbyRequest::Leave);
+    }
+
+    pub fn set_ready(&mut self, ready: bool) {
+        self.outbox.push(LobbyRequest::Ready(ready));
+    }
+
+    /// host only. everyone gets LobbyEvent::Started with `game_server`, or the lobby server's
+    /// own game server if None.
+    pub fn start(&mut self, game_server: Option<SocketAddr>) {
+        self.outbox.push(LobbyRequest::Start(game_server));
+    }
+
+    /// the lobby we're in, as of the last update from the server
+    pub fn current(&self) -> Option<&LobbyInfo> {
+        self.current.as_ref()
+    }
+
+    /// our own entry in the current lobby
+    pub fn me(&self) -> Option<&LobbyMember> {
+        self.current.as_ref()?.member(self.me?)
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.me().map_or(false, |me| me.host)
+    }
+
+    /// the last lobby list we asked for
+    pub fn lobbies(&self) -> &[LobbyInfo] {
+        &self.lobbies
+    }
+
+    fn handle(&mut self, reply: LobbyReply) -> LobbyEvent {
+        match reply {
+            LobbyReply::List(lobbies) => {
+                self.lobbies = lobbies.clone();
+                LobbyEvent::List(lobbies)
+            }
+            LobbyReply::Joined { me, lobby } => {
+                self.me = Some(me);
+                self.current = Some(lobby.clone());
+                LobbyEvent::Joined(lobby)
+            }
+            LobbyReply::Changed(lobby) => {
+                self.current = Some(lobby.clone());
+                LobbyEvent::Changed(lobby)
+            }
+            LobbyReply::Left => {
+                self.leave_current();
+                LobbyEvent::Left
+            }
+            LobbyReply::Started { lobby, game_server } => {
+                // the lobby's gone now
+                self.leave_current();
+                LobbyEvent::Started { lobby, game_server }
+            }
+            LobbyReply::Error(error) => LobbyEvent::Error(error),
+        }
+    }
+
+    fn leave_current(&mut self) {
+        self.current = None;
+        self.me = None;
+    }
+}
+
+/// Client side of the lobby service, add it after ClientNetworkingPlugin
+#[derive(Default)]
+pub struct LobbyClientPlugin;
+
+impl Plugin for LobbyClientPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+        .init_resource::<LobbyClient>()
+        .add_event::<LobbyEvent>()
+        .add_system_to_stage(CoreStage::PreUpdate, lobby_client_receiver.system().after(NetworkSystem::Receive))
+        .add_system_to_stage(CoreStage::PostUpdate, lobby_client_sender.system().before(NetworkSystem::Flush))
+        ;
+    }
+}
+
+// PreUpdate, after laminar_receiver: turn replies into LobbyEvents
+fn lobby_client_receiver(
+    mut net: ResMut<client::NetworkResource>,
+    mut lobby: ResMut<LobbyClient>,
+    mut events: EventWriter<LobbyEvent>,
+) {
+    for (_, payload) in std::mem::take(&mut net.lobby_inbox) {
+        match LobbyReply::decode(&payload) {
+            Some(reply) => events.send(lobby.handle(reply)),
+            None => log::warn!("Malformed lobby message from server"),
+        }
+    }
+    // the server forgets about us when we disconnect, so we're not in a lobby any more
+    if lobby.current.is_some() && net.connection_state() == ConnectionState::Disconnected {
+        lobby.leave_current();
+        events.send(LobbyEvent::Left);
+    }
+}
+
+// PostUpdate, before laminar_flusher: send this frame's requests, if we're connected
+fn lobby_client_sender(mut net: ResMut<client::NetworkResource>, mut lobby: ResMut<LobbyClient>) {
+    if lobby.outbox.is_empty() || net.connection_state() != ConnectionState::Connected {
+        return;
+    }
+    let server = *net.server_addr();
+    for request in lobby.outbox.drain(..) {
+        net.send_internal(MessageKind::Lobby, lobby_packet(server, request.encode()));
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::server::{LobbyServer, LobbyServerPlugin, LobbyStarted};
+
+#[cfg(not(target_arch = "wasm32"))]
+mod server {
+    use super::*;
+
+    use crate::server::NetworkResource;
+    use crate::PeerDisconnected;
+
+    // longest lobby or player name we accept, in bytes
+    const MAX_NAME_LEN: usize = 64;
+    // longest property key or value, in bytes
+    const MAX_PROPERTY_LEN: usize = 64;
+    const MAX_PLAYERS: u8 = 64;
+    // lobby lists stop short of this, well inside laminar's default 16 KiB packet limit.
+    // the biggest possible lobby is under 7 KiB.
+    const MAX_LIST_LEN: usize = 12 * 1024;
+
+    /// Bevy event (server), a lobby was started and its members sent off to `game_server`
+    #[derive(Debug, Clone)]
+    pub struct LobbyStarted {
+        pub lobby: LobbyInfo,
+        pub peers: Vec<PeerHandle>,
+        pub game_server: SocketAddr,
+    }
+
+    #[derive(Debug)]
+    struct Lobby {
+        info: LobbyInfo,
+        // same order as info.members
+        peers: Vec<PeerHandle>,
+        next_member: u32,
+    }
+
+    /// Bevy resource (server), added by LobbyServerPlugin
+    #[derive(Debug, Default)]
+    pub struct LobbyServer {
+        /// where members are sent when the host starts without naming a server
+        pub game_server: Option<SocketAddr>,
+        lobbies: BTreeMap<LobbyId, Lobby>,
+        membership: HashMap<PeerHandle, LobbyId>,
+        next_lobby: u32,
+    }
+
+    impl LobbyServer {
+        pub fn lobbies(&self) -> impl Iterator<Item = &LobbyInfo> {
+            self.lobbies.values().map(|lobby| &lobby.info)
+        }
+
+        pub fn lobby(&self, id: LobbyId) -> Option<&LobbyInfo> {
+            self.lobbies.get(&id).map(|lobby| &lobby.info)
+        }
+
+        /// the lobby a peer is in
+        pub fn lobby_of(&self, handle: PeerHandle) -> Option<&LobbyInfo> {
+            self.lobby(*self.membership.get(&handle)?)
+        }
+
+        fn handle(
+            &mut self,
+            from: PeerHandle,
+            request: LobbyRequest,
+            out: &mut Vec<(PeerHandle, LobbyReply)>,
+            started: &mut Vec<LobbyStarted>,
+        ) {
+            let result = match request {
+                LobbyRequest::Create { settings, player_name } => self.create(from, settings, player_name, out),
+                LobbyRequest::List => {
+                    out.push((from, LobbyReply::List(self.list())));
+                    Ok(())
+                }
+                LobbyRequest::Join { lobby, player_name } => self.join(from, lobby, player_name, out),
+                LobbyRequest::Leave => self.leave(from, out).map(|_| out.push((from, LobbyReply::Left))),
+                LobbyRequest::Ready(ready) => self.set_ready(from, ready, out),
+                LobbyRequest::Start(game_server) => self.start(from, game_server, out, started),
+            };
+            if let Err(error) = result {
+                out.push((from, LobbyReply::Error(error)));
+            }
+        }
+
+        fn create(
+            &mut self,
+            from: PeerHandle,
+            settings: LobbySettings,
+            player_name: String,
+            out: &mut Vec<(PeerHandle, LobbyReply)>,
+        ) -> Result<(), LobbyError> {
+            if self.membership.contains_key(&from) {
+                return Err(LobbyError::AlreadyInLobby);
+            }
+            if !valid_settings(&settings) || !valid_name(&player_name) {
+                return Err(LobbyError::InvalidSettings);
+            }
+            let id = LobbyId(self.next_lobby);
+            self.next_lobby = self.next_lobby.wrapping_add(1);
+            let host = LobbyMember { id: 0, name: player_name, ready: false, host: true };
+            let info = LobbyInfo { id, settings, members: vec![host] };
+            log::info!("{} created lobby {} '{}'", from, id, info.settings.name);
+            out.push((from, LobbyReply::Joined { me: 0, lobby: info.clone() }));
+            self.lobbies.insert(id, Lobby { info, peers: vec![from], next_member: 1 });
+            self.membership.insert(from, id);
+            Ok(())
+        }
+
+        fn join(
+            &mut self,
+            from: PeerHandle,
+            id: LobbyId,
+            player_name: String,
+            out: &mut Vec<(PeerHandle, LobbyReply)>,
+        ) -> Result<(), LobbyError> {
+            if self.membership.contains_key(&from) {
+                return Err(LobbyError::AlreadyInLobby);
+            }
+            if !valid_name(&player_name) {
+                return Err(LobbyError::InvalidSettings);
+            }
+            let lobby = self.lobbies.get_mut(&id).ok_or(LobbyError::NotFound)?;
+            if lobby.info.is_full() {
+                return Err(LobbyError::Full);
+            }
+            let me = lobby.next_member;
+            lobby.next_member += 1;
+            lobby.info.members.push(LobbyMember { id: me, name: player_name, ready: false, host: false });
+            lobby.peers.push(from);
+            self.membership.insert(from, id);
+            out.push((from, LobbyReply::Joined { me, lobby: lobby.info.clone() }));
+            broadcast(lobby, Some(from), out);
+            Ok(())
+        }
+
+        // also used when a member disconnects, so the caller sends any Left
+        fn leave(&mut self, from: PeerHandle, out: &mut Vec<(PeerHandle, LobbyReply)>) -> Result<(), LobbyError> {
+            let id = self.membership.remove(&from).ok_or(LobbyError::NotInLobby)?;
+            let lobby = self.lobbies.get_mut(&id).expect("membership of a missing lobby");
+            let index = lobby.peers.iter().position(|peer| *peer == from).expect("member missing from lobby");
+            lobby.peers.remove(index);
+            let member = lobby.info.members.remove(index);
+            if lobby.peers.is_empty() {
+                log::info!("Lobby {} is empty, closing it", id);
+                self.lobbies.remove(&id);
+                return Ok(());
+            }
+            if member.host {
+                // longest serving member takes over
+                lobby.info.members[0].host = true;
+            }
+            broadcast(lobby, None, out);
+            Ok(())
+        }
+
+        fn set_ready(
+            &mut self,
+            from: PeerHandle,
+            ready: bool,
+            out: &mut Vec<(PeerHandle, LobbyReply)>,
+        ) -> Result<(), LobbyError> {
+            let (lobby, index) = self.lobby_mut(from)?;
+            if lobby.info.members[index].ready != ready {
+                lobby.info.members[index].ready = ready;
+                broadcast(lobby, None, out);
+            }
+            Ok(())
+        }
+
+        fn start(
+            &mut self,
+            from: PeerHandle,
+            game_server: Option<SocketAddr>,
+            out: &mut Vec<(PeerHandle, LobbyReply)>,
+            started: &mut Vec<LobbyStarted>,
+        ) -> Result<(), LobbyError> {
+            let fallback = self.game_server;
+            let (lobby, index) = self.lobby_mut(from)?;
+            if !lobby.info.members[index].host {
+                return Err(LobbyError::NotHost);
+            }
+            if !lobby.info.all_ready() {
+                return Err(LobbyError::NotReady);
+            }
+            let game_server = game_server.or(fallback).ok_or(LobbyError::NoGameServer)?;
+            let id = lobby.info.id;
+            let lobby = self.lobbies.remove(&id).unwrap();
+            for peer in &lobby.peers {
+                self.membership.remove(peer);
+                out.push((*peer, LobbyReply::Started { lobby: id, game_server }));
+            }
+            log::info!("Lobby {} started, {} players sent to {}", id, lobby.peers.len(), game_server);
+            started.push(LobbyStarted { lobby: lobby.info, peers: lobby.peers, game_server });
+            Ok(())
+        }
+
+        // as many lobbies as fit in one reply
+        fn list(&self) -> Vec<LobbyInfo> {
+            let mut len = 0;
+            let mut lobbies = Vec::new();
+            for info in self.lobbies() {
+                let mut buf = Vec::new();
+                write_lobby(&mut buf, info);
+                len += buf.len();
+                if len > MAX_LIST_LEN {
+                    log::debug!("Lobby list cut short at {} of {} lobbies", lobbies.len(), self.lobbies.len());
+                    break;
+                }
+                lobbies.push(info.clone());
+            }
+            lobbies
+        }
+
+        fn lobby_mut(&mut self, from: PeerHandle) -> Result<(&mut Lobby, usize), LobbyError> {
+            let id = self.membership.get(&from).ok_or(LobbyError::NotInLobby)?;
+            let lobby = self.lobbies.get_mut(id).expect("membership of a missing lobby");
+            let index = lobby.peers.iter().position(|peer| *peer == from).expect("member missing from lobby");
+            Ok((lobby, index))
+        }
+    }
+
+    // tell every member (bar one) what the lobby looks like now
+    fn broadcast(lobby: &Lobby, except: Option<PeerHandle>, out: &mut Vec<(PeerHandle, LobbyReply)>) {
+        for peer in &lobby.peers {
+            if Some(*peer) != except {
+                out.push((*peer, LobbyReply::Changed(lobby.info.clone())));
+            }
+        }
+    }
+
+    fn valid_name(name: &str) -> bool {
+        !name.trim().is_empty() && name.len() <= MAX_NAME_LEN
+    }
+
+    fn valid_settings(settings: &LobbySettings) -> bool {
+        valid_name(&settings.name)
+            && (1..=MAX_PLAYERS).contains(&settings.max_players)
+            && settings.properties.len() <= MAX_PROPERTIES
+            && settings.properties.iter().all(|(key, value)| key.len() <= MAX_PROPERTY_LEN && value.len() <= MAX_PROPERTY_LEN)
+    }
+
+    /// Server side of the lobby service, add it after ServerNetworkingPlugin.
+    /// `game_server` is where players go when the host doesn't say.
+    #[derive(Default)]
+    pub struct LobbyServerPlugin {
+        pub game_server: Option<SocketAddr>,
+    }
+
+    impl Plugin for LobbyServerPlugin {
+        fn build(&self, app: &mut AppBuilder) {
+            app
+            .insert_resource(LobbyServer {
+                game_server: self.game_server,
+                ..Default::default()
+            })
+            .add_event::<LobbyStarted>()
+            .add_system_to_stage(CoreStage::PreUpdate, lobby_server.system().after(NetworkSystem::Receive))
+            ;
+        }
+    }
+
+    // PreUpdate, after laminar_receiver: answer requests, and drop disconnected peers from lobbies
+    fn lobby_server(
+        mut net: ResMut<NetworkResource>,
+        mut lobbies: ResMut<LobbyServer>,
+        mut disconnected: EventReader<PeerDisconnected>,
+        mut started_events: EventWriter<LobbyStarted>,
+    ) {
+        let net = &mut *net;
+        let mut out = Vec::new();
+        let mut started = Vec::new();
+        for (from, payload) in std::mem::take(&mut net.lobby_inbox) {
+            match LobbyRequest::decode(&payload) {
+                Some(request) => lobbies.handle(from, request, &mut out, &mut started),
+                None => log::warn!("Malformed lobby request from {}", from),
+            }
+        }
+        for event in disconnected.iter() {
+            // not in a lobby is fine
+            lobbies.leave(event.handle, &mut out).ok();
+        }
+        for (to, reply) in out {
+            // the peer may have gone already
+            if net.peer(to).is_some() {
+                if let Err(err) = net.send_internal(MessageKind::Lobby, lobby_packet(to, reply.encode())) {
+                    log::warn!("Couldn't send lobby reply to {}: {}", to, err);
+                }
+            }
+        }
+        for event in started {
+            started_events.send(event);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn peer(port: u16) -> PeerHandle {
+            ([127, 0, 0, 1], port).into()
+        }
+
+        fn settings(name: &str, max_players: u8) -> LobbySettings {
+            LobbySettings { name: name.to_string(), max_players, properties: BTreeMap::new() }
+        }
+
+        fn info() -> LobbyInfo {
+            let mut properties = BTreeMap::new();
+            properties.insert("map".to_string(), "dust".to_string());
+            LobbyInfo {
+                id: LobbyId(7),
+                settings: LobbySettings { name: "fun".to_string(), max_players: 4, properties },
+                members: vec![
+                    LobbyMember { id: 0, name: "alice".to_string(), ready: false, host: true },
+                    LobbyMember { id: 1, name: "bob".to_string(), ready: true, host: false },
+                ],
+            }
+        }
+
+        // replies from one request, and any started lobbies
+        fn handle(server: &mut LobbyServer, from: PeerHandle, request: LobbyRequest) -> (Vec<(PeerHandle, LobbyReply)>, Vec<LobbyStarted>) {
+            let mut out = Vec::new();
+            let mut started = Vec::new();
+            server.handle(from, request, &mut out, &mut started);
+            (out, started)
+        }
+
+        fn create(server: &mut LobbyServer, from: PeerHandle, max_players: u8) -> LobbyId {
+            let request = LobbyRequest::Create { settings: settings("fun", max_players), player_name: "host".to_string() };
+            match handle(server, from, request).0.as_slice() {
+                [(to, LobbyReply::Joined { me: 0, lobby })] if *to == from => lobby.id,
+                other => panic!("create failed: {:?}", other),
+            }
+        }
+
+        fn join(server: &mut LobbyServer, from: PeerHandle, lobby: LobbyId) -> Vec<(PeerHandle, LobbyReply)> {
+            handle(server, from, LobbyRequest::Join { lobby, player_name: "guest".to_string() }).0
+        }
+
+        fn error(out: &[(PeerHandle, LobbyReply)]) -> Option<LobbyError> {
+            match out {
+                [(_, LobbyReply::Error(error))] => Some(*error),
+                _ => None,
+            }
+        }
+
+        #[test]
+        fn requests_roundtrip() {
+            let mut properties = BTreeMap::new();
+            properties.insert("mode".to_string(), "ctf".to_string());
+            let requests = vec![
+                LobbyRequest::Create {
+                    settings: LobbySettings { name: "fun".to_string(), max_players: 4, properties },
+                    player_name: "alice".to_string(),
+                },
+                LobbyRequest::List,
+                LobbyRequest::Join { lobby: LobbyId(3), player_name: "bob".to_string() },
+                LobbyRequest::Leave,
+                LobbyRequest::Ready(true),
+                LobbyRequest::Ready(false),
+                LobbyRequest::Start(None),
+                LobbyRequest::Start(Some(([10, 0, 0, 1], 7777).into())),
+                LobbyRequest::Start(Some("[2001:db8::1]:7777".parse().unwrap())),
+            ];
+            for request in requests {
+                assert_eq!(LobbyRequest::decode(&request.encode()), Some(request.clone()));
+            }
+        }
+
+        #[test]
+        fn replies_roundtrip() {
+            let replies = vec![
+                LobbyReply::List(vec![]),
+                LobbyReply::List(vec![info(), info()]),
+                LobbyReply::Joined { me: 1, lobby: info() },
+                LobbyReply::Changed(info()),
+                LobbyReply::Left,
+                LobbyReply::Started { lobby: LobbyId(7), game_server: ([10, 0, 0, 1], 7777).into() },
+                LobbyReply::Error(LobbyError::NotReady),
+            ];
+            for reply in replies {
+                assert_eq!(LobbyReply::decode(&reply.encode()), Some(reply.clone()));
+            }
+        }
+
+        #[test]
+        fn malformed_messages_are_rejected() {
+            let encoded = LobbyReply::Joined { me: 1, lobby: info() }.encode();
+            for len in 0..encoded.len() {
+                assert_eq!(LobbyReply::decode(&encoded[..len]), None, "truncated to {}", len);
+            }
+            assert_eq!(LobbyRequest::decode(&[99]), None);
+            assert_eq!(LobbyReply::decode(&[REPLY_ERROR, 99]), None);
+
+            let mut too_many = vec![CREATE];
+            write_str(&mut too_many, "fun");
+            too_many.push(4);
+            write_varint(&mut too_many, MAX_PROPERTIES + 1);
+            for i in 0..=MAX_PROPERTIES {
+                write_str(&mut too_many, &i.to_string());
+                write_str(&mut too_many, "x");
+            }
+            write_str(&mut too_many, "alice");
+            assert_eq!(LobbyRequest::decode(&too_many), None);
+        }
+
+        #[test]
+        fn create_lobby() {
+            let mut server = LobbyServer::default();
+            let lobby = create(&mut server, peer(1), 4);
+            let info = server.lobby(lobby).unwrap();
+            assert_eq!(info.members.len(), 1);
+            assert!(info.members[0].host);
+            assert_eq!(server.lobby_of(peer(1)).map(|info| info.id), Some(lobby));
+
+            let again = LobbyRequest::Create { settings: settings("more", 4), player_name: "host".to_string() };
+            assert_eq!(error(&handle(&mut server, peer(1), again).0), Some(LobbyError::AlreadyInLobby));
+        }
+
+        #[test]
+        fn create_checks_settings() {
+            let mut server = LobbyServer::default();
+            let long = "x".repeat(MAX_PROPERTY_LEN + 1);
+            let mut invalid = vec![settings("", 4), settings("  ", 4), settings("fun", 0), settings("fun", MAX_PLAYERS + 1)];
+            let mut long_value = settings("fun", 4);
+            long_value.properties.insert("map".to_string(), long.clone());
+            invalid.push(long_value);
+            let mut long_key = settings("fun", 4);
+            long_key.properties.insert(long, "dust".to_string());
+            invalid.push(long_key);
+            let mut too_many = settings("fun", 4);
+            for i in 0..=MAX_PROPERTIES {
+                too_many.properties.insert(i.to_string(), String::new());
+            }
+            invalid.push(too_many);
+            for settings in invalid {
+                let request = LobbyRequest::Create { settings: settings.clone(), player_name: "host".to_string() };
+                assert_eq!(error(&handle(&mut server, peer(1), request).0), Some(LobbyError::InvalidSettings), "{:?}", settings);
+            }
+            let request = LobbyRequest::Create { settings: settings("fun", 4), player_name: String::new() };
+            assert_eq!(error(&handle(&mut server, peer(1), request).0), Some(LobbyError::InvalidSettings));
+            assert_eq!(server.lobbies().count(), 0);
+        }
+
+        #[test]
+        fn join_when_full() {
+            let mut server = LobbyServer::default();
+            let lobby = create(&mut server, peer(1), 2);
+            let out = join(&mut server, peer(2), lobby);
+            assert!(matches!(&out[0], (to, LobbyReply::Joined { me: 1, .. }) if *to == peer(2)));
+            assert!(matches!(&out[1], (to, LobbyReply::Changed(info)) if *to == peer(1) && info.members.len() == 2));
+            assert_eq!(error(&join(&mut server, peer(3), lobby)), Some(LobbyError::Full));
+            assert_eq!(error(&join(&mut server, peer(3), LobbyId(lobby.0 + 1))), Some(LobbyError::NotFound));
+            assert_eq!(error(&join(&mut server, peer(2), lobby)), Some(LobbyError::AlreadyInLobby));
+            assert!(server.lobby_of(peer(3)).is_none());
+        }
+
+        #[test]
+        fn leave_hands_over_host() {
+            let mut server = LobbyServer::default();
+            let lobby = create(&mut server, peer(1), 4);
+            join(&mut server, peer(2), lobby);
+            join(&mut server, peer(3), lobby);
+
+            let (out, _) = handle(&mut server, peer(1), LobbyRequest::Leave);
+            assert!(out.contains(&(peer(1), LobbyReply::Left)));
+            let info = server.lobby(lobby).unwrap();
+            assert_eq!(info.members.len(), 2);
+            assert!(info.members[0].host);
+            assert!(!info.members[1].host);
+            assert!(server.lobby_of(peer(2)).unwrap().member(1).unwrap().host);
+            assert!(out.iter().any(|(to, reply)| *to == peer(3) && matches!(reply, LobbyReply::Changed(info) if info.host().map(|host| host.id) == Some(1))));
+
+            assert_eq!(error(&handle(&mut server, peer(1), LobbyRequest::Leave).0), Some(LobbyError::NotInLobby));
+            handle(&mut server, peer(2), LobbyRequest::Leave);
+            handle(&mut server, peer(3), LobbyRequest::Leave);
+            assert!(server.lobby(lobby).is_none());
+        }
+
+        #[test]
+        fn start() {
+            let mut server = LobbyServer::default();
+            let lobby = create(&mut server, peer(1), 4);
+            join(&mut server, peer(2), lobby);
+
+            assert_eq!(error(&handle(&mut server, peer(2), LobbyRequest::Start(None)).0), Some(LobbyError::NotHost));
+            assert_eq!(error(&handle(&mut server, peer(1), LobbyRequest::Start(None)).0), Some(LobbyError::NotReady));
+            handle(&mut server, peer(2), LobbyRequest::Ready(true));
+            assert_eq!(error(&handle(&mut server, peer(1), LobbyRequest::Start(None)).0), Some(LobbyError::NoGameServer));
+            assert!(server.lobby(lobby).is_some());
+
+            let game_server: SocketAddr = ([10, 0, 0, 1], 7777).into();
+            server.game_server = Some(game_server);
+            let (out, started) = handle(&mut server, peer(1), LobbyRequest::Start(None));
+            assert_eq!(out, vec![
+                (peer(1), LobbyReply::Started { lobby, game_server }),
+                (peer(2), LobbyReply::Started { lobby, game_server }),
+            ]);
+            assert_eq!(started.len(), 1);
+            assert_eq!(started[0].peers, vec![peer(1), peer(2)]);
+            assert!(server.lobby(lobby).is_none());
+            assert!(server.lobby_of(peer(2)).is_none());
+        }
+
+        #[test]
+        fn host_can_name_the_game_server() {
+            let mut server = LobbyServer::default();
+            let lobby = create(&mut server, peer(1), 4);
+            let game_server: SocketAddr = ([10, 0, 0, 2], 7777).into();
+            let (out, _) = handle(&mut server, peer(1), LobbyRequest::Start(Some(game_server)));
+            assert_eq!(out, vec![(peer(1), LobbyReply::Started { lobby, game_server })]);
+        }
+
+        #[test]
+        fn list_fits_in_a_packet() {
+            let mut server = LobbyServer::default();
+            let mut big = settings(&"n".repeat(MAX_NAME_LEN), MAX_PLAYERS);
+            for i in 0..MAX_PROPERTIES {
+                big.properties.insert(format!("{:0>64}", i), "v".repeat(MAX_PROPERTY_LEN));
+            }
+            for port in 1..=10 {
+                let request = LobbyRequest::Create { settings: big.clone(), player_name: "n".repeat(MAX_NAME_LEN) };
+                assert!(matches!(handle(&mut server, peer(port), request).0[0].1, LobbyReply::Joined { .. }));
+            }
+            let (out, _) = handle(&mut server, peer(100), LobbyRequest::List);
+            // fits in one of laminar's default 16 KiB packets
+            let encoded = out[0].1.encode();
+            assert!(encoded.len() < 16 * 1024, "{} bytes", encoded.len());
+            match &out[0].1 {
+                LobbyReply::List(lobbies) => assert!(!lobbies.is_empty() && lobbies.len() < 10),
+                other => panic!("expected a list, got {:?}", other),
+            }
+        }
+    }
+}
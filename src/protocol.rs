@@ -0,0 +1,238 @@
+use std::{
+    convert::TryInto,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use laminar::{DeliveryGuarantee, OrderingGuarantee, Packet as LaminarPacket};
+
+// Every non-empty payload we hand to laminar starts with a one byte header saying who it's for.
+// User payloads get MessageKind::User and are stripped before being published as PeerEvents,
+// everything else is our own housekeeping traffic and never reaches user systems.
+// Empty payloads are still the welcome/handshake packets, and have no header.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub(crate) enum MessageKind {
+    User = 0,
+    ClockPing = 1,
+    ClockPong = 2,
+    RpcRequest = 3,
+    RpcResponse = 4,
+    TransferOffer = 5,
+    TransferChunk = 6,
+    TransferAck = 7,
+    TransferAbort = 8,
+    Snapshot = 9,
+    SnapshotAck = 10,
+    Channel = 11,
+    Batch = 12,
+    Goodbye = 13,
+    Lobby = 14,
+    Relay = 15,
+    Session = 16,
+    Channels = 17,
+}
+
+impl MessageKind {
+    fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(MessageKind::User),
+            1 => Some(MessageKind::ClockPing),
+            2 => Some(MessageKind::ClockPong),
+            3 => Some(MessageKind::RpcRequest),
+            4 => Some(MessageKind::RpcResponse),
+            5 => Some(MessageKind::TransferOffer),
+            6 => Some(MessageKind::TransferChunk),
+            7 => Some(MessageKind::TransferAck),
+            8 => Some(MessageKind::TransferAbort),
+            9 => Some(MessageKind::Snapshot),
+            10 => Some(MessageKind::SnapshotAck),
+            11 => Some(MessageKind::Channel),
+            12 => Some(MessageKind::Batch),
+            13 => Some(MessageKind::Goodbye),
+            14 => Some(MessageKind::Lobby),
+            15 => Some(MessageKind::Relay),
+            16 => Some(MessageKind::Session),
+            17 => Some(MessageKind::Channels),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_transfer(self) -> bool {
+        matches!(
+            self,
+            MessageKind::TransferOffer
+                | MessageKind::TransferChunk
+                | MessageKind::TransferAck
+                | MessageKind::TransferAbort
+        )
+    }
+}
+
+/// build a new packet with the same destination and guarantees, but a different payload
+pub(crate) fn repack(packet: &LaminarPacket, payload: Vec<u8>) -> LaminarPacket {
+    build(packet.addr(), packet.delivery_guarantee(), packet.order_guarantee(), payload)
+}
+
+/// build a packet from its parts, since laminar's Packet::new isn't public
+pub(crate) fn build(
+    addr: SocketAddr,
+    delivery: DeliveryGuarantee,
+    ordering: OrderingGuarantee,
+    payload: Vec<u8>,
+) -> LaminarPacket {
+    match (delivery, ordering) {
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::None) => {
+            LaminarPacket::unreliable(addr, payload)
+        }
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(stream_id)) => {
+            LaminarPacket::unreliable_sequenced(addr, payload, stream_id)
+        }
+        // laminar has no unreliable+ordered. without resends the best it could do is drop
+        // anything older than the newest, which is sequenced, so never upgrade to reliable
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::Ordered(stream_id)) => {
+            LaminarPacket::unreliable_sequenced(addr, payload, stream_id)
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::None) => {
+            LaminarPacket::reliable_unordered(addr, payload)
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(stream_id)) => {
+            LaminarPacket::reliable_sequenced(addr, payload, stream_id)
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(stream_id)) => {
+            LaminarPacket::reliable_ordered(addr, payload, stream_id)
+        }
+    }
+}
+
+/// the same packet to another address, eg. a resumed peer's new one
+pub(crate) fn readdress(packet: LaminarPacket, addr: SocketAddr) -> LaminarPacket {
+    if packet.addr() == addr {
+        return packet;
+    }
+    build(addr, packet.delivery_guarantee(), packet.order_guarantee(), packet.payload().to_vec())
+}
+
+/// prefix the payload with our header byte
+pub(crate) fn wrap(kind: MessageKind, packet: LaminarPacket) -> LaminarPacket {
+    let mut payload = Vec::with_capacity(packet.payload().len() + 1);
+    payload.push(kind as u8);
+    payload.extend_from_slice(packet.payload());
+    repack(&packet, payload)
+}
+
+// the Goodbye a full server answers new connections with. plain goodbyes are empty.
+const REJECTED: u8 = 1;
+
+/// the whole payload of a rejection, sent unbatched so a connecting client can spot it
+pub(crate) fn rejection() -> Vec<u8> {
+    vec![MessageKind::Goodbye as u8, REJECTED]
+}
+
+pub(crate) fn is_rejection(payload: &[u8]) -> bool {
+    payload == [MessageKind::Goodbye as u8, REJECTED]
+}
+
+/// strip our header byte. None for empty handshake packets, or junk we don't understand.
+pub(crate) fn unwrap(packet: LaminarPacket) -> Option<(MessageKind, LaminarPacket)> {
+    let (&header, rest) = packet.payload().split_first()?;
+    let kind = MessageKind::from_u8(header)?;
+    Some((kind, repack(&packet, rest.to_vec())))
+}
+
+// LEB128 style, 7 bits per byte, low bits first
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// reads a varint off the front of `bytes`, advancing it
+pub(crate) fn read_varint(bytes: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let slice: &[u8] = *bytes;
+        let (&byte, rest) = slice.split_first()?;
+        *bytes = rest;
+        if shift >= std::mem::size_of::<usize>() * 8 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+/// utf8, prefixed with its length as a varint
+pub(crate) fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+// at most `max` bytes, on a char boundary
+pub(crate) fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+pub(crate) fn read_str(bytes: &mut &[u8]) -> Option<String> {
+    let len = read_varint(bytes)?;
+    if len > bytes.len() {
+        return None;
+    }
+    let (s, rest) = bytes.split_at(len);
+    *bytes = rest;
+    String::from_utf8(s.to_vec()).ok()
+}
+
+//  [4 or 6][octets][port: u16]
+pub(crate) fn write_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_le_bytes());
+}
+
+pub(crate) fn read_addr(bytes: &mut &[u8]) -> Option<SocketAddr> {
+    let (&family, rest) = bytes.split_first()?;
+    let len = match family {
+        4 => 4,
+        6 => 16,
+        _ => return None,
+    };
+    if rest.len() < len + 2 {
+        return None;
+    }
+    let (octets, rest) = rest.split_at(len);
+    let ip = if family == 4 {
+        let octets: [u8; 4] = octets.try_into().ok()?;
+        IpAddr::V4(Ipv4Addr::from(octets))
+    } else {
+        let octets: [u8; 16] = octets.try_into().ok()?;
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+    let port = u16::from_le_bytes(rest[..2].try_into().ok()?);
+    *bytes = &rest[2..];
+    Some(SocketAddr::new(ip, port))
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use instant::Instant;
+
+use crate::protocol::{read_str, read_varint, truncate, write_str, write_varint};
+
+// Server info queries for server browsers, native UDP builds only. The client sends a raw
+// datagram to the game port, and the server's datagram socket answers it before the connection
+// challenge, so laminar never sees it and no Peer is created. Like discovery, the client uses a
+// plain UDP socket of its own, no connection needed.
+//
+//  Query: [magic][0][nonce: u64][padding up to QUERY_LEN]
+//  Reply: [magic][1][nonce: u64][players: u16][max_players: u16, 0 for no limit]
+//         [name][map][property count][key][value]...
+//
+// The nonce matches a reply to the query it answers, which is what the round trip time is
+// measured against. Replies never outgrow the query, so names are capped and properties are
+// left out once they'd push the reply over QUERY_LEN.
+
+const MAGIC: &[u8; 7] = b"BNLINFO";
+
+const QUERY: u8 = 0;
+const REPLY: u8 = 1;
+
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+const QUERY_LEN: usize = 256;
+const MAX_NAME_LEN: usize = 32;
+
+// resend unanswered queries this often, and give up after QUERY_TIMEOUT
+const QUERY_RESEND: Duration = Duration::from_millis(500);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Bevy resource (server): what to tell server browsers. Insert it to answer queries, and
+/// remove it to stop. Player counts are filled in from the NetworkResource.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerInfo {
+    /// cut down to 32 bytes
+    pub name: String,
+    /// cut down to 32 bytes
+    pub map: String,
+    /// anything else for the browser to show. left out of replies once they'd be too big
+    pub properties: BTreeMap<String, String>,
+}
+
+/// Bevy event (client), a server answered query_server()
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfoReceived {
+    pub addr: SocketAddr,
+    pub info: ServerInfo,
+    pub players: u16,
+    /// None for no limit
+    pub max_players: Option<u16>,
+    pub rtt: Duration,
+}
+
+fn query(nonce: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(QUERY_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.push(QUERY);
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    buf.resize(QUERY_LEN, 0);
+    buf
+}
+
+// the nonce, for a query that's long enough to be answered
+fn parse_query(payload: &[u8]) -> Option<u64> {
+    if payload.len() < QUERY_LEN {
+        return None;
+    }
+    let body = payload.strip_prefix(&MAGIC[..])?.strip_prefix(&[QUERY])?;
+    Some(u64::from_le_bytes(body.get(0..8)?.try_into().ok()?))
+}
+
+// everything in a reply after the nonce
+fn reply_body(info: &ServerInfo, players: usize, max_players: Option<usize>) -> Vec<u8> {
+    let clamp = |n: usize| n.min(u16::MAX as usize) as u16;
+    let mut buf = Vec::with_capacity(QUERY_LEN - HEADER_LEN);
+    buf.extend_from_slice(&clamp(players).to_le_bytes());
+    buf.extend_from_slice(&max_players.map_or(0, clamp).to_le_bytes());
+    write_str(&mut buf, truncate(&info.name, MAX_NAME_LEN));
+    write_str(&mut buf, truncate(&info.map, MAX_NAME_LEN));
+    let mut properties = Vec::new();
+    let mut count = 0;
+    for (key, value) in &info.properties {
+        let mut property = Vec::new();
+        write_str(&mut property, key);
+        write_str(&mut property, value);
+        // leaving a byte for the count
+        if HEADER_LEN + buf.len() + 1 + properties.len() + property.len() > QUERY_LEN {
+            break;
+        }
+        properties.extend_from_slice(&property);
+        count += 1;
+    }
+    write_varint(&mut buf, count);
+    buf.extend_from_slice(&properties);
+    buf
+}
+
+fn parse_reply(payload: &[u8]) -> Option<(u64, ServerInfo, u16, Option<u16>)> {
+    let body = payload.strip_prefix(&MAGIC[..])?.strip_prefix(&[REPLY])?;
+    let nonce = u64::from_le_bytes(body.get(0..8)?.try_into().ok()?);
+    let players = u16::from_le_bytes(body.get(8..10)?.try_into().ok()?);
+    let max_players = u16::from_le_bytes(body.get(10..12)?.try_into().ok()?);
+    let mut body = &body[12..];
+    let name = read_str(&mut body)?;
+    let map = read_str(&mut body)?;
+    let mut properties = BTreeMap::new();
+    for _ in 0..read_varint(&mut body)? {
+        let key = read_str(&mut body)?;
+        properties.insert(key, read_str(&mut body)?);
+    }
+    let max_players = if max_players == 0 { None } else { Some(max_players) };
+    Some((nonce, ServerInfo { name, map, properties }, players, max_players))
+}
+
+/// Server side: the current reply, shared between NetworkResource and the datagram socket
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InfoResponder {
+    state: Arc<Mutex<Option<InfoState>>>,
+}
+
+#[derive(Debug)]
+struct InfoState {
+    body: Vec<u8>,
+    players: usize,
+    max_players: Option<usize>,
+}
+
+impl InfoResponder {
+    /// re-encode the reply if anything in it has changed
+    pub(crate) fn update(&self, info: &ServerInfo, changed: bool, players: usize, max_players: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        let stale = match &*state {
+            Some(state) => changed || state.players != players || state.max_players != max_players,
+            None => true,
+        };
+        if stale {
+            *state = Some(InfoState {
+                body: reply_body(info, players, max_players),
+                players,
+                max_players,
+            });
+        }
+    }
+
+    /// stop answering
+    pub(crate) fn clear(&self) {
+        *self.state.lock().unwrap() = None;
+    }
+
+    /// the reply, if this datagram is a query we're answering
+    pub(crate) fn answer(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let nonce = parse_query(payload)?;
+        let state = self.state.lock().unwrap();
+        let body = &state.as_ref()?.body;
+        let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+        buf.extend_from_slice(MAGIC);
+        buf.push(REPLY);
+        buf.extend_from_slice(&nonce.to_le_bytes());
+        buf.extend_from_slice(body);
+        Some(buf)
+    }
+}
+
+/// true for anything that looks like one of our queries, answered or not, so it's never
+/// mistaken for laminar traffic
+pub(crate) fn is_query(payload: &[u8]) -> bool {
+    payload.strip_prefix(&MAGIC[..]).and_then(|rest| rest.first()) == Some(&QUERY)
+}
+
+/// Client side: the socket query_server() sends from, and the queries still waiting on an answer
+#[derive(Debug, Default)]
+pub(crate) struct ServerQueries {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    next_nonce: u64,
+    pending: HashMap<SocketAddr, PendingQuery>,
+}
+
+#[derive(Debug)]
+struct PendingQuery {
+    started: Instant,
+    // each send has its own nonce, so a late answer to an earlier one still gets the right rtt
+    sent: Vec<(u64, Instant)>,
+}
+
+impl ServerQueries {
+    pub(crate) fn start(&mut self, addr: SocketAddr, now: Instant) -> io::Result<()> {
+        self.pending.insert(addr, PendingQuery { started: now, sent: Vec::new() });
+        self.send(addr, now)
+    }
+
+    pub(crate) fn is_pending(&self, addr: SocketAddr) -> bool {
+        self.pending.contains_key(&addr)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn socket(&mut self, addr: SocketAddr) -> io::Result<&UdpSocket> {
+        let (socket, bind): (_, SocketAddr) = match addr {
+            SocketAddr::V4(_) => (&mut self.v4, (Ipv4Addr::UNSPECIFIED, 0).into()),
+            SocketAddr::V6(_) => (&mut self.v6, (Ipv6Addr::UNSPECIFIED, 0).into()),
+        };
+        if socket.is_none() {
+            let bound = UdpSocket::bind(bind)?;
+            bound.set_nonblocking(true)?;
+            *socket = Some(bound);
+        }
+        Ok(socket.as_ref().unwrap())
+    }
+
+    fn send(&mut self, addr: SocketAddr, now: Instant) -> io::Result<()> {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.socket(addr)?.send_to(&query(nonce), addr)?;
+        if let Some(query) = self.pending.get_mut(&addr) {
+            query.sent.push((nonce, now));
+        }
+        Ok(())
+    }
+
+    /// answers that have arrived, after resending or giving up on the rest
+    pub(crate) fn poll(&mut self, now: Instant) -> Vec<ServerInfoReceived> {
+        let mut answers = Vec::new();
+        let mut buf = [0; 1500];
+        for socket in self.v4.iter().chain(self.v6.iter()) {
+            loop {
+                let (len, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    // WouldBlock when we're done, or icmp errors from servers that aren't there
+                    Err(_) => break,
+                };
+                let (nonce, info, players, max_players) = match parse_reply(&buf[..len]) {
+                    Some(reply) => reply,
+                    None => continue,
+                };
+                let sent_at = self.pending.get(&from)
+                    .and_then(|query| query.sent.iter().find(|(sent, _)| *sent == nonce))
+                    .map(|(_, at)| *at);
+                if let Some(sent_at) = sent_at {
+                    self.pending.remove(&from);
+                    answers.push(ServerInfoReceived { addr: from, info, players, max_players, rtt: now - sent_at });
+                }
+            }
+        }
+        self.pending.retain(|_, query| now - query.started < QUERY_TIMEOUT);
+        let resend: Vec<_> = self.pending.iter()
+            .filter(|(_, query)| query.sent.last().map_or(true, |(_, at)| now - *at >= QUERY_RESEND))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in resend {
+            self.send(addr, now).ok();
+        }
+        answers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> ServerInfo {
+        let mut properties = BTreeMap::new();
+        properties.insert("mode".to_string(), "ctf".to_string());
+        ServerInfo { name: "my server".to_string(), map: "dust".to_string(), properties }
+    }
+
+    fn answer(info: &ServerInfo, players: usize, max_players: Option<usize>, nonce: u64) -> Vec<u8> {
+        let responder = InfoResponder::default();
+        responder.update(info, true, players, max_players);
+        responder.answer(&query(nonce)).unwrap()
+    }
+
+    #[test]
+    fn query_roundtrip() {
+        assert_eq!(parse_query(&query(42)), Some(42));
+        assert_eq!(parse_query(&query(u64::MAX)), Some(u64::MAX));
+        assert!(is_query(&query(42)));
+    }
+
+    #[test]
+    fn short_queries_are_not_answered() {
+        let short = &query(42)[..QUERY_LEN - 1];
+        assert_eq!(parse_query(short), None);
+        // still kept away from laminar
+        assert!(is_query(short));
+        let responder = InfoResponder::default();
+        responder.update(&info(), true, 1, None);
+        assert_eq!(responder.answer(short), None);
+    }
+
+    #[test]
+    fn reply_roundtrip() {
+        let reply = answer(&info(), 3, Some(8), 42);
+        assert!(!is_query(&reply));
+        assert_eq!(parse_reply(&reply), Some((42, info(), 3, Some(8))));
+        let reply = answer(&ServerInfo::default(), 100_000, None, 7);
+        assert_eq!(parse_reply(&reply), Some((7, ServerInfo::default(), u16::MAX, None)));
+    }
+
+    #[test]
+    fn replies_never_outgrow_the_query() {
+        let mut big = ServerInfo {
+            name: "é".repeat(MAX_NAME_LEN),
+            map: "m".repeat(MAX_NAME_LEN * 2),
+            properties: BTreeMap::new(),
+        };
+        for i in 0..20 {
+            big.properties.insert(format!("key{:02}", i), "v".repeat(20));
+        }
+        let reply = answer(&big, 1, None, 42);
+        assert!(reply.len() <= QUERY_LEN, "{} bytes", reply.len());
+        let (_, info, _, _) = parse_reply(&reply).unwrap();
+        assert_eq!(info.name, "é".repeat(MAX_NAME_LEN / 2));
+        assert_eq!(info.map, "m".repeat(MAX_NAME_LEN));
+        // the first few properties, in order
+        assert!(!info.properties.is_empty() && info.properties.len() < big.properties.len());
+        assert!(info.properties.keys().eq(big.properties.keys().take(info.properties.len())));
+    }
+
+    #[test]
+    fn bad_replies_are_ignored() {
+        let reply = answer(&info(), 3, Some(8), 42);
+        for len in 0..reply.len() {
+            assert_eq!(parse_reply(&reply[..len]), None, "truncated to {}", len);
+        }
+        assert_eq!(parse_reply(&query(42)), None);
+    }
+
+    #[test]
+    fn cleared_responder_stays_quiet() {
+        let responder = InfoResponder::default();
+        assert_eq!(responder.answer(&query(1)), None);
+        responder.update(&info(), true, 1, None);
+        assert!(responder.answer(&query(1)).is_some());
+        responder.clear();
+        assert_eq!(responder.answer(&query(1)), None);
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use instant::Instant;
+
+use crate::PeerHandle;
+
+// Per-peer token buckets for incoming traffic on the server, checked in the datagram socket so
+// a flooding peer costs us as little as possible. Drops are tallied there and turned into
+// RateLimitExceeded events once per frame by laminar_receiver.
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub packets_per_sec: f32,
+    pub bytes_per_sec: f32,
+    /// how much traffic can arrive at once, as seconds worth of the rates above
+    pub burst: f32,
+    /// kick peers that go over the limit in this many frames within `violation_window`.
+    /// None never kicks.
+    pub kick_after: Option<u32>,
+    pub violation_window: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            packets_per_sec: 240.0,
+            bytes_per_sec: 128.0 * 1024.0,
+            burst: 1.0,
+            kick_after: None,
+            violation_window: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Bevy event (server), a peer sent more than its rate limit allows this frame
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitExceeded {
+    pub handle: PeerHandle,
+    pub dropped_packets: u32,
+    pub dropped_bytes: usize,
+    /// we're disconnecting it, after too many violations
+    pub kicked: bool,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    packets: f32,
+    bytes: f32,
+    updated: Instant,
+    // frames with drops, for kick_after
+    violations: VecDeque<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    config: Option<RateLimitConfig>,
+    buckets: HashMap<SocketAddr, Bucket>,
+    // dropped since the last take_exceeded: packets, bytes
+    dropped: HashMap<SocketAddr, (u32, usize)>,
+}
+
+/// shared between NetworkResource and the datagram socket
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn config(&self) -> Option<RateLimitConfig> {
+        self.state.lock().unwrap().config.clone()
+    }
+
+    pub(crate) fn set_config(&self, config: Option<RateLimitConfig>) {
+        let mut state = self.state.lock().unwrap();
+        state.config = config;
+        state.buckets.clear();
+        state.dropped.clear();
+    }
+
+    /// take a datagram's worth of tokens, false if the peer's out and it should be dropped
+    pub(crate) fn allow(&self, addr: SocketAddr, len: usize, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let config = match &state.config {
+            Some(config) => config.clone(),
+            None => return true,
+        };
+        let max_packets = config.packets_per_sec * config.burst;
+        let max_bytes = config.bytes_per_sec * config.burst;
+        let bucket = state.buckets.entry(addr).or_insert_with(|| Bucket {
+            packets: max_packets,
+            bytes: max_bytes,
+            updated: now,
+            violations: VecDeque::new(),
+        });
+        if now > bucket.updated {
+            let elapsed = (now - bucket.updated).as_secs_f32();
+            bucket.packets = (bucket.packets + elapsed * config.packets_per_sec).min(max_packets);
+            bucket.bytes = (bucket.bytes + elapsed * config.bytes_per_sec).min(max_bytes);
+            bucket.updated = now;
+        }
+        if bucket.packets >= 1.0 && bucket.bytes >= len as f32 {
+            bucket.packets -= 1.0;
+            bucket.bytes -= len as f32;
+            return true;
+        }
+        let dropped = state.dropped.entry(addr).or_insert((0, 0));
+        dropped.0 += 1;
+        dropped.1 += len;
+        false
+    }
+
+    /// everyone who went over the limit since the last call, and whether to kick them
+    pub(crate) fn take_exceeded(&self, now: Instant) -> Vec<RateLimitExceeded> {
+        let mut state = self.state.lock().unwrap();
+        let config = match &state.config {
+            Some(config) => config.clone(),
+            None => return Vec::new(),
+        };
+        let dropped: Vec<_> = state.dropped.drain().collect();
+        let mut exceeded = Vec::with_capacity(dropped.len());
+        for (addr, (dropped_packets, dropped_bytes)) in dropped {
+            let mut kicked = false;
+            if let Some(bucket) = state.buckets.get_mut(&addr) {
+                bucket.violations.push_back(now);
+                while let Some(first) = bucket.violations.front() {
+                    if now > *first && now - *first > config.violation_window {
+                        bucket.violations.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                kicked = config.kick_after.map_or(false, |limit| bucket.violations.len() as u32 >= limit);
+                if kicked {
+                    bucket.violations.clear();
+                }
+            }
+            exceeded.push(RateLimitExceeded {
+                handle: addr,
+                dropped_packets,
+                dropped_bytes,
+                kicked,
+            });
+        }
+        exceeded
+    }
+
+    pub(crate) fn forget(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.buckets.remove(&addr);
+        state.dropped.remove(&addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    fn limiter(config: RateLimitConfig) -> RateLimiter {
+        let limiter = RateLimiter::default();
+        limiter.set_config(Some(config));
+        limiter
+    }
+
+    fn ten_per_sec() -> RateLimitConfig {
+        RateLimitConfig {
+            packets_per_sec: 10.0,
+            bytes_per_sec: 1000.0,
+            burst: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn no_config_allows_everything() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        assert!((0..10_000).all(|_| limiter.allow(addr(1), 1000, now)));
+        assert!(limiter.take_exceeded(now).is_empty());
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let limiter = limiter(ten_per_sec());
+        let now = Instant::now();
+        assert!((0..10).all(|_| limiter.allow(addr(1), 10, now)));
+        assert!(!limiter.allow(addr(1), 10, now));
+        // other peers have their own buckets
+        assert!(limiter.allow(addr(2), 10, now));
+
+        let later = now + Duration::from_millis(250);
+        assert!(limiter.allow(addr(1), 10, later));
+        assert!(limiter.allow(addr(1), 10, later));
+        assert!(!limiter.allow(addr(1), 10, later));
+
+        // refills up to the burst, no further
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| limiter.allow(addr(1), 10, much_later)).count(), 10);
+    }
+
+    #[test]
+    fn bytes_are_limited_too() {
+        let limiter = limiter(ten_per_sec());
+        let now = Instant::now();
+        assert!(limiter.allow(addr(1), 600, now));
+        assert!(!limiter.allow(addr(1), 600, now));
+        assert!(limiter.allow(addr(1), 400, now));
+    }
+
+    #[test]
+    fn drops_are_reported_once() {
+        let limiter = limiter(ten_per_sec());
+        let now = Instant::now();
+        for _ in 0..13 {
+            limiter.allow(addr(1), 10, now);
+        }
+        let exceeded = limiter.take_exceeded(now);
+        assert_eq!(exceeded.len(), 1);
+        assert_eq!(exceeded[0].handle, addr(1));
+        assert_eq!(exceeded[0].dropped_packets, 3);
+        assert_eq!(exceeded[0].dropped_bytes, 30);
+        assert!(!exceeded[0].kicked);
+        assert!(limiter.take_exceeded(now).is_empty());
+    }
+
+    #[test]
+    fn kicks_after_repeated_violations() {
+        let limiter = limiter(RateLimitConfig {
+            kick_after: Some(3),
+            violation_window: Duration::from_secs(10),
+            ..ten_per_sec()
+        });
+        let start = Instant::now();
+        let mut kicked = Vec::new();
+        for frame in 0..3u32 {
+            let now = start + Duration::from_millis(100) * frame;
+            for _ in 0..20 {
+                limiter.allow(addr(1), 10, now);
+            }
+            kicked.push(limiter.take_exceeded(now)[0].kicked);
+        }
+        assert_eq!(kicked, [false, false, true]);
+    }
+
+    #[test]
+    fn old_violations_fall_out_of_the_window() {
+        let limiter = limiter(RateLimitConfig {
+            kick_after: Some(2),
+            violation_window: Duration::from_secs(1),
+            ..ten_per_sec()
+        });
+        let start = Instant::now();
+        for frame in 0..4u32 {
+            let now = start + Duration::from_secs(5) * frame;
+            for _ in 0..20 {
+                limiter.allow(addr(1), 10, now);
+            }
+            assert!(!limiter.take_exceeded(now)[0].kicked, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn forget_resets_the_bucket() {
+        let limiter = limiter(ten_per_sec());
+        let now = Instant::now();
+        for _ in 0..11 {
+            limiter.allow(addr(1), 10, now);
+        }
+        limiter.forget(addr(1));
+        assert!(limiter.take_exceeded(now).is_empty());
+        assert!(limiter.allow(addr(1), 10, now));
+    }
+}
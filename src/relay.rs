@@ -0,0 +1,644 @@
+use std::{fmt, net::SocketAddr};
+
+use bevy::{
+    log,
+    app::{AppBuilder, CoreStage, EventWriter, Plugin},
+    ecs::prelude::*,
+};
+
+use laminar::Packet as LaminarPacket;
+
+use crate::client;
+use crate::protocol::{self, read_addr, write_addr, MessageKind};
+use crate::{ConnectionState, NetworkSystem, PeerHandle};
+
+// Relaying, for players who can't reach each other directly. Everyone connects to a relay server
+// (RelayServerPlugin). One client hosts, the others join it by the host's PeerHandle, which is
+// the host's address as the relay sees it, and the relay forwards messages between the host and
+// its guests with the delivery guarantees they were sent with. Guests can only talk to their
+// host, the host to any of its guests, each addressing the other by PeerHandle.
+//
+// Everything is a MessageKind::Relay message:
+//
+//  Host:  [0]                          Hosting:    [16][our handle: addr]
+//  Join:  [1][host: addr]              Joined:     [17][host: addr]
+//  Send:  [2][to: addr][payload]       PeerJoined: [18][guest: addr]
+//  Leave: [3]                          PeerLeft:   [19][handle: addr]
+//                                      Message:    [20][from: addr][payload]
+//                                      Error:      [21][error: u8]
+
+/// Bevy event (client), news from the relay
+#[derive(Debug, Clone)]
+pub enum RelayEvent {
+    /// we're hosting, guests join us with this handle
+    Hosting { handle: PeerHandle },
+    /// we're a guest of `host`
+    Joined { host: PeerHandle },
+    /// a guest joined us
+    PeerJoined { handle: PeerHandle },
+    /// a guest left us, or our host went away, which ends our session
+    PeerLeft { handle: PeerHandle },
+    /// a message relayed to us, packet.addr() is who sent it
+    Message { packet: LaminarPacket },
+    Error(RelayError),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RelayError {
+    /// nobody's hosting at that handle
+    NoSuchHost,
+    /// host or join while already hosting or a guest
+    AlreadyInSession,
+    NotInSession,
+    /// sent to someone outside our session
+    NotPaired,
+}
+
+impl RelayError {
+    fn from_u8(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => RelayError::NoSuchHost,
+            1 => RelayError::AlreadyInSession,
+            2 => RelayError::NotInSession,
+            3 => RelayError::NotPaired,
+            _ => return None,
+        })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn to_u8(self) -> u8 {
+        match self {
+            RelayError::NoSuchHost => 0,
+            RelayError::AlreadyInSession => 1,
+            RelayError::NotInSession => 2,
+            RelayError::NotPaired => 3,
+        }
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            RelayError::NoSuchHost => "nobody is hosting there",
+            RelayError::AlreadyInSession => "already hosting or joined",
+            RelayError::NotInSession => "not hosting or joined",
+            RelayError::NotPaired => "not in the same session",
+        };
+        f.write_str(text)
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+const HOST: u8 = 0;
+const JOIN: u8 = 1;
+const SEND: u8 = 2;
+const LEAVE: u8 = 3;
+
+const HOSTING: u8 = 16;
+const JOINED: u8 = 17;
+const PEER_JOINED: u8 = 18;
+const PEER_LEFT: u8 = 19;
+const MESSAGE: u8 = 20;
+const ERROR: u8 = 21;
+
+fn with_addr(kind: u8, addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(20 + payload.len());
+    buf.push(kind);
+    write_addr(&mut buf, addr);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+// reliable and ordered, on laminar's default stream
+fn control_packet(addr: SocketAddr, payload: Vec<u8>) -> LaminarPacket {
+    LaminarPacket::reliable_ordered(addr, payload, None)
+}
+
+/// whether we're hosting or a guest, as far as the relay has told us
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RelayRole {
+    Host { handle: PeerHandle },
+    Guest { host: PeerHandle },
+}
+
+/// Bevy resource (client), added by RelayClientPlugin. Like LobbyClient, requests go out at the
+/// end of the frame once we're connected to the relay.
+#[derive(Debug, Default)]
+pub struct RelayClient {
+    role: Option<RelayRole>,
+    guests: Vec<PeerHandle>,
+    outbox: Vec<LaminarPacket>,
+}
+
+impl RelayClient {
+    pub fn host(&mut self) {
+        self.control(vec![HOST]);
+    }
+
+    /// join whoever's hosting at `host`, the handle from their RelayEvent::Hosting
+    pub fn join(&mut self, host: PeerHandle) {
+        self.control(with_addr(JOIN, host, &[]));
+    }
+
+    /// stop hosting, or leave our host
+    pub fn leave(&mut self) {
+        self.control(vec![LEAVE]);
+        self.reset();
+    }
+
+    /// send a packet through the relay. packet.addr() is who it's for, our host or one of our
+    /// guests, and it's delivered with the packet's guarantees.
+    pub fn send(&mut self, packet: LaminarPacket) {
+        let body = with_addr(SEND, packet.addr(), packet.payload());
+        self.outbox.push(protocol::repack(&packet, body));
+    }
+
+    pub fn role(&self) -> Option<RelayRole> {
+        self.role
+    }
+
+    /// our guests, while hosting
+    pub fn guests(&self) -> &[PeerHandle] {
+        &self.guests
+    }
+
+    // the address is filled in with the relay's when sent
+    fn control(&mut self, body: Vec<u8>) {
+        self.outbox.push(control_packet(SocketAddr::from(([0, 0, 0, 0], 0)), body));
+    }
+
+    fn handle(&mut self, packet: LaminarPacket) -> Option<RelayEvent> {
+        let (&kind, mut body) = packet.payload().split_first()?;
+        Some(match kind {
+            HOSTING => {
+                let handle = read_addr(&mut body)?;
+                self.role = Some(RelayRole::Host { handle });
+                RelayEvent::Hosting { handle }
+            }
+            JOINED => {
+                let host = read_addr(&mut body)?;
+                self.role = Some(RelayRole::Guest { host });
+                RelayEvent::Joined { host }
+            }
+            PEER_JOINED => {
+                let handle = read_addr(&mut body)?;
+                self.guests.push(handle);
+                RelayEvent::PeerJoined { handle }
+            }
+            PEER_LEFT => {
+                let handle = read_addr(&mut body)?;
+                self.guests.retain(|guest| *guest != handle);
+                if self.role == Some(RelayRole::Guest { host: handle }) {
+                    self.role = None;
+                }
+                RelayEvent::PeerLeft { handle }
+            }
+            MESSAGE => {
+                let from = read_addr(&mut body)?;
+                let delivery = packet.delivery_guarantee();
+                let ordering = packet.order_guarantee();
+                RelayEvent::Message { packet: protocol::build(from, delivery, ordering, body.to_vec()) }
+            }
+            ERROR => {
+                let error = RelayError::from_u8(*body.first()?)?;
+                if error == RelayError::NotInSession {
+                    self.reset();
+                }
+                RelayEvent::Error(error)
+            }
+            _ => return None,
+        })
+    }
+
+    fn reset(&mut self) {
+        self.role = None;
+        self.guests.clear();
+    }
+}
+
+/// Client side of relaying, add it after ClientNetworkingPlugin and connect() to the relay
+#[derive(Default)]
+pub struct RelayClientPlugin;
+
+impl Plugin for RelayClientPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+        .init_resource::<RelayClient>()
+        .add_event::<RelayEvent>()
+        .add_system_to_stage(CoreStage::PreUpdate, relay_client_receiver.system().after(NetworkSystem::Receive))
+        .add_system_to_stage(CoreStage::PostUpdate, relay_client_sender.system().before(NetworkSystem::Flush))
+        ;
+    }
+}
+
+// PreUpdate, after laminar_receiver: turn relay messages into RelayEvents
+fn relay_client_receiver(
+    mut net: ResMut<client::NetworkResource>,
+    mut relay: ResMut<RelayClient>,
+    mut events: EventWriter<RelayEvent>,
+) {
+    for packet in std::mem::take(&mut net.relay_inbox) {
+        match relay.handle(packet) {
+            Some(event) => events.send(event),
+            None => log::warn!("Malformed relay message from server"),
+        }
+    }
+    // the relay drops our session when we disconnect
+    if relay.role.is_some() && net.connection_state() == ConnectionState::Disconnected {
+        relay.reset();
+    }
+}
+
+// PostUpdate, before laminar_flusher: send this frame's relay traffic, if we're connected
+fn relay_client_sender(mut net: ResMut<client::NetworkResource>, mut relay: ResMut<RelayClient>) {
+    if relay.outbox.is_empty() || net.connection_state() != ConnectionState::Connected {
+        return;
+    }
+    let server = *net.server_addr();
+    for packet in relay.outbox.drain(..) {
+        let packet = protocol::build(server, packet.delivery_guarantee(), packet.order_guarantee(), packet.payload().to_vec());
+        net.send_internal(MessageKind::Relay, packet);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::server::{RelayServer, RelayServerPlugin};
+
+#[cfg(not(target_arch = "wasm32"))]
+mod server {
+    use super::*;
+
+    use std::collections::{HashMap, HashSet};
+
+    use crate::server::NetworkResource;
+    use crate::PeerDisconnected;
+
+    /// Bevy resource (server), added by RelayServerPlugin
+    #[derive(Debug, Default)]
+    pub struct RelayServer {
+        // host -> its guests
+        hosts: HashMap<PeerHandle, HashSet<PeerHandle>>,
+        // guest -> its host
+        guests: HashMap<PeerHandle, PeerHandle>,
+    }
+
+    impl RelayServer {
+        /// everyone hosting right now
+        pub fn hosts(&self) -> impl Iterator<Item = PeerHandle> + '_ {
+            self.hosts.keys().copied()
+        }
+
+        pub fn guests_of(&self, host: PeerHandle) -> impl Iterator<Item = PeerHandle> + '_ {
+            self.hosts.get(&host).into_iter().flatten().copied()
+        }
+
+        pub fn host_of(&self, guest: PeerHandle) -> Option<PeerHandle> {
+            self.guests.get(&guest).copied()
+        }
+
+        fn in_session(&self, handle: PeerHandle) -> bool {
+            self.hosts.contains_key(&handle) || self.guests.contains_key(&handle)
+        }
+
+        fn paired(&self, from: PeerHandle, to: PeerHandle) -> bool {
+            self.guests.get(&from) == Some(&to) || self.guests.get(&to) == Some(&from)
+        }
+
+        fn handle(&mut self, packet: LaminarPacket, out: &mut Vec<LaminarPacket>) {
+            let from = packet.addr();
+            let (&kind, mut body) = match packet.payload().split_first() {
+                Some(split) => split,
+                None => return,
+            };
+            let result = match kind {
+                HOST => self.host(from, out),
+                JOIN => match read_addr(&mut body) {
+                    Some(host) => self.join(from, host, out),
+                    None => return,
+                },
+                SEND => match read_addr(&mut body) {
+                    Some(to) if self.paired(from, to) => {
+                        let forward = with_addr(MESSAGE, from, body);
+                        out.push(protocol::build(to, packet.delivery_guarantee(), packet.order_guarantee(), forward));
+                        Ok(())
+                    }
+                    Some(_) => Err(RelayError::NotPaired),
+                    None => return,
+                },
+                LEAVE => {
+                    if self.in_session(from) {
+                        self.leave(from, out);
+                        Ok(())
+                    } else {
+                        Err(RelayError::NotInSession)
+                    }
+                }
+                _ => {
+                    log::warn!("Unknown relay message {} from {}", kind, from);
+                    return;
+                }
+            };
+            if let Err(error) = result {
+                out.push(control_packet(from, vec![ERROR, error.to_u8()]));
+            }
+        }
+
+        fn host(&mut self, from: PeerHandle, out: &mut Vec<LaminarPacket>) -> Result<(), RelayError> {
+            if self.in_session(from) {
+                return Err(RelayError::AlreadyInSession);
+            }
+            self.hosts.insert(from, HashSet::new());
+            log::info!("{} is hosting through the relay", from);
+            out.push(control_packet(from, with_addr(HOSTING, from, &[])));
+            Ok(())
+        }
+
+        fn join(&mut self, from: PeerHandle, host: PeerHandle, out: &mut Vec<LaminarPacket>) -> Result<(), RelayError> {
+            if self.in_session(from) {
+                return Err(RelayError::AlreadyInSession);
+            }
+            let guests = self.hosts.get_mut(&host).ok_or(RelayError::NoSuchHost)?;
+            guests.insert(from);
+            self.guests.insert(from, host);
+            out.push(control_packet(from, with_addr(JOINED, host, &[])));
+            out.push(control_packet(host, with_addr(PEER_JOINED, from, &[])));
+            Ok(())
+        }
+
+        // also used when a peer disconnects
+        fn leave(&mut self, from: PeerHandle, out: &mut Vec<LaminarPacket>) {
+            if let Some(guests) = self.hosts.remove(&from) {
+                for guest in guests {
+                    self.guests.remove(&guest);
+                    out.push(control_packet(guest, with_addr(PEER_LEFT, from, &[])));
+                }
+            } else if let Some(host) = self.guests.remove(&from) {
+                if let Some(guests) = self.hosts.get_mut(&host) {
+                    guests.remove(&from);
+                }
+                out.push(control_packet(host, with_addr(PEER_LEFT, from, &[])));
+            }
+        }
+    }
+
+    /// Turns a server into a relay, add it after ServerNetworkingPlugin
+    #[derive(Default)]
+    pub struct RelayServerPlugin;
+
+    impl Plugin for RelayServerPlugin {
+        fn build(&self, app: &mut AppBuilder) {
+            app
+            .init_resource::<RelayServer>()
+            .add_system_to_stage(CoreStage::PreUpdate, relay_server.system().after(NetworkSystem::Receive))
+            ;
+        }
+    }
+
+    // PreUpdate, after laminar_receiver: forward messages, and end sessions of disconnected peers
+    fn relay_server(
+        mut net: ResMut<NetworkResource>,
+        mut relay: ResMut<RelayServer>,
+        mut disconnected: EventReader<PeerDisconnected>,
+    ) {
+        let net = &mut *net;
+        let mut out = Vec::new();
+        for packet in std::mem::take(&mut net.relay_inbox) {
+            relay.handle(packet, &mut out);
+        }
+        for event in disconnected.iter() {
+            relay.leave(event.handle, &mut out);
+        }
+        for packet in out {
+            if net.peer(packet.addr()).is_some() {
+                net.send_internal(MessageKind::Relay, packet).unwrap_or_default();
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        use laminar::OrderingGuarantee;
+
+        fn peer(port: u16) -> PeerHandle {
+            ([127, 0, 0, 1], port).into()
+        }
+
+        // what one message from `from` sends, and to whom
+        fn handle(relay: &mut RelayServer, from: PeerHandle, body: Vec<u8>) -> Vec<(PeerHandle, Vec<u8>)> {
+            let mut out = Vec::new();
+            relay.handle(control_packet(from, body), &mut out);
+            out.into_iter().map(|packet| (packet.addr(), packet.payload().to_vec())).collect()
+        }
+
+        fn error(to: PeerHandle, error: RelayError) -> Vec<(PeerHandle, Vec<u8>)> {
+            vec![(to, vec![ERROR, error.to_u8()])]
+        }
+
+        // peer(1) hosting, with guests at the given ports
+        fn session(guests: &[u16]) -> RelayServer {
+            let mut relay = RelayServer::default();
+            handle(&mut relay, peer(1), vec![HOST]);
+            for guest in guests {
+                handle(&mut relay, peer(*guest), with_addr(JOIN, peer(1), &[]));
+            }
+            relay
+        }
+
+        #[test]
+        fn host_then_join() {
+            let mut relay = RelayServer::default();
+            assert_eq!(handle(&mut relay, peer(1), vec![HOST]), vec![(peer(1), with_addr(HOSTING, peer(1), &[]))]);
+            assert_eq!(handle(&mut relay, peer(2), with_addr(JOIN, peer(1), &[])), vec![
+                (peer(2), with_addr(JOINED, peer(1), &[])),
+                (peer(1), with_addr(PEER_JOINED, peer(2), &[])),
+            ]);
+            assert_eq!(relay.hosts().collect::<Vec<_>>(), vec![peer(1)]);
+            assert_eq!(relay.guests_of(peer(1)).collect::<Vec<_>>(), vec![peer(2)]);
+            assert_eq!(relay.host_of(peer(2)), Some(peer(1)));
+        }
+
+        #[test]
+        fn messages_are_forwarded_within_a_session() {
+            let mut relay = session(&[2, 3]);
+            let send = LaminarPacket::unreliable_sequenced(peer(2), with_addr(SEND, peer(1), b"hi"), Some(4));
+            let mut out = Vec::new();
+            relay.handle(send, &mut out);
+            assert_eq!(out.len(), 1);
+            assert_eq!((out[0].addr(), out[0].payload()), (peer(1), &with_addr(MESSAGE, peer(2), b"hi")[..]));
+            assert_eq!(out[0].order_guarantee(), OrderingGuarantee::Sequenced(Some(4)));
+            // and from the host to a guest
+            let out = handle(&mut relay, peer(1), with_addr(SEND, peer(3), b"yo"));
+            assert_eq!(out, vec![(peer(3), with_addr(MESSAGE, peer(1), b"yo"))]);
+        }
+
+        #[test]
+        fn sends_outside_the_session_are_refused() {
+            let mut relay = session(&[2, 3]);
+            // guests only talk to their host
+            assert_eq!(handle(&mut relay, peer(2), with_addr(SEND, peer(3), b"hi")), error(peer(2), RelayError::NotPaired));
+            assert_eq!(handle(&mut relay, peer(9), with_addr(SEND, peer(1), b"hi")), error(peer(9), RelayError::NotPaired));
+            assert_eq!(handle(&mut relay, peer(1), with_addr(SEND, peer(9), b"hi")), error(peer(1), RelayError::NotPaired));
+        }
+
+        #[test]
+        fn a_host_leaving_ends_the_session() {
+            let mut relay = session(&[2, 3]);
+            let mut out = handle(&mut relay, peer(1), vec![LEAVE]);
+            out.sort();
+            assert_eq!(out, vec![
+                (peer(2), with_addr(PEER_LEFT, peer(1), &[])),
+                (peer(3), with_addr(PEER_LEFT, peer(1), &[])),
+            ]);
+            assert_eq!(relay.hosts().count(), 0);
+            assert_eq!(relay.host_of(peer(2)), None);
+            // the guests are free to host or join someone else
+            assert_eq!(handle(&mut relay, peer(2), vec![HOST]), vec![(peer(2), with_addr(HOSTING, peer(2), &[]))]);
+        }
+
+        #[test]
+        fn a_guest_leaving_tells_its_host() {
+            let mut relay = session(&[2, 3]);
+            assert_eq!(handle(&mut relay, peer(2), vec![LEAVE]), vec![(peer(1), with_addr(PEER_LEFT, peer(2), &[]))]);
+            assert_eq!(relay.guests_of(peer(1)).collect::<Vec<_>>(), vec![peer(3)]);
+            // disconnecting works the same way
+            let mut out = Vec::new();
+            relay.leave(peer(3), &mut out);
+            assert_eq!(out.len(), 1);
+            assert_eq!(relay.guests_of(peer(1)).count(), 0);
+            assert_eq!(handle(&mut relay, peer(3), vec![LEAVE]), error(peer(3), RelayError::NotInSession));
+        }
+
+        #[test]
+        fn joining_nobody() {
+            let mut relay = session(&[]);
+            assert_eq!(handle(&mut relay, peer(2), with_addr(JOIN, peer(9), &[])), error(peer(2), RelayError::NoSuchHost));
+            // only hosts can be joined
+            handle(&mut relay, peer(3), with_addr(JOIN, peer(1), &[]));
+            assert_eq!(handle(&mut relay, peer(2), with_addr(JOIN, peer(3), &[])), error(peer(2), RelayError::NoSuchHost));
+        }
+
+        #[test]
+        fn one_session_at_a_time() {
+            let mut relay = session(&[2]);
+            assert_eq!(handle(&mut relay, peer(1), vec![HOST]), error(peer(1), RelayError::AlreadyInSession));
+            assert_eq!(handle(&mut relay, peer(2), vec![HOST]), error(peer(2), RelayError::AlreadyInSession));
+            handle(&mut relay, peer(5), vec![HOST]);
+            assert_eq!(handle(&mut relay, peer(1), with_addr(JOIN, peer(5), &[])), error(peer(1), RelayError::AlreadyInSession));
+            assert_eq!(handle(&mut relay, peer(2), with_addr(JOIN, peer(5), &[])), error(peer(2), RelayError::AlreadyInSession));
+            assert_eq!(relay.guests_of(peer(5)).count(), 0);
+        }
+
+        #[test]
+        fn malformed_messages_are_ignored() {
+            let mut relay = session(&[2]);
+            assert!(handle(&mut relay, peer(2), vec![]).is_empty());
+            assert!(handle(&mut relay, peer(2), vec![JOIN, 4, 127]).is_empty());
+            assert!(handle(&mut relay, peer(2), vec![SEND]).is_empty());
+            assert!(handle(&mut relay, peer(2), vec![HOSTING]).is_empty());
+            assert_eq!(relay.host_of(peer(2)), Some(peer(1)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use laminar::OrderingGuarantee;
+
+    fn peer(port: u16) -> PeerHandle {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    fn relay() -> SocketAddr {
+        ([10, 0, 0, 1], 7777).into()
+    }
+
+    fn handle(client: &mut RelayClient, body: Vec<u8>) -> Option<RelayEvent> {
+        client.handle(control_packet(relay(), body))
+    }
+
+    #[test]
+    fn hosting() {
+        let mut client = RelayClient::default();
+        let event = handle(&mut client, with_addr(HOSTING, peer(1), &[]));
+        assert!(matches!(event, Some(RelayEvent::Hosting { handle }) if handle == peer(1)));
+        assert_eq!(client.role(), Some(RelayRole::Host { handle: peer(1) }));
+
+        handle(&mut client, with_addr(PEER_JOINED, peer(2), &[]));
+        let event = handle(&mut client, with_addr(PEER_JOINED, peer(3), &[]));
+        assert!(matches!(event, Some(RelayEvent::PeerJoined { handle }) if handle == peer(3)));
+        assert_eq!(client.guests(), &[peer(2), peer(3)]);
+
+        let event = handle(&mut client, with_addr(PEER_LEFT, peer(2), &[]));
+        assert!(matches!(event, Some(RelayEvent::PeerLeft { handle }) if handle == peer(2)));
+        assert_eq!(client.guests(), &[peer(3)]);
+        assert_eq!(client.role(), Some(RelayRole::Host { handle: peer(1) }));
+    }
+
+    #[test]
+    fn joining_until_the_host_leaves() {
+        let mut client = RelayClient::default();
+        let event = handle(&mut client, with_addr(JOINED, peer(1), &[]));
+        assert!(matches!(event, Some(RelayEvent::Joined { host }) if host == peer(1)));
+        assert_eq!(client.role(), Some(RelayRole::Guest { host: peer(1) }));
+        // someone else leaving changes nothing for us
+        handle(&mut client, with_addr(PEER_LEFT, peer(2), &[]));
+        assert_eq!(client.role(), Some(RelayRole::Guest { host: peer(1) }));
+        handle(&mut client, with_addr(PEER_LEFT, peer(1), &[]));
+        assert_eq!(client.role(), None);
+    }
+
+    #[test]
+    fn relayed_messages_come_from_the_sender() {
+        let mut client = RelayClient::default();
+        let packet = LaminarPacket::reliable_sequenced(relay(), with_addr(MESSAGE, peer(2), b"hi"), Some(4));
+        match client.handle(packet) {
+            Some(RelayEvent::Message { packet }) => {
+                assert_eq!((packet.addr(), packet.payload()), (peer(2), &b"hi"[..]));
+                assert_eq!(packet.order_guarantee(), OrderingGuarantee::Sequenced(Some(4)));
+            }
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut client = RelayClient::default();
+        handle(&mut client, with_addr(HOSTING, peer(1), &[]));
+        handle(&mut client, with_addr(PEER_JOINED, peer(2), &[]));
+        for (byte, error) in &[(0, RelayError::NoSuchHost), (1, RelayError::AlreadyInSession), (3, RelayError::NotPaired)] {
+            let event = handle(&mut client, vec![ERROR, *byte]);
+            assert!(matches!(event, Some(RelayEvent::Error(e)) if e == *error));
+        }
+        assert!(client.role().is_some());
+        // the relay doesn't think we're in a session, so we aren't
+        let event = handle(&mut client, vec![ERROR, 2]);
+        assert!(matches!(event, Some(RelayEvent::Error(RelayError::NotInSession))));
+        assert_eq!((client.role(), client.guests().len()), (None, 0));
+    }
+
+    #[test]
+    fn malformed_messages_are_none() {
+        let mut client = RelayClient::default();
+        for body in vec![vec![], vec![HOSTING], vec![JOINED, 4, 1, 2], vec![MESSAGE, 9], vec![ERROR], vec![ERROR, 99], vec![HOST]] {
+            assert!(handle(&mut client, body.clone()).is_none(), "{:?}", body);
+        }
+        assert_eq!(client.role(), None);
+    }
+
+    #[test]
+    fn requests_are_queued() {
+        let mut client = RelayClient::default();
+        client.host();
+        client.send(LaminarPacket::unreliable(peer(2), b"hi".to_vec()));
+        handle(&mut client, with_addr(HOSTING, peer(1), &[]));
+        client.leave();
+        assert_eq!(client.role(), None);
+        let bodies: Vec<_> = client.outbox.iter().map(|packet| packet.payload().to_vec()).collect();
+        assert_eq!(bodies, vec![vec![HOST], with_addr(SEND, peer(2), b"hi"), vec![LEAVE]]);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{collections::HashMap, convert::TryInto, time::Duration};
+
+use instant::Instant;
+
+use crate::PeerHandle;
+
+// Request/response on top of reliable_unordered laminar packets, usable in both directions.
+//
+//  RpcRequest:  [id: u32][method_len: u8][method: utf8][payload]
+//  RpcResponse: [id: u32][payload]
+//
+// ids are allocated by whoever sends the request, so an RpcHandle is only unique together
+// with the peer it was sent to / received from.
+
+/// Identifies one request, either one we sent (resolved by an RpcResponse event) or one we
+/// received (pass it to `respond` to reply).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct RpcHandle {
+    peer: PeerHandle,
+    id: u32,
+}
+
+impl RpcHandle {
+    pub fn peer(&self) -> PeerHandle {
+        self.peer
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RpcError {
+    /// no response within the timeout given to `request`
+    Timeout,
+    /// the connection went away before a response arrived
+    Disconnected,
+    /// `request` was given a method name over 255 bytes, nothing was sent
+    MethodNameTooLong,
+}
+
+/// Bevy event: the remote side sent us a request. Reply with `respond(request.handle, ..)`.
+#[derive(Debug, Clone)]
+pub struct RpcRequest {
+    pub handle: RpcHandle,
+    pub method: String,
+    pub payload: Vec<u8>,
+}
+
+/// Bevy event: a request we sent has resolved, one way or another.
+#[derive(Debug, Clone)]
+pub struct RpcResponse {
+    pub handle: RpcHandle,
+    pub result: Result<Vec<u8>, RpcError>,
+}
+
+/// tracks our outstanding requests and their deadlines
+#[derive(Debug, Default)]
+pub(crate) struct RpcTracker {
+    next_id: u32,
+    pending: HashMap<RpcHandle, Instant>,
+}
+
+impl RpcTracker {
+    pub(crate) fn start(&mut self, peer: PeerHandle, timeout: Duration, now: Instant) -> RpcHandle {
+        let handle = RpcHandle { peer, id: self.next_id };
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending.insert(handle, now + timeout);
+        handle
+    }
+
+    /// false if we weren't waiting for this one, eg. it already timed out
+    pub(crate) fn complete(&mut self, handle: RpcHandle) -> bool {
+        self.pending.remove(&handle).is_some()
+    }
+
+    /// the requests whose deadline has passed, failed with Timeout
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<RpcResponse> {
+        let expired: Vec<RpcHandle> = self
+            .pending
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(handle, _)| *handle)
+            .collect();
+        self.fail(expired, RpcError::Timeout)
+    }
+
+    /// the requests to a peer that's gone, failed with Disconnected
+    pub(crate) fn drop_peer(&mut self, peer: PeerHandle) -> Vec<RpcResponse> {
+        let dropped: Vec<RpcHandle> = self
+            .pending
+            .keys()
+            .filter(|handle| handle.peer == peer)
+            .copied()
+            .collect();
+        self.fail(dropped, RpcError::Disconnected)
+    }
+
+    fn fail(&mut self, handles: Vec<RpcHandle>, error: RpcError) -> Vec<RpcResponse> {
+        handles
+            .into_iter()
+            .map(|handle| {
+                self.pending.remove(&handle);
+                RpcResponse { handle, result: Err(error) }
+            })
+            .collect()
+    }
+}
+
+/// the method name's length has to fit in a u8
+pub(crate) fn check_method(method: &str) -> Result<(), RpcError> {
+    if method.len() > u8::MAX as usize {
+        return Err(RpcError::MethodNameTooLong);
+    }
+    Ok(())
+}
+
+// check_method first
+pub(crate) fn encode_request(id: u32, method: &str, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(5 + method.len() + payload.len());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.push(method.len() as u8);
+    buf.extend_from_slice(method.as_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+pub(crate) fn encode_response(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+pub(crate) fn decode_request(peer: PeerHandle, bytes: &[u8]) -> Option<RpcRequest> {
+    let id = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+    let method_len = *bytes.get(4)? as usize;
+    let method = std::str::from_utf8(bytes.get(5..5 + method_len)?).ok()?;
+    Some(RpcRequest {
+        handle: RpcHandle { peer, id },
+        method: method.to_string(),
+        payload: bytes[5 + method_len..].to_vec(),
+    })
+}
+
+pub(crate) fn decode_response(peer: PeerHandle, bytes: &[u8]) -> Option<(RpcHandle, Vec<u8>)> {
+    let id = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+    Some((RpcHandle { peer, id }, bytes[4..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> PeerHandle {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    // (id, result) for each response, in id order
+    fn results(responses: Vec<RpcResponse>) -> Vec<(u32, Result<Vec<u8>, RpcError>)> {
+        let mut results: Vec<_> = responses.into_iter().map(|response| (response.handle.id(), response.result)).collect();
+        results.sort_by_key(|(id, _)| *id);
+        results
+    }
+
+    #[test]
+    fn request_roundtrip() {
+        let bytes = encode_request(0xdead_beef, "get_score", b"player 1");
+        let request = decode_request(peer(1), &bytes).unwrap();
+        assert_eq!(request.handle, RpcHandle { peer: peer(1), id: 0xdead_beef });
+        assert_eq!(request.method, "get_score");
+        assert_eq!(request.payload, b"player 1");
+
+        let request = decode_request(peer(1), &encode_request(7, "", &[])).unwrap();
+        assert_eq!((request.method.as_str(), request.payload.len()), ("", 0));
+        let long = "x".repeat(255);
+        assert_eq!(decode_request(peer(1), &encode_request(7, &long, &[1])).unwrap().method, long);
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let (handle, payload) = decode_response(peer(2), &encode_response(42, b"1234")).unwrap();
+        assert_eq!((handle.peer(), handle.id()), (peer(2), 42));
+        assert_eq!(payload, b"1234");
+        assert_eq!(decode_response(peer(2), &encode_response(42, &[])).unwrap().1, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn malformed_messages_are_none() {
+        let bytes = encode_request(1, "method", b"payload");
+        for len in 0..5 + "method".len() {
+            assert!(decode_request(peer(1), &bytes[..len]).is_none(), "truncated to {}", len);
+        }
+        let mut bad_utf8 = encode_request(1, "ab", &[]);
+        bad_utf8[5] = 0xff;
+        assert!(decode_request(peer(1), &bad_utf8).is_none());
+        assert!(decode_response(peer(1), &[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn long_method_names_are_refused() {
+        assert_eq!(check_method(&"x".repeat(255)), Ok(()));
+        assert_eq!(check_method(&"x".repeat(256)), Err(RpcError::MethodNameTooLong));
+    }
+
+    #[test]
+    fn requests_time_out() {
+        let mut rpc = RpcTracker::default();
+        let now = Instant::now();
+        let quick = rpc.start(peer(1), Duration::from_millis(100), now);
+        let slow = rpc.start(peer(1), Duration::from_secs(5), now);
+        assert_ne!(quick, slow);
+
+        assert!(rpc.expired(now + Duration::from_millis(99)).is_empty());
+        assert_eq!(results(rpc.expired(now + Duration::from_millis(100))), vec![(quick.id(), Err(RpcError::Timeout))]);
+        // reported once, and a late response for it is dropped
+        assert!(rpc.expired(now + Duration::from_secs(1)).is_empty());
+        assert!(!rpc.complete(quick));
+
+        assert!(rpc.complete(slow));
+        assert!(rpc.expired(now + Duration::from_secs(10)).is_empty());
+    }
+
+    #[test]
+    fn dropping_a_peer_fails_its_requests() {
+        let mut rpc = RpcTracker::default();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(5);
+        let first = rpc.start(peer(1), timeout, now);
+        let other = rpc.start(peer(2), timeout, now);
+        let second = rpc.start(peer(1), timeout, now);
+
+        assert_eq!(results(rpc.drop_peer(peer(1))), vec![
+            (first.id(), Err(RpcError::Disconnected)),
+            (second.id(), Err(RpcError::Disconnected)),
+        ]);
+        assert!(rpc.drop_peer(peer(1)).is_empty());
+        assert!(!rpc.complete(first));
+        assert_eq!(results(rpc.expired(now + timeout)), vec![(other.id(), Err(RpcError::Timeout))]);
+    }
+
+    #[test]
+    fn ids_wrap() {
+        let mut rpc = RpcTracker { next_id: u32::MAX, ..Default::default() };
+        let now = Instant::now();
+        assert_eq!(rpc.start(peer(1), Duration::from_secs(1), now).id(), u32::MAX);
+        assert_eq!(rpc.start(peer(1), Duration::from_secs(1), now).id(), 0);
+    }
+}
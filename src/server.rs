@@ -1,6 +1,6 @@
 use bevy::{
     log,
-    app::{AppBuilder, EventWriter, Plugin},
+    app::{AppBuilder, CoreStage, EventWriter, Plugin},
     ecs::prelude::*,
     tasks::{IoTaskPool, TaskPool, Task},
 };
@@ -19,7 +19,10 @@ use std::{
     fmt::Debug,
     net::SocketAddr,
     io,
-    collections::HashMap,
+    path::PathBuf,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use instant::Instant;
@@ -45,6 +48,21 @@ pub use laminar::{
 };
 
 use crate::prelude::*;
+use crate::clock;
+use crate::PeerEventWriters;
+use crate::protocol::{self, MessageKind};
+use crate::rpc::{self, RpcTracker};
+use crate::transfer::{self, Transfers};
+use crate::snapshot::SnapshotEncoder;
+use crate::capture::{self, CaptureEvent, SharedRecorder};
+use crate::conditioner::{Direction, LinkConditioner};
+use crate::filter::AddressFilter;
+use crate::challenge::Challenges;
+use crate::discovery::DiscoveryResponder;
+use crate::query::{self, InfoResponder};
+use crate::ratelimit::RateLimiter;
+use crate::batch::{self, Batcher};
+use crate::session::{self, SessionMessage};
 
 pub mod prelude {
     pub use super::{LaminarConfig, LaminarPacket, LaminarSocketEvent};
@@ -52,6 +70,7 @@ pub mod prelude {
     pub use naia_client_socket::LinkConditionerConfig;
     pub use super::NetworkResource;
     pub use super::ServerNetworkingPlugin;
+    pub use super::{local_peer_handle, LocalMessageReceived};
 }
 
 #[derive(Debug)]
@@ -59,12 +78,39 @@ pub struct LaminarDatagramSocketForNaia {
     pub bind_address: SocketAddr,
     pub naia_packet_receiver: Receiver<NaiaPacket>,
     pub naia_payload_sender: Sender<NaiaPacket>,
+    pub conditioner: LinkConditioner,
+    pub filter: AddressFilter,
+    challenges: Challenges,
+    rate_limiter: RateLimiter,
+    info: InfoResponder,
 }
 
-impl LaminarDatagramSocket for LaminarDatagramSocketForNaia {
-    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
-        match self.naia_payload_sender.send(NaiaPacket::new(*addr, payload.to_vec())) {
-            Ok(()) => Ok(payload.len()),
+impl LaminarDatagramSocketForNaia {
+    // only addresses that passed the connection challenge, and are within their rate limit,
+    // get through to laminar. server info queries are answered here, whoever they're from.
+    fn admit(&mut self, addr: SocketAddr, payload: &[u8]) -> io::Result<bool> {
+        let now = Instant::now();
+        if query::is_query(payload) {
+            if let Some(reply) = self.info.answer(payload) {
+                if let Some(reply) = self.conditioner.condition(Direction::Outgoing, addr, reply, now) {
+                    self.send_to_naia(addr, reply)?;
+                }
+            }
+            return Ok(false);
+        }
+        let mut reply = None;
+        let admitted = self.challenges.admit(addr, payload, now, &mut reply);
+        if let Some(reply) = reply {
+            if let Some(reply) = self.conditioner.condition(Direction::Outgoing, addr, reply, now) {
+                self.send_to_naia(addr, reply)?;
+            }
+        }
+        Ok(admitted && self.rate_limiter.allow(addr, payload.len(), now))
+    }
+
+    fn send_to_naia(&self, addr: SocketAddr, payload: Vec<u8>) -> io::Result<()> {
+        match self.naia_payload_sender.send(NaiaPacket::new(addr, payload)) {
+            Ok(()) => Ok(()),
             Err(err) => {
                     log::error!("Failed sending packet to naia sender?");
                     assert!(false, "Crossbeam SendError for {:?}", err);
@@ -74,24 +120,63 @@ impl LaminarDatagramSocket for LaminarDatagramSocketForNaia {
             }
         }
     }
+}
+
+impl LaminarDatagramSocket for LaminarDatagramSocketForNaia {
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if let Some(conditioned) = self.conditioner.condition(Direction::Outgoing, *addr, payload.to_vec(), now) {
+            self.send_to_naia(*addr, conditioned)?;
+        }
+        // anything the conditioner didn't delay, or delayed datagrams that are now due
+        while let Some((addr, payload)) = self.conditioner.release(Direction::Outgoing, now) {
+            self.send_to_naia(addr, payload)?;
+        }
+        Ok(payload.len())
+    }
 
     fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
-        match self.naia_packet_receiver.try_recv() {
-            Ok(packet) => {
-                let payload = packet.payload();
-                buffer[..payload.len()].clone_from_slice(payload);
-                Ok((&buffer[..payload.len()], packet.address()))
+        let now = Instant::now();
+        // laminar polls us every frame, so that's when delayed outgoing datagrams go out
+        while let Some((addr, payload)) = self.conditioner.release(Direction::Outgoing, now) {
+            self.send_to_naia(addr, payload)?;
+        }
+        loop {
+            if let Some((addr, payload)) = self.conditioner.release(Direction::Incoming, now) {
+                // could have been banned while it was delayed
+                if !self.filter.is_allowed(addr.ip()) || !self.admit(addr, &payload)? {
+                    continue;
+                }
+                buffer[..payload.len()].clone_from_slice(&payload);
+                return Ok((&buffer[..payload.len()], addr));
             }
-            Err(error) => match error {
-                CrossbeamTryRecvError::Empty => {
-                    Err(io::Error::new(io::ErrorKind::WouldBlock, error))
-                },
-                CrossbeamTryRecvError::Disconnected => {
-                    log::error!("Crossbeam channel for naia is Disconnected?");
-                    assert!(false, "Crossbeam channel for naia is Disconnected? {:?}", error);
-                    Err(io::Error::new(io::ErrorKind::NotConnected, error))
+            match self.naia_packet_receiver.try_recv() {
+                Ok(packet) => {
+                    let address = packet.address();
+                    if !self.filter.is_allowed(address.ip()) {
+                        continue;
+                    }
+                    let conditioned = self.conditioner.condition(Direction::Incoming, address, packet.payload().to_vec(), now);
+                    if let Some(payload) = conditioned {
+                        if !self.admit(address, &payload)? {
+                            continue;
+                        }
+                        buffer[..payload.len()].clone_from_slice(&payload);
+                        return Ok((&buffer[..payload.len()], address));
+                    }
+                    // dropped or delayed by the conditioner, try the next one
                 }
-            },
+                Err(error) => match error {
+                    CrossbeamTryRecvError::Empty => {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, error));
+                    },
+                    CrossbeamTryRecvError::Disconnected => {
+                        log::error!("Crossbeam channel for naia is Disconnected?");
+                        assert!(false, "Crossbeam channel for naia is Disconnected? {:?}", error);
+                        return Err(io::Error::new(io::ErrorKind::NotConnected, error));
+                    }
+                },
+            }
         }
     }
 
@@ -108,6 +193,19 @@ impl LaminarDatagramSocket for LaminarDatagramSocketForNaia {
 #[derive(Default)]
 pub struct ServerNetworkingPlugin {
     pub link_conditioner: Option<LinkConditionerConfig>,
+    pub capture: Option<CaptureMode>,
+    /// named channels, must be declared identically on client and server
+    pub channels: ChannelRegistry,
+    /// coalesce small messages into fewer datagrams, off by default
+    pub batching: Option<BatchConfig>,
+    /// ban list file to load at startup, see AddressFilter::load
+    pub ban_list: Option<PathBuf>,
+    /// per-peer limits on incoming traffic, off by default
+    pub rate_limit: Option<RateLimitConfig>,
+    /// answer LAN discovery broadcasts once listening, off by default
+    pub discovery: Option<DiscoveryConfig>,
+    /// keep peers whose connection drops Suspended this long, so they can resume, off by default
+    pub session_grace: Option<Duration>,
 }
 
 impl Plugin for ServerNetworkingPlugin {
@@ -119,14 +217,63 @@ impl Plugin for ServerNetworkingPlugin {
             .0
             .clone();
 
-        app
-        .insert_resource(NetworkResource::new(
+        // a NetworkSettings resource inserted before the plugin wins over link_conditioner
+        let settings = app.world().get_resource::<NetworkSettings>().cloned().unwrap_or_else(|| NetworkSettings {
+            link_conditioner: self.link_conditioner.as_ref().map(Into::into),
+            ..Default::default()
+        });
+
+        let mut net_resource = NetworkResource::new(
             task_pool,
-            self.link_conditioner.clone(),
-        ))
+            settings.link_conditioner_config(),
+        );
+        net_resource.settings = settings.clone();
+        net_resource.set_channels(self.channels.clone());
+        net_resource.set_batching(self.batching.clone());
+        net_resource.set_rate_limit(self.rate_limit.clone());
+        net_resource.set_discovery(self.discovery.clone());
+        net_resource.set_session_grace(self.session_grace);
+        if let Some(path) = &self.ban_list {
+            if let Err(err) = net_resource.address_filter().load(path) {
+                log::error!("Can't load ban list {:?}: {}", path, err);
+            }
+        }
+        match &self.capture {
+            Some(CaptureMode::Record(path)) => match PacketRecorder::create(path) {
+                Ok(recorder) => net_resource.start_recording(recorder),
+                Err(err) => log::error!("Can't record to {:?}: {}", path, err),
+            },
+            Some(CaptureMode::Replay { path, paced }) => match CaptureReader::open(path) {
+                Ok(reader) => net_resource.start_replay(PacketReplayer::new(reader, *paced)),
+                Err(err) => log::error!("Can't replay {:?}: {}", path, err),
+            },
+            None => {},
+        }
+
+        app
+        .insert_resource(net_resource)
+        .insert_resource(settings)
         .add_event::<LaminarPacket>()
         .add_event::<PeerEvent>()
-        .add_system(laminar_poller.system())
+        .add_event::<PeerConnected>()
+        .add_event::<PeerDisconnected>()
+        .add_event::<PeerTimedOut>()
+        .add_event::<ConnectionStateChanged>()
+        .add_event::<MessageReceived>()
+        .add_event::<ChannelMessage>()
+        .add_event::<RpcRequest>()
+        .add_event::<RpcResponse>()
+        .add_event::<TransferProgress>()
+        .add_event::<TransferCompleted>()
+        .add_event::<TransferFailed>()
+        .add_event::<RateLimitExceeded>()
+        .add_event::<LocalMessageReceived>()
+        .add_system_to_stage(CoreStage::PreUpdate, apply_network_settings.system().before(NetworkSystem::Receive))
+        .add_system_to_stage(CoreStage::PreUpdate, discovery_responder.system())
+        .add_system_to_stage(CoreStage::PreUpdate, server_info_updater.system())
+        .add_system_to_stage(CoreStage::PreUpdate, laminar_receiver.system().label(NetworkSystem::Receive))
+        .add_system_to_stage(CoreStage::PreUpdate, local_peer_receiver.system().after(NetworkSystem::Receive))
+        .add_system_to_stage(CoreStage::PostUpdate, laminar_flusher.system().label(NetworkSystem::Flush))
         ;
     }
 }
@@ -137,37 +284,118 @@ impl Plugin for ServerNetworkingPlugin {
 #[derive(Debug)]
 pub struct Peer {
     pub epoch: Instant,
+    /// where it first connected from, which is its PeerHandle for good, even if it resumes its
+    /// session from somewhere else
     pub socket_addr: SocketAddr,
-    connection_state: ConnectionState,
+    // where it is now
+    current_addr: SocketAddr,
+    session_token: Option<SessionToken>,
+    suspended_until: Option<Instant>,
+    connection_state: ConnectionStateMachine,
     event_sender: Sender<LaminarPacket>,
+    snapshots: SnapshotEncoder,
+    recorder: SharedRecorder,
+    channels: Arc<ChannelRegistry>,
+    // shared with NetworkResource, so its sends and ours keep their order on a stream
+    batcher: Arc<Mutex<Batcher>>,
+    extensions: Extensions,
 }
 
 impl Peer {
-    fn new(socket_addr: SocketAddr, event_sender: Sender<LaminarPacket>) -> Self {
+    fn new(
+        socket_addr: SocketAddr,
+        event_sender: Sender<LaminarPacket>,
+        recorder: SharedRecorder,
+        channels: Arc<ChannelRegistry>,
+        batcher: Arc<Mutex<Batcher>>,
+    ) -> Self {
         Self {
             epoch: Instant::now(),
             socket_addr,
-            connection_state: ConnectionState::Connecting,
+            current_addr: socket_addr,
+            session_token: None,
+            suspended_until: None,
+            connection_state: ConnectionStateMachine::default(),
             event_sender,
+            snapshots: SnapshotEncoder::default(),
+            recorder,
+            channels,
+            batcher,
+            extensions: Extensions::default(),
         }
     }
 
+    pub fn handle(&self) -> PeerHandle {
+        self.socket_addr
+    }
+
+    /// same as handle(), where it first connected from
     pub fn addr(&self) -> SocketAddr {
         self.socket_addr
     }
 
-    pub fn send(&self, packet: LaminarPacket) -> Result<(), CrossbeamSendError<LaminarPacket>> {
-        self.event_sender.send(packet)
+    /// the address it's at now, which differs from its handle once it's resumed elsewhere, or if
+    /// it turned up at an address a resumed peer had left
+    pub fn current_addr(&self) -> SocketAddr {
+        self.current_addr
+    }
+
+    /// sends to a suspended peer go nowhere, and sends on a named channel's stream are refused
+    /// with ChannelError::ReservedStream
+    pub fn send(&self, packet: LaminarPacket) -> Result<(), ChannelError> {
+        self.channels.check_raw(&packet)?;
+        if self.state() == ConnectionState::Suspended {
+            return Ok(());
+        }
+        capture::record_outgoing(&self.recorder, MessageKind::User, &packet);
+        // batched by handle, like NetworkResource's sends, and readdressed when it goes out
+        let packet = protocol::readdress(packet, self.socket_addr);
+        match self.batcher.lock().unwrap().push(MessageKind::User, packet) {
+            Some(packet) => self
+                .event_sender
+                .send(protocol::readdress(packet, self.current_addr))
+                .map_err(|_| ChannelError::Disconnected),
+            None => Ok(()),
+        }
     }
 
     pub fn state(&self) -> ConnectionState {
-        self.connection_state
+        self.connection_state.state()
+    }
+
+    fn transition(&mut self, to: ConnectionState, cause: TransitionCause) -> Option<ConnectionStateChanged> {
+        self.connection_state.transition(self.socket_addr, to, cause)
+    }
+
+    /// when a suspended peer is given up on, unless it resumes first
+    pub fn suspended_until(&self) -> Option<Instant> {
+        self.suspended_until
     }
-    
-    fn set_state(&mut self, new_state: ConnectionState) -> bool {
-        let changed = new_state != self.connection_state;
-        self.connection_state = new_state;
-        changed
+
+    /// game data attached to this peer, dropped when the peer is removed
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// attach a value to this peer, replacing any previous value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.extensions.insert(value)
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.extensions.remove()
     }
 }
 
@@ -175,8 +403,70 @@ pub struct NetworkResource {
     task_pool: TaskPool,
     listeners: Vec<ServerListener>,
     manager: Option<LaminarConnectionManager<LaminarDatagramSocketForNaia, LaminarVirtualConnection>>,
-    link_conditioner: Option<LinkConditionerConfig>,
+    conditioner: LinkConditioner,
+    filter: AddressFilter,
+    challenges: Challenges,
+    rate_limiter: RateLimiter,
+    info: InfoResponder,
     peers: HashMap<SocketAddr, Peer>,
+    max_peers: Option<usize>,
+    // addresses we've told the server is full, until laminar drops their connection
+    turned_away: HashSet<SocketAddr>,
+    session_grace: Option<Duration>,
+    sessions: HashMap<SessionToken, PeerHandle>,
+    // current address -> handle, for peers that aren't at their handle
+    aliases: HashMap<SocketAddr, PeerHandle>,
+    // port of the next handle for a new client at an address whose handle is taken
+    next_spare_handle: u16,
+    // addresses resumed peers left while laminar still had a connection there. everything from
+    // that connection is ignored until laminar drops it.
+    stale: HashSet<SocketAddr>,
+    discovery_config: Option<DiscoveryConfig>,
+    discovery: Option<DiscoveryResponder>,
+    // listen-server mode: the local player's channel, and its socket events for laminar_receiver
+    local: Option<LocalPeer>,
+    local_events: Vec<LaminarSocketEvent>,
+    epoch: Instant,
+    tick: u64,
+    rpc: RpcTracker,
+    transfers: Transfers,
+    snapshot_config: SnapshotConfig,
+    recorder: SharedRecorder,
+    replayer: Option<PacketReplayer>,
+    // stands in for laminar's event sender while replaying, nothing is actually sent
+    replay_sink: (Sender<LaminarPacket>, Receiver<LaminarPacket>),
+    channels: Arc<ChannelRegistry>,
+    // send_internal only has &self
+    batcher: Arc<Mutex<Batcher>>,
+    // state changes made outside laminar_receiver, published on its next run
+    transitions: Vec<ConnectionStateChanged>,
+    // last NetworkSettings applied
+    settings: NetworkSettings,
+    // lobby messages for the lobby plugin, cleared every frame in case there isn't one
+    pub(crate) lobby_inbox: Vec<(PeerHandle, Vec<u8>)>,
+    // same for the relay plugins, whole packets since relayed ones keep their guarantees
+    pub(crate) relay_inbox: Vec<LaminarPacket>,
+}
+
+// the local player in listen-server mode. whatever we send it lands in `receiver` instead of
+// going to laminar, and local_peer_receiver publishes it.
+#[derive(Debug)]
+struct LocalPeer {
+    sender: Sender<LaminarPacket>,
+    receiver: Receiver<LaminarPacket>,
+}
+
+/// The PeerHandle of the local player in listen-server mode. No real peer can have it.
+pub fn local_peer_handle() -> PeerHandle {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+/// Bevy event (server, listen-server mode): the server sent the local player something.
+/// `channel` is set for messages sent with send_on.
+#[derive(Debug, Clone)]
+pub struct LocalMessageReceived {
+    pub packet: LaminarPacket,
+    pub channel: Option<ChannelId>,
 }
 
 // just used to keep tasks in scope so they aren't dropped
@@ -193,15 +483,138 @@ impl NetworkResource {
     {
         NetworkResource {
             task_pool,
-            link_conditioner,
+            conditioner: LinkConditioner::from_naia(link_conditioner.as_ref()),
+            filter: AddressFilter::default(),
+            challenges: Challenges::default(),
+            rate_limiter: RateLimiter::default(),
+            info: InfoResponder::default(),
             listeners: Vec::new(),
             manager: None,
             peers: HashMap::new(),
+            max_peers: None,
+            turned_away: HashSet::new(),
+            session_grace: None,
+            sessions: HashMap::new(),
+            aliases: HashMap::new(),
+            next_spare_handle: 1,
+            stale: HashSet::new(),
+            discovery_config: None,
+            discovery: None,
+            local: None,
+            local_events: Vec::new(),
+            epoch: Instant::now(),
+            tick: 0,
+            rpc: RpcTracker::default(),
+            transfers: Transfers::default(),
+            snapshot_config: SnapshotConfig::default(),
+            recorder: Arc::new(Mutex::new(None)),
+            replayer: None,
+            replay_sink: unbounded(),
+            channels: Arc::default(),
+            batcher: Arc::default(),
+            transitions: Vec::new(),
+            settings: NetworkSettings {
+                link_conditioner: link_conditioner.as_ref().map(Into::into),
+                ..Default::default()
+            },
+            lobby_inbox: Vec::new(),
+            relay_inbox: Vec::new(),
         }
     }
 
+    /// time since this resource was created, which is what clients sync their ServerClock to
+    pub fn server_time(&self) -> Duration {
+        Instant::now() - self.epoch
+    }
+
+    /// number of times laminar_receiver has run, ie. frames since startup
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
     fn new_peer(&self, addr: SocketAddr) -> Peer {
-        Peer::new(addr, self.event_sender().clone())
+        let event_sender = if self.replaying() {
+            self.replay_sink.0.clone()
+        } else {
+            self.event_sender().clone()
+        };
+        Peer::new(addr, event_sender, self.recorder.clone(), self.channels.clone(), self.batcher.clone())
+    }
+
+    /// adjust simulated latency, jitter, loss and corruption while running, globally or per peer
+    pub fn link_conditioner(&self) -> &LinkConditioner {
+        &self.conditioner
+    }
+
+    /// the NetworkSettings in use, see apply_settings
+    pub fn settings(&self) -> &NetworkSettings {
+        &self.settings
+    }
+
+    /// switch to new settings. the conditioner's incoming profile changes right away if it
+    /// differs, the laminar config is used by the next listen_with_settings().
+    /// apply_network_settings does this whenever the NetworkSettings resource changes.
+    pub fn apply_settings(&mut self, settings: NetworkSettings) {
+        if settings.link_conditioner != self.settings.link_conditioner {
+            let profile = settings.link_conditioner_config().map(|config| LinkProfile::from_naia(&config));
+            self.conditioner.set_incoming(profile);
+        }
+        self.settings = settings;
+    }
+
+    /// ban or allow addresses and ranges while running. blocked datagrams are dropped before
+    /// laminar sees them, so already connected peers that get banned just time out.
+    pub fn address_filter(&self) -> &AddressFilter {
+        &self.filter
+    }
+
+    /// limit how many packets and bytes per second each peer can send us. traffic over the
+    /// limit is dropped before laminar sees it, and reported as RateLimitExceeded events.
+    pub fn set_rate_limit(&mut self, config: Option<RateLimitConfig>) {
+        self.rate_limiter.set_config(config);
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimitConfig> {
+        self.rate_limiter.config()
+    }
+
+    /// record every message in and out, and peer status changes, until stop_recording is called
+    pub fn start_recording(&mut self, recorder: PacketRecorder) {
+        *self.recorder.lock().unwrap() = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) {
+        *self.recorder.lock().unwrap() = None;
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recorder.lock().unwrap().is_some()
+    }
+
+    /// feed a recording through laminar_receiver instead of the network. don't call listen(),
+    /// sends are silently dropped while replaying.
+    pub fn start_replay(&mut self, replayer: PacketReplayer) {
+        self.replayer = Some(replayer);
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.replayer.is_some()
+    }
+
+    // keep peers up to date with replayed status events, so net.peer(..) works as it did live
+    fn apply_replayed(&mut self, handle: PeerHandle, state: ConnectionState) {
+        match state {
+            ConnectionState::Disconnected => {
+                self.peers.remove(&handle);
+            },
+            _ => {
+                if !self.peers.contains_key(&handle) {
+                    let peer = self.new_peer(handle);
+                    self.peers.insert(handle, peer);
+                }
+                self.peers.get_mut(&handle).unwrap().connection_state.force(state);
+            },
+        }
     }
 
     pub fn peer(&self, handle: PeerHandle) -> Option<&Peer> {
@@ -216,13 +629,223 @@ impl NetworkResource {
         self.peers.len()
     }
 
-    // fn peers_mut(&mut self) -> &mut HashMap<SocketAddr, Peer> {
-    //     &mut self.peers
-    // }
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values()
+    }
 
-    // pub fn peers(&self) -> &HashMap<SocketAddr, Peer> {
-    //     &self.peers
-    // }
+    /// turn away new peers once there are this many, None for no limit.
+    /// they're sent a goodbye, and their client goes to Disconnected with TransitionCause::Rejected.
+    pub fn set_max_peers(&mut self, max_peers: Option<usize>) {
+        self.max_peers = max_peers;
+    }
+
+    pub fn max_peers(&self) -> Option<usize> {
+        self.max_peers
+    }
+
+    /// give new peers a session token, and keep peers whose connection drops Suspended this
+    /// long, so their client can reconnect as the same PeerHandle. None removes them right away.
+    /// Suspended peers still count towards max_peers.
+    pub fn set_session_grace(&mut self, grace: Option<Duration>) {
+        self.session_grace = grace;
+    }
+
+    pub fn session_grace(&self) -> Option<Duration> {
+        self.session_grace
+    }
+
+    // which peer laminar means by an address: anyone not at its handle is found through the
+    // aliases. None for a resumed peer's old connection, and for a new client at an address a
+    // resumed peer has left (eg. a NAT reusing the port), which needs a spare_handle().
+    fn handle_of(&self, addr: SocketAddr) -> Option<PeerHandle> {
+        if self.stale.contains(&addr) {
+            return None;
+        }
+        if let Some(handle) = self.aliases.get(&addr) {
+            return Some(*handle);
+        }
+        match self.peers.get(&addr) {
+            Some(peer) if peer.current_addr != addr => None,
+            _ => Some(addr),
+        }
+    }
+
+    // a handle for a new client whose address is already someone's handle. on the unspecified
+    // address, like the local player's, so no real peer can have it.
+    fn spare_handle(&mut self) -> Option<PeerHandle> {
+        for _ in 0..u16::MAX {
+            let handle = SocketAddr::from(([0, 0, 0, 0], self.next_spare_handle));
+            self.next_spare_handle = self.next_spare_handle.checked_add(1).unwrap_or(1);
+            if !self.peers.contains_key(&handle) {
+                return Some(handle);
+            }
+        }
+        None
+    }
+
+    // a client is back with its session token, maybe from a new address. None if the token
+    // doesn't get it anywhere, so the hello is treated as a new connection. No changes for
+    // repeats of the hello, otherwise the caller welcomes it.
+    fn resume(&mut self, token: SessionToken, addr: SocketAddr) -> Option<Vec<ConnectionStateChanged>> {
+        let handle = *self.sessions.get(&token)?;
+        let peer = self.peers.get_mut(&handle)?;
+        // repeats of the hello that resumed it
+        if peer.current_addr == addr && peer.state() == ConnectionState::Connected {
+            return Some(Vec::new());
+        }
+        if !matches!(peer.state(), ConnectionState::Connected | ConnectionState::Timeout | ConnectionState::Suspended) {
+            return None;
+        }
+        // if it's still connected at its old address as far as we know, that connection's gone
+        let connected = peer.state() != ConnectionState::Suspended;
+        let mut changes: Vec<_> = peer.transition(ConnectionState::Suspended, TransitionCause::Dropped).into_iter().collect();
+        changes.extend(peer.transition(ConnectionState::Connected, TransitionCause::Resumed));
+        let old_addr = std::mem::replace(&mut peer.current_addr, addr);
+        peer.suspended_until = None;
+        if old_addr != handle {
+            self.aliases.remove(&old_addr);
+        }
+        if old_addr != addr && connected {
+            self.stale.insert(old_addr);
+        }
+        self.stale.remove(&addr);
+        if addr != handle {
+            self.aliases.insert(addr, handle);
+        }
+        log::info!("{} resumed its session from {}", handle, addr);
+        Some(changes)
+    }
+
+    // its connection dropped. Suspended for the grace period if it has a session, otherwise
+    // None and it should be removed.
+    fn suspend(&mut self, handle: PeerHandle, cause: TransitionCause) -> Option<ConnectionStateChanged> {
+        let grace = self.session_grace?;
+        let peer = self.peers.get_mut(&handle)?;
+        peer.session_token?;
+        let change = peer.transition(ConnectionState::Suspended, cause)?;
+        peer.suspended_until = Some(Instant::now() + grace);
+        Some(change)
+    }
+
+    // remove a peer for good, along with its session
+    fn remove_peer(&mut self, handle: PeerHandle, cause: TransitionCause) -> Option<ConnectionStateChanged> {
+        let mut peer = self.peers.remove(&handle)?;
+        if let Some(token) = peer.session_token {
+            self.sessions.remove(&token);
+        }
+        if peer.current_addr != handle {
+            self.aliases.remove(&peer.current_addr);
+        }
+        peer.transition(ConnectionState::Disconnected, cause)
+    }
+
+    /// answer LAN discovery broadcasts, from the next listen() on. None stops answering.
+    pub fn set_discovery(&mut self, config: Option<DiscoveryConfig>) {
+        if config.is_none() {
+            self.discovery = None;
+        }
+        self.discovery_config = config;
+    }
+
+    pub fn discovery(&self) -> Option<&DiscoveryConfig> {
+        self.discovery_config.as_ref()
+    }
+
+    /// say goodbye to a peer. it goes to Disconnecting, and is Disconnected once laminar drops
+    /// the connection. false if there's no such peer, or it's already on its way out.
+    pub fn disconnect(&mut self, handle: PeerHandle) -> bool {
+        // there's no connection to say goodbye on, so it's just gone
+        if self.peers.get(&handle).map(Peer::state) == Some(ConnectionState::Suspended) {
+            if let Some(change) = self.remove_peer(handle, TransitionCause::LocalDisconnect) {
+                self.transitions.push(change);
+            }
+            return true;
+        }
+        let change = match self.peers.get_mut(&handle) {
+            Some(peer) => peer.transition(ConnectionState::Disconnecting, TransitionCause::LocalDisconnect),
+            None => None,
+        };
+        match change {
+            Some(change) => {
+                if handle == local_peer_handle() {
+                    // nothing to wait for, it's gone on the next frame
+                    self.local = None;
+                    self.local_events.push(LaminarSocketEvent::Disconnect(handle));
+                } else {
+                    let goodbye = LaminarPacket::unreliable(handle, vec![]);
+                    self.send_internal(MessageKind::Goodbye, goodbye).unwrap_or_default();
+                }
+                self.transitions.push(change);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Listen-server mode: add the local player as a peer, with local_peer_handle(). It connects
+    /// on the next frame, with the usual events, and from then on what it sends with
+    /// send_from_local() arrives like anyone else's messages, without touching a socket. What we
+    /// send it shows up as LocalMessageReceived events. Needs listen() first, and
+    /// disconnect(handle) removes it again.
+    pub fn connect_local(&mut self) -> PeerHandle {
+        let handle = local_peer_handle();
+        if self.local.is_some() {
+            return handle;
+        }
+        let (sender, receiver) = unbounded();
+        let mut peer = Peer::new(handle, sender.clone(), self.recorder.clone(), self.channels.clone(), self.batcher.clone());
+        if let Some(change) = peer.transition(ConnectionState::Connecting, TransitionCause::Handshake) {
+            self.transitions.push(change);
+        }
+        self.peers.insert(handle, peer);
+        self.local = Some(LocalPeer { sender, receiver });
+        self.local_events.push(LaminarSocketEvent::Connect(handle));
+        handle
+    }
+
+    /// the local player's handle, while there is one
+    pub fn local_peer(&self) -> Option<PeerHandle> {
+        self.local.as_ref().map(|_| local_peer_handle())
+    }
+
+    /// a message from the local player to us, the packet's address is ignored.
+    /// sends on a named channel's stream are logged and dropped
+    pub fn send_from_local(&mut self, packet: LaminarPacket) {
+        match self.channels.check_raw(&packet) {
+            Ok(()) => self.push_from_local(MessageKind::User, packet),
+            Err(err) => log::error!("Dropped a send from the local player: {:?}", err),
+        }
+    }
+
+    /// send_from_local() on a named channel
+    pub fn send_on_from_local(&mut self, channel: ChannelId, payload: Vec<u8>) -> Result<(), ChannelError> {
+        let packet = self.channels.packet(channel, local_peer_handle(), &payload)?;
+        self.push_from_local(MessageKind::Channel, packet);
+        Ok(())
+    }
+
+    fn push_from_local(&mut self, kind: MessageKind, packet: LaminarPacket) {
+        if self.local.is_none() {
+            return;
+        }
+        let packet = protocol::build(local_peer_handle(), packet.delivery_guarantee(), packet.order_guarantee(), packet.payload().to_vec());
+        self.local_events.push(LaminarSocketEvent::Packet(protocol::wrap(kind, packet)));
+    }
+
+    // to laminar, at the peer's current address, or to the local player if it's for them
+    fn route(&self, packet: LaminarPacket) -> Result<(), CrossbeamSendError<LaminarPacket>> {
+        if packet.addr() == local_peer_handle() {
+            return match &self.local {
+                Some(local) => local.sender.send(packet),
+                None => Ok(()),
+            };
+        }
+        match self.peers.get(&packet.addr()) {
+            Some(peer) if peer.state() == ConnectionState::Suspended => Ok(()),
+            Some(peer) => self.event_sender().send(protocol::readdress(packet, peer.current_addr)),
+            None => self.event_sender().send(packet),
+        }
+    }
 
     /// everything is going to crash with an assert unless this returns true
     pub fn initialized(&self) -> bool {
@@ -244,8 +867,103 @@ impl NetworkResource {
         self.manager_mut().manual_poll(Instant::now());
     }
 
-    pub fn send(&self, packet: LaminarPacket) -> Result<(), CrossbeamSendError<LaminarPacket>> {
-        self.event_sender().send(packet)
+    /// sends on a named channel's stream are refused with ChannelError::ReservedStream, use send_on
+    /// for those
+    pub fn send(&self, packet: LaminarPacket) -> Result<(), ChannelError> {
+        self.channels.check_raw(&packet)?;
+        self.send_internal(MessageKind::User, packet).map_err(|_| ChannelError::Disconnected)
+    }
+
+    /// send a payload to a peer on a named channel, with that channel's guarantees
+    pub fn send_on(&self, handle: PeerHandle, channel: ChannelId, payload: Vec<u8>) -> Result<(), ChannelError> {
+        let packet = self.channels.packet(channel, handle, &payload)?;
+        self.send_internal(MessageKind::Channel, packet).map_err(|_| ChannelError::Disconnected)
+    }
+
+    pub fn channels(&self) -> &ChannelRegistry {
+        &self.channels
+    }
+
+    /// the ServerNetworkingPlugin sets this from its `channels`
+    pub fn set_channels(&mut self, channels: ChannelRegistry) {
+        self.channels = Arc::new(channels);
+    }
+
+    /// coalesce small messages to the same peer into fewer datagrams when flushing.
+    /// None sends every message as soon as it's sent.
+    pub fn set_batching(&mut self, config: Option<BatchConfig>) {
+        self.batcher.lock().unwrap().set_config(config);
+    }
+
+    pub fn batching(&self) -> Option<BatchConfig> {
+        self.batcher.lock().unwrap().config().cloned()
+    }
+
+    pub(crate) fn send_internal(&self, kind: MessageKind, packet: LaminarPacket) -> Result<(), CrossbeamSendError<LaminarPacket>> {
+        if self.replaying() {
+            return Ok(());
+        }
+        // route() would drop it anyway, and it shouldn't be recorded as sent
+        if self.peers.get(&packet.addr()).map_or(false, |peer| peer.state() == ConnectionState::Suspended) {
+            return Ok(());
+        }
+        capture::record_outgoing(&self.recorder, kind, &packet);
+        let packet = self.batcher.lock().unwrap().push(kind, packet);
+        match packet {
+            Some(packet) => self.route(packet),
+            None => Ok(()),
+        }
+    }
+
+    /// send an rpc request to a peer. the returned handle will show up in an RpcResponse
+    /// event, with either the peer's reply or an RpcError. Err if the method name is too long.
+    pub fn request(&mut self, handle: PeerHandle, method: &str, payload: Vec<u8>, timeout: Duration) -> Result<RpcHandle, RpcError> {
+        rpc::check_method(method)?;
+        let rpc_handle = self.rpc.start(handle, timeout, Instant::now());
+        let packet = LaminarPacket::reliable_unordered(
+            handle,
+            rpc::encode_request(rpc_handle.id(), method, &payload),
+        );
+        self.send_internal(MessageKind::RpcRequest, packet).unwrap_or_default();
+        Ok(rpc_handle)
+    }
+
+    /// reply to an RpcRequest a peer sent us
+    pub fn respond(&self, handle: RpcHandle, payload: Vec<u8>) -> Result<(), CrossbeamSendError<LaminarPacket>> {
+        let packet = LaminarPacket::reliable_unordered(
+            handle.peer(),
+            rpc::encode_response(handle.id(), &payload),
+        );
+        self.send_internal(MessageKind::RpcResponse, packet)
+    }
+
+    /// start sending a blob to a peer in chunks. progress and the outcome arrive as
+    /// TransferProgress, TransferCompleted and TransferFailed events.
+    pub fn send_blob(&mut self, handle: PeerHandle, name: &str, data: Vec<u8>) -> Result<TransferHandle, TransferError> {
+        self.transfers.start(handle, name, data, Instant::now())
+    }
+
+    /// abort a transfer in either direction, both sides get a TransferFailed event
+    pub fn cancel_transfer(&mut self, handle: TransferHandle) {
+        self.transfers.cancel(handle);
+    }
+
+    /// chunk size, window, timeouts and size limit for blob transfers
+    pub fn transfer_config_mut(&mut self) -> &mut TransferConfig {
+        &mut self.transfers.config
+    }
+
+    /// send a state snapshot to a peer, delta encoded against the last one they acked if it's
+    /// recent enough, or in full otherwise. returns the snapshot's seq, None if no such peer.
+    pub fn send_snapshot(&mut self, handle: PeerHandle, data: &[u8]) -> Option<u32> {
+        let peer = self.peers.get_mut(&handle)?;
+        let (seq, payload) = peer.snapshots.encode(data, &self.snapshot_config);
+        self.send_internal(MessageKind::Snapshot, LaminarPacket::unreliable(handle, payload)).ok()?;
+        Some(seq)
+    }
+
+    pub fn snapshot_config_mut(&mut self) -> &mut SnapshotConfig {
+        &mut self.snapshot_config
     }
 
     pub fn event_sender(&self) -> &Sender<LaminarPacket> {
@@ -258,6 +976,17 @@ impl NetworkResource {
         self.manager().event_receiver()
     }
 
+    /// listen() with the laminar config from settings()
+    pub fn listen_with_settings(
+        &mut self,
+        socket_address: SocketAddr,
+        webrtc_listen_address: Option<SocketAddr>,
+        public_webrtc_address: Option<SocketAddr>,
+    ) {
+        let config = self.settings.laminar_config();
+        self.listen(config, socket_address, webrtc_listen_address, public_webrtc_address);
+    }
+
     /// The 3 listening addresses aren't strictly necessary, you can put the same IP address with
     /// a different port for the socket address; Unless you have some configuration issues with 
     /// public and private addresses that need to be connected to.
@@ -276,17 +1005,12 @@ impl NetworkResource {
                 listen_addr
             });
             let public_webrtc_address = public_webrtc_address.unwrap_or(webrtc_listen_address);
-            let socket = block_on(NaiaServerSocket::listen(
+            // no naia link conditioner, LaminarDatagramSocketForNaia does the conditioning
+            block_on(NaiaServerSocket::listen(
                 socket_address,
                 webrtc_listen_address,
                 public_webrtc_address,
-            ));
-
-            if let Some(ref conditioner) = self.link_conditioner {
-                socket.with_link_conditioner(conditioner)
-            } else {
-                socket
-            }
+            ))
         };
         
         // all packets from naia, regardless of src_addr, are sent to laminar, which is responsible
@@ -303,6 +1027,11 @@ impl NetworkResource {
                 bind_address: socket_address,
                 naia_packet_receiver: naia_packet_rx,
                 naia_payload_sender: naia_payload_tx,
+                conditioner: self.conditioner.clone(),
+                filter: self.filter.clone(),
+                challenges: self.challenges.clone(),
+                rate_limiter: self.rate_limiter.clone(),
+                info: self.info.clone(),
             },
             laminar_config
         ));
@@ -345,53 +1074,205 @@ impl NetworkResource {
             socket_address,
             tasks: vec![ receiver_task, sender_task ],
         });
+
+        if let Some(config) = &self.discovery_config {
+            match DiscoveryResponder::bind(config, socket_address.port()) {
+                Ok(responder) => self.discovery = Some(responder),
+                Err(err) => log::error!("Can't listen for discovery on port {}: {}", config.port, err),
+            }
+        }
+    }
+}
+
+// PreUpdate, before laminar_receiver: pick up changes to the NetworkSettings resource
+fn apply_network_settings(settings: Res<NetworkSettings>, mut net: ResMut<NetworkResource>) {
+    if settings.is_changed() && *settings != net.settings {
+        net.apply_settings(settings.clone());
     }
 }
 
-fn laminar_poller(
+// PreUpdate: keep the reply to server info queries up to date, or stop answering if there's no
+// ServerInfo
+fn server_info_updater(info: Option<Res<ServerInfo>>, net: Res<NetworkResource>) {
+    match info {
+        Some(info) => net.info.update(&info, info.is_changed(), net.peers.len(), net.max_peers),
+        None => net.info.clear(),
+    }
+}
+
+// PreUpdate: answer LAN discovery broadcasts
+fn discovery_responder(net: Res<NetworkResource>) {
+    if let Some(responder) = &net.discovery {
+        responder.respond(net.peers.len(), net.max_peers);
+    }
+}
+
+// PreUpdate, after laminar_receiver: publish what we sent the local player. RPC, snapshots,
+// transfers, lobbies and relaying all need a client on the other end, so anything they send it is
+// dropped, with an error the first time each kind turns up.
+fn local_peer_receiver(
+    net: Res<NetworkResource>,
+    mut messages: EventWriter<LocalMessageReceived>,
+    mut unsupported: Local<HashSet<MessageKind>>,
+) {
+    let local = match &net.local {
+        Some(local) => local,
+        None => return,
+    };
+    for packet in local.receiver.try_iter() {
+        for message in batch::unbatch(packet) {
+            match message {
+                (MessageKind::User, packet) => messages.send(LocalMessageReceived { packet, channel: None }),
+                (MessageKind::Channel, packet) => {
+                    if let Some(message) = net.channels.message(packet.addr(), packet.payload()) {
+                        let packet = protocol::repack(&packet, message.payload);
+                        messages.send(LocalMessageReceived { packet, channel: Some(message.channel) });
+                    }
+                },
+                // housekeeping for remote connections, the local player doesn't need it
+                (kind @ MessageKind::ClockPing, _)
+                | (kind @ MessageKind::ClockPong, _)
+                | (kind @ MessageKind::Goodbye, _)
+                | (kind @ MessageKind::Session, _)
+                | (kind @ MessageKind::Channels, _) => log::trace!("Dropped {:?} message to the local peer", kind),
+                (kind, _) => {
+                    if unsupported.insert(kind) {
+                        log::error!("{:?} messages aren't supported for the local peer, dropping them", kind);
+                    }
+                },
+            }
+        }
+    }
+}
+
+// PreUpdate: receive, and publish everything that arrived as bevy events
+fn laminar_receiver(
     mut net: ResMut<NetworkResource>,
-    mut peer_events: EventWriter<PeerEvent>,
+    mut peer_events: PeerEventWriters,
+    mut channel_messages: EventWriter<ChannelMessage>,
+    mut rpc_requests: EventWriter<RpcRequest>,
+    mut rpc_responses: EventWriter<RpcResponse>,
+    mut transfer_progress: EventWriter<TransferProgress>,
+    mut transfer_completed: EventWriter<TransferCompleted>,
+    mut transfer_failed: EventWriter<TransferFailed>,
+    mut rate_limit_events: EventWriter<RateLimitExceeded>,
 ){
+    let net = &mut *net;
+    net.tick += 1;
+    capture::next_frame(&net.recorder);
+
+    if let Some(replayer) = net.replayer.as_mut() {
+        for event in replayer.due_events() {
+            match event {
+                // recorded statuses already cover any state changes these made
+                CaptureEvent::Incoming(packet) => {
+                    dispatch(net, packet, &mut peer_events, &mut channel_messages, &mut rpc_requests, &mut rpc_responses);
+                },
+                CaptureEvent::Status(handle, state) => {
+                    net.apply_replayed(handle, state);
+                    capture::publish_status(&net.recorder, &mut peer_events, handle, state);
+                },
+                CaptureEvent::Outgoing(_) => {},
+            }
+        }
+        for response in net.rpc.expired(Instant::now()) {
+            rpc_responses.send(response);
+        }
+        transfer::publish_events(&mut net.transfers, &mut transfer_progress, &mut transfer_completed, &mut transfer_failed);
+        return;
+    }
+
     if !net.initialized() {
         return;
     }
 
+    for change in std::mem::take(&mut net.transitions) {
+        capture::publish_transition(&net.recorder, &mut peer_events, change);
+    }
+
     net.poll();
 
     let event_receiver = net.event_receiver().clone();
 
     // publish to bevy events - we won't expose the event_receiver
     // also adding in a bit of connection tracking.
-    while let Ok(event) = event_receiver.try_recv() {
+    // the local player's events go first, they were queued last frame
+    let local_events = std::mem::take(&mut net.local_events);
+    for event in local_events.into_iter().chain(std::iter::from_fn(|| event_receiver.try_recv().ok())) {
         match event {
             LaminarSocketEvent::Connect(addr) => {
-                if let Some(existing_peer) = net.peers.get_mut(&addr) {
-                    assert_eq!(existing_peer.state(), ConnectionState::Connecting);
-                    // log::warn!("Connect event for existing peer on {}, in state: {:?} (setting to connected)", addr, existing_peer.state());
-                    let new_state = ConnectionState::Connected;
-                    if existing_peer.set_state(new_state) {
-                        peer_events.send(PeerEvent::Status(addr, new_state));
+                if net.turned_away.contains(&addr) {
+                    continue;
+                }
+                let handle = match net.handle_of(addr) {
+                    Some(handle) => handle,
+                    None => continue,
+                };
+                if let Some(existing_peer) = net.peers.get_mut(&handle) {
+                    // suspended peers only come back with their session token, in a resume hello
+                    if existing_peer.state() == ConnectionState::Suspended {
+                        continue;
+                    }
+                    // a packet may already have moved it to Connected, which makes this a no-op
+                    if let Some(change) = existing_peer.transition(ConnectionState::Connected, TransitionCause::Established) {
+                        capture::publish_transition(&net.recorder, &mut peer_events, change);
                     }
                 } else {
                     log::warn!("Laminar connect event but no known peer {}", addr);
                 }
             },
             LaminarSocketEvent::Disconnect(addr) => {
-                if let Some(mut existing_peer) = net.peers.remove(&addr) {
-                    let state = ConnectionState::Disconnected;
-                    if existing_peer.set_state(state) {
-                        peer_events.send(PeerEvent::Status(addr, existing_peer.state()));
+                net.challenges.forget(addr);
+                net.rate_limiter.forget(addr);
+                if net.turned_away.remove(&addr) {
+                    continue;
+                }
+                // the old connection of a peer that's resumed elsewhere. the address is free for
+                // anyone new from here on
+                if net.stale.remove(&addr) {
+                    continue;
+                }
+                let handle = match net.handle_of(addr) {
+                    Some(handle) => handle,
+                    None => continue,
+                };
+                if let Some(existing_peer) = net.peers.get(&handle) {
+                    let cause = match existing_peer.state() {
+                        ConnectionState::Timeout => TransitionCause::Timeout,
+                        ConnectionState::Disconnecting => existing_peer.connection_state.cause().unwrap_or(TransitionCause::Dropped),
+                        _ => TransitionCause::Dropped,
+                    };
+                    let change = match existing_peer.state() {
+                        // already waiting for it to resume
+                        ConnectionState::Suspended => None,
+                        // a goodbye means it isn't coming back
+                        ConnectionState::Disconnecting => net.remove_peer(handle, cause),
+                        _ => net.suspend(handle, cause).or_else(|| net.remove_peer(handle, cause)),
+                    };
+                    if let Some(change) = change {
+                        capture::publish_transition(&net.recorder, &mut peer_events, change);
                     }
                 } else {
                     log::warn!("Got laminar disconnected event for unknown peer {}", addr);
                 }
+                for response in net.rpc.drop_peer(handle) {
+                    rpc_responses.send(response);
+                }
+                net.transfers.drop_peer(handle);
             },
             LaminarSocketEvent::Timeout(addr) => {
-                // laminar will send disconnect right after timeout, so no removal here
-                if let Some(existing_peer) = net.peers.get_mut(&addr) {
-                    let state = ConnectionState::Timeout;
-                    if existing_peer.set_state(state) {
-                        peer_events.send(PeerEvent::Status(addr, existing_peer.state()));
+                // laminar will send disconnect right after timeout, so no removal here.
+                // peers that are Disconnecting are expected to go quiet, so they stay Disconnecting.
+                if net.turned_away.contains(&addr) {
+                    continue;
+                }
+                let handle = match net.handle_of(addr) {
+                    Some(handle) => handle,
+                    None => continue,
+                };
+                if let Some(existing_peer) = net.peers.get_mut(&handle) {
+                    if let Some(change) = existing_peer.transition(ConnectionState::Timeout, TransitionCause::Timeout) {
+                        capture::publish_transition(&net.recorder, &mut peer_events, change);
                     }
                 } else {
                     log::warn!("Got laminar timeout event for unknown peer {}", addr);
@@ -399,6 +1280,35 @@ fn laminar_poller(
             },
             LaminarSocketEvent::Packet(packet) => {
                 // log::info!(">>packet, str: '{}'", String::from_utf8_lossy(packet.payload()));
+                if let Some(token) = session::parse_resume_hello(packet.payload()) {
+                    if let Some(changes) = net.resume(token, packet.addr()) {
+                        if !changes.is_empty() {
+                            // welcome it like a new peer, so laminar finishes setting up the new connection
+                            let welcome = LaminarPacket::reliable_unordered(packet.addr(), vec![]);
+                            net.event_sender().send(welcome).unwrap_or_default();
+                        }
+                        for change in changes {
+                            capture::publish_transition(&net.recorder, &mut peer_events, change);
+                        }
+                        continue;
+                    }
+                }
+                // packets from a resumed peer's new address are from its handle as far as anyone
+                // else is concerned
+                let from = packet.addr();
+                let handle = match net.handle_of(from) {
+                    Some(handle) => Some(handle),
+                    None if net.stale.contains(&from) => None,
+                    // someone new where a resumed peer used to be
+                    None => net.spare_handle(),
+                };
+                let packet = match handle {
+                    Some(handle) => protocol::readdress(packet, handle),
+                    None => {
+                        log::debug!("Dropping packet from {}, its peer has moved on", packet.addr());
+                        continue;
+                    },
+                };
                 // NB: peer_handle() is added by our trait, just returns the socket addr
                 if let Some(existing_peer) = net.peers.get_mut(&packet.peer_handle()) {
                     // if we are getting a packet from a peer still in Connecting state, we need to send them a packet
@@ -407,20 +1317,64 @@ fn laminar_poller(
                         // still sending empty welcome/handshake packets
                         continue;
                     }
-                    assert_eq!(existing_peer.state(), ConnectionState::Connected);
-                    peer_events.send(PeerEvent::Packet(packet));
+                    // suspended peers only come back with their session token
+                    if existing_peer.state() == ConnectionState::Suspended {
+                        log::debug!("Dropping packet from {} while Suspended", packet.addr());
+                        continue;
+                    }
+                    // a real message can beat laminar's connect event, in which case it's connected now
+                    if let Some(change) = existing_peer.transition(ConnectionState::Connected, TransitionCause::Established) {
+                        capture::publish_transition(&net.recorder, &mut peer_events, change);
+                    }
+                    if existing_peer.state() != ConnectionState::Connected {
+                        log::debug!("Dropping packet from {} while {:?}", packet.addr(), existing_peer.state());
+                        continue;
+                    }
+                    capture::record_incoming(&net.recorder, &packet);
+                    for change in dispatch(net, packet, &mut peer_events, &mut channel_messages, &mut rpc_requests, &mut rpc_responses) {
+                        capture::publish_transition(&net.recorder, &mut peer_events, change);
+                    }
                 } else {
                     // got a packet from an unknown peer, must be a new connection.
-                    let pc = net.new_peer(packet.addr());
-                    let initial_state = pc.state();
+                    if net.max_peers.map_or(false, |max| net.peers.len() >= max) {
+                        // once per connection. the client stops once it sees the goodbye, and
+                        // laminar drops the connection when it times out
+                        if net.turned_away.insert(from) {
+                            log::warn!("Turning away {}, server is full", from);
+                            let goodbye = LaminarPacket::reliable_unordered(from, protocol::rejection());
+                            net.event_sender().send(goodbye).unwrap_or_default();
+                        }
+                        continue;
+                    }
+                    let mut pc = net.new_peer(packet.addr());
+                    let change = pc.transition(ConnectionState::Connecting, TransitionCause::Handshake);
+                    let token = net.session_grace.map(|_| SessionToken::generate());
+                    pc.session_token = token;
+                    pc.current_addr = from;
+                    if from != packet.addr() {
+                        net.aliases.insert(from, packet.addr());
+                    }
                     net.peers.insert(packet.addr(), pc);
                     // send a welcome packet.
-                    let welcome_packet = LaminarPacket::reliable_unordered(packet.addr(), vec![]);
-                    log::info!("New peer detected! Welcoming {}", packet.addr());
-                    net.send(welcome_packet).unwrap_or_default();
-                    peer_events.send(PeerEvent::Status(packet.peer_handle(), initial_state));
-                    // welcome packets are 0 len
-                    assert_eq!(packet.payload().len(), 0);
+                    let welcome_packet = LaminarPacket::reliable_unordered(from, vec![]);
+                    log::info!("New peer detected! Welcoming {}", from);
+                    // sent raw, welcome packets have no header
+                    net.event_sender().send(welcome_packet).unwrap_or_default();
+                    let channels_packet = LaminarPacket::reliable_unordered(packet.addr(), net.channels.announcement());
+                    net.send_internal(MessageKind::Channels, channels_packet).unwrap_or_default();
+                    if let Some(token) = token {
+                        net.sessions.insert(token, packet.addr());
+                        let token_packet = LaminarPacket::reliable_unordered(packet.addr(), session::encode(SessionMessage::Token(token)));
+                        net.send_internal(MessageKind::Session, token_packet).unwrap_or_default();
+                    }
+                    if let Some(change) = change {
+                        capture::publish_transition(&net.recorder, &mut peer_events, change);
+                    }
+                    // hello packets are 0 len, anything else is from a connection we've forgotten
+                    // (eg. we restarted, or its session expired), and isn't worth aborting over
+                    if !packet.payload().is_empty() {
+                        log::debug!("First packet from {} wasn't a hello, dropped it", packet.addr());
+                    }
                     // dont publish welcome packets
                     // packet_events.send(packet);
                 }
@@ -428,4 +1382,289 @@ fn laminar_poller(
             },
         }
    }
+
+    // suspended peers that didn't come back in time
+    let now = Instant::now();
+    let expired: Vec<_> = net.peers.values()
+        .filter(|peer| peer.suspended_until.map_or(false, |until| now >= until))
+        .map(Peer::handle)
+        .collect();
+    for handle in expired {
+        log::info!("Session for {} expired", handle);
+        if let Some(change) = net.remove_peer(handle, TransitionCause::Timeout) {
+            capture::publish_transition(&net.recorder, &mut peer_events, change);
+        }
+    }
+
+    for mut exceeded in net.rate_limiter.take_exceeded(Instant::now()) {
+        // the limiter goes by where packets come from, which isn't a resumed peer's handle
+        let handle = net.handle_of(exceeded.handle);
+        exceeded.handle = handle.unwrap_or(exceeded.handle);
+        log::debug!("{} went over its rate limit, dropped {} packets", exceeded.handle, exceeded.dropped_packets);
+        if let (true, Some(handle)) = (exceeded.kicked, handle) {
+            log::info!("Kicking {} for flooding", handle);
+            net.disconnect(handle);
+        }
+        rate_limit_events.send(exceeded);
+    }
+
+    for response in net.rpc.expired(Instant::now()) {
+        rpc_responses.send(response);
+    }
+    transfer::publish_events(&mut net.transfers, &mut transfer_progress, &mut transfer_completed, &mut transfer_failed);
+}
+
+// hand a message (or a batch of them) from a connected peer to whatever it's for. replays come
+// through here too. returns the state changes it made, for the caller to publish.
+fn dispatch(
+    net: &mut NetworkResource,
+    packet: LaminarPacket,
+    peer_events: &mut PeerEventWriters,
+    channel_messages: &mut EventWriter<ChannelMessage>,
+    rpc_requests: &mut EventWriter<RpcRequest>,
+    rpc_responses: &mut EventWriter<RpcResponse>,
+) -> Vec<ConnectionStateChanged> {
+    let mut changes = Vec::new();
+    for message in batch::unbatch(packet) {
+        match message {
+            (MessageKind::User, packet) => {
+                peer_events.send(PeerEvent::Packet(packet));
+            },
+            (MessageKind::Channel, packet) => {
+                match net.channels.message(packet.addr(), packet.payload()) {
+                    Some(message) => channel_messages.send(message),
+                    None => log::warn!("Message on unknown channel from {}", packet.addr()),
+                }
+            },
+            (MessageKind::ClockPing, packet) => {
+                if let Some(pong) = clock::pong_payload(packet.payload(), net.server_time(), net.tick()) {
+                    let pong_packet = LaminarPacket::unreliable(packet.addr(), pong);
+                    net.send_internal(MessageKind::ClockPong, pong_packet).unwrap_or_default();
+                }
+            },
+            (MessageKind::RpcRequest, packet) => {
+                match rpc::decode_request(packet.addr(), packet.payload()) {
+                    Some(request) => rpc_requests.send(request),
+                    None => log::warn!("Malformed rpc request from {}", packet.addr()),
+                }
+            },
+            (MessageKind::RpcResponse, packet) => {
+                match rpc::decode_response(packet.addr(), packet.payload()) {
+                    Some((handle, payload)) => {
+                        // late responses for requests that already timed out are dropped
+                        if net.rpc.complete(handle) {
+                            rpc_responses.send(RpcResponse { handle, result: Ok(payload) });
+                        }
+                    },
+                    None => log::warn!("Malformed rpc response from {}", packet.addr()),
+                }
+            },
+            (MessageKind::SnapshotAck, packet) => {
+                if let Some(peer) = net.peers.get_mut(&packet.addr()) {
+                    peer.snapshots.handle_ack(packet.payload());
+                }
+            },
+            (MessageKind::Lobby, packet) => {
+                net.lobby_inbox.push((packet.addr(), packet.payload().to_vec()));
+            },
+            (MessageKind::Relay, packet) => {
+                net.relay_inbox.push(packet);
+            },
+            (MessageKind::Session, packet) => {
+                // resume hellos that didn't match a session, it's a new peer now
+                if session::decode(packet.payload()).is_none() {
+                    log::warn!("Malformed session message from {}", packet.addr());
+                }
+            },
+            (MessageKind::Goodbye, packet) => {
+                // it's stopped talking to us, laminar drops it once it times out
+                if let Some(peer) = net.peers.get_mut(&packet.addr()) {
+                    changes.extend(peer.transition(ConnectionState::Disconnecting, TransitionCause::RemoteDisconnect));
+                }
+            },
+            (kind, packet) if kind.is_transfer() => {
+                net.transfers.handle_message(kind, packet.addr(), packet.payload(), Instant::now());
+            },
+            (kind, packet) => {
+                log::warn!("Unexpected {:?} message from {}", kind, packet.addr());
+            },
+        }
+    }
+    changes
+}
+
+// PostUpdate: queue our own housekeeping traffic, then hand everything sent this frame to laminar.
+// laminar only has the one poll, so this also receives; those events wait for the next PreUpdate.
+fn laminar_flusher(mut net: ResMut<NetworkResource>) {
+    let net = &mut *net;
+    net.lobby_inbox.clear();
+    net.relay_inbox.clear();
+    if net.replaying() {
+        // Peer::send still batches while replaying
+        net.batcher.lock().unwrap().flush();
+        while net.replay_sink.1.try_recv().is_ok() {}
+        return;
+    }
+    if !net.initialized() {
+        return;
+    }
+
+    net.transfers.update(Instant::now());
+    let outbox: Vec<_> = net.transfers.outbox.drain(..).collect();
+    for (kind, packet) in outbox {
+        net.send_internal(kind, packet).unwrap_or_default();
+    }
+    let packets = net.batcher.lock().unwrap().flush();
+    for packet in packets {
+        net.route(packet).unwrap_or_default();
+    }
+
+    net.poll();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([192, 168, 1, 20], port).into()
+    }
+
+    // a peer with a session, in `state`
+    fn add_peer(net: &mut NetworkResource, handle: PeerHandle, state: ConnectionState) -> SessionToken {
+        let mut peer = Peer::new(handle, unbounded().0, net.recorder.clone(), net.channels.clone(), net.batcher.clone());
+        let token = SessionToken::generate();
+        peer.session_token = Some(token);
+        peer.connection_state.force(state);
+        net.peers.insert(handle, peer);
+        net.sessions.insert(token, handle);
+        token
+    }
+
+    fn steps(changes: &[ConnectionStateChanged]) -> Vec<(ConnectionState, ConnectionState, TransitionCause)> {
+        changes.iter().map(|change| (change.from, change.to, change.cause)).collect()
+    }
+
+    #[test]
+    fn unknown_tokens_dont_resume() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        add_peer(&mut net, addr(1000), ConnectionState::Connected);
+        assert!(net.resume(SessionToken::generate(), addr(2000)).is_none());
+        assert!(net.aliases.is_empty());
+    }
+
+    #[test]
+    fn resume_from_a_new_address() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        let token = add_peer(&mut net, addr(1000), ConnectionState::Connected);
+
+        let changes = net.resume(token, addr(2000)).unwrap();
+        assert_eq!(steps(&changes), vec![
+            (ConnectionState::Connected, ConnectionState::Suspended, TransitionCause::Dropped),
+            (ConnectionState::Suspended, ConnectionState::Connected, TransitionCause::Resumed),
+        ]);
+        let peer = net.peer(addr(1000)).unwrap();
+        assert_eq!(peer.addr(), addr(1000));
+        assert_eq!(peer.current_addr(), addr(2000));
+        assert_eq!(net.handle_of(addr(2000)), Some(addr(1000)));
+        // repeats of the hello change nothing
+        assert!(net.resume(token, addr(2000)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn old_address_is_stale_until_its_connection_drops() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        let token = add_peer(&mut net, addr(1000), ConnectionState::Connected);
+        net.resume(token, addr(2000)).unwrap();
+        assert_eq!(net.handle_of(addr(1000)), None);
+
+        // laminar drops the old connection, and a new client turns up there
+        assert!(net.stale.remove(&addr(1000)));
+        assert_eq!(net.handle_of(addr(1000)), None);
+        assert_eq!(net.handle_of(addr(2000)), Some(addr(1000)));
+    }
+
+    #[test]
+    fn new_clients_at_a_taken_handle_get_their_own() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        let first = add_peer(&mut net, addr(1000), ConnectionState::Connected);
+        let second = add_peer(&mut net, addr(1001), ConnectionState::Connected);
+        net.resume(first, addr(2000)).unwrap();
+        net.resume(second, addr(2001)).unwrap();
+        net.stale.clear();
+
+        // two new clients behind the same ip, where the resumed peers were
+        let mut spares = Vec::new();
+        for from in &[addr(1000), addr(1001)] {
+            let handle = net.spare_handle().unwrap();
+            assert!(net.handle_of(handle).is_some() && handle != local_peer_handle());
+            let mut peer = net.new_peer(handle);
+            peer.current_addr = *from;
+            net.peers.insert(handle, peer);
+            net.aliases.insert(*from, handle);
+            spares.push(handle);
+        }
+        assert_ne!(spares[0], spares[1]);
+        assert_eq!(net.handle_of(addr(1000)), Some(spares[0]));
+        assert_eq!(net.handle_of(addr(1001)), Some(spares[1]));
+        assert_eq!(net.handle_of(addr(2000)), Some(addr(1000)));
+        assert_eq!(net.handle_of(addr(2001)), Some(addr(1001)));
+
+        net.remove_peer(spares[0], TransitionCause::Dropped);
+        assert_eq!(net.handle_of(addr(1000)), None);
+        assert_eq!(net.handle_of(addr(1001)), Some(spares[1]));
+    }
+
+    #[test]
+    fn spare_handles_skip_taken_ones() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        add_peer(&mut net, SocketAddr::from(([0, 0, 0, 0], 1)), ConnectionState::Connected);
+        assert_eq!(net.spare_handle(), Some(SocketAddr::from(([0, 0, 0, 0], 2))));
+        net.next_spare_handle = u16::MAX;
+        assert_eq!(net.spare_handle(), Some(SocketAddr::from(([0, 0, 0, 0], u16::MAX))));
+        assert_eq!(net.spare_handle(), Some(SocketAddr::from(([0, 0, 0, 0], 2))));
+    }
+
+    #[test]
+    fn suspended_peers_leave_nothing_stale() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        let token = add_peer(&mut net, addr(1000), ConnectionState::Suspended);
+        net.peers.get_mut(&addr(1000)).unwrap().suspended_until = Some(Instant::now());
+
+        let changes = net.resume(token, addr(2000)).unwrap();
+        assert_eq!(steps(&changes), vec![(ConnectionState::Suspended, ConnectionState::Connected, TransitionCause::Resumed)]);
+        assert_eq!(net.peer(addr(1000)).unwrap().suspended_until, None);
+        assert!(net.stale.is_empty());
+        assert_eq!(net.handle_of(addr(1000)), None);
+    }
+
+    #[test]
+    fn resume_back_home() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        let token = add_peer(&mut net, addr(1000), ConnectionState::Connected);
+        net.resume(token, addr(2000)).unwrap();
+        net.resume(token, addr(1000)).unwrap();
+        assert_eq!(net.peer(addr(1000)).unwrap().current_addr(), addr(1000));
+        assert!(net.aliases.is_empty());
+        assert_eq!(net.handle_of(addr(1000)), Some(addr(1000)));
+        assert_eq!(net.handle_of(addr(2000)), None);
+    }
+
+    #[test]
+    fn leaving_peers_dont_resume() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        let token = add_peer(&mut net, addr(1000), ConnectionState::Disconnecting);
+        assert!(net.resume(token, addr(2000)).is_none());
+        assert_eq!(net.peer(addr(1000)).unwrap().current_addr(), addr(1000));
+    }
+
+    #[test]
+    fn removing_a_peer_ends_its_session() {
+        let mut net = NetworkResource::new(TaskPool::new(), None);
+        let token = add_peer(&mut net, addr(1000), ConnectionState::Connected);
+        net.resume(token, addr(2000)).unwrap();
+        net.remove_peer(addr(1000), TransitionCause::Dropped);
+        assert!(net.resume(token, addr(3000)).is_none());
+        assert!(net.aliases.is_empty());
+    }
 }
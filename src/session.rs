@@ -0,0 +1,142 @@
+use std::{convert::TryInto, fmt};
+
+use crate::protocol::MessageKind;
+
+// Session resume. With a grace period set, the server hands every new peer a random token, and
+// when a peer's connection drops it's kept Suspended for the grace period instead of removed.
+// A client that connects again says hello with its token instead of an empty packet, and the
+// server carries on with the same Peer and PeerHandle, even if it's now at a new address.
+//
+//  Token:  [0][token: 16 bytes]   server -> client, as soon as it's a peer
+//  Resume: [1][token: 16 bytes]   client -> server, as its laminar hello
+//
+// Both go in MessageKind::Session messages. A resume with a token the server doesn't know (it
+// expired, or the server restarted) is treated like any other hello, so the client just gets a
+// new session instead.
+
+const TOKEN: u8 = 0;
+const RESUME: u8 = 1;
+
+const TOKEN_LEN: usize = 16;
+
+/// Proof that a client owns a session on the server, see NetworkResource::session_token
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub struct SessionToken([u8; TOKEN_LEN]);
+
+impl SessionToken {
+    pub fn from_bytes(bytes: [u8; TOKEN_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; TOKEN_LEN] {
+        &self.0
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn generate() -> Self {
+        let mut bytes = [0; TOKEN_LEN];
+        getrandom::getrandom(&mut bytes).expect("no randomness for session tokens");
+        Self(bytes)
+    }
+}
+
+// it's a secret, so keep it out of logs
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionToken(..)")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum SessionMessage {
+    Token(SessionToken),
+    Resume(SessionToken),
+}
+
+pub(crate) fn encode(message: SessionMessage) -> Vec<u8> {
+    let (kind, token) = match message {
+        SessionMessage::Token(token) => (TOKEN, token),
+        SessionMessage::Resume(token) => (RESUME, token),
+    };
+    let mut buf = Vec::with_capacity(1 + TOKEN_LEN);
+    buf.push(kind);
+    buf.extend_from_slice(&token.0);
+    buf
+}
+
+pub(crate) fn decode(payload: &[u8]) -> Option<SessionMessage> {
+    let (&kind, body) = payload.split_first()?;
+    let token = SessionToken(body.try_into().ok()?);
+    match kind {
+        TOKEN => Some(SessionMessage::Token(token)),
+        RESUME => Some(SessionMessage::Resume(token)),
+        _ => None,
+    }
+}
+
+/// the laminar hello for resuming a session, header included since hellos skip the batcher
+pub(crate) fn resume_hello(token: SessionToken) -> Vec<u8> {
+    let mut buf = vec![MessageKind::Session as u8];
+    buf.extend_from_slice(&encode(SessionMessage::Resume(token)));
+    buf
+}
+
+/// the token, if this laminar payload is a resume hello
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn parse_resume_hello(payload: &[u8]) -> Option<SessionToken> {
+    let (&kind, body) = payload.split_first()?;
+    if kind != MessageKind::Session as u8 {
+        return None;
+    }
+    match decode(body)? {
+        SessionMessage::Resume(token) => Some(token),
+        SessionMessage::Token(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token() -> SessionToken {
+        SessionToken::from_bytes([7; TOKEN_LEN])
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        for message in [SessionMessage::Token(token()), SessionMessage::Resume(token())].iter() {
+            assert_eq!(decode(&encode(*message)), Some(*message));
+        }
+        let generated = SessionToken::generate();
+        assert_eq!(decode(&encode(SessionMessage::Token(generated))), Some(SessionMessage::Token(generated)));
+    }
+
+    #[test]
+    fn bad_messages_are_rejected() {
+        let encoded = encode(SessionMessage::Token(token()));
+        assert_eq!(decode(&encoded[..encoded.len() - 1]), None);
+        let mut long = encoded.clone();
+        long.push(0);
+        assert_eq!(decode(&long), None);
+        assert_eq!(decode(&[]), None);
+        let mut unknown = encoded;
+        unknown[0] = 9;
+        assert_eq!(decode(&unknown), None);
+    }
+
+    #[test]
+    fn resume_hello_roundtrip() {
+        assert_eq!(parse_resume_hello(&resume_hello(token())), Some(token()));
+        // a token message isn't a hello, nor is anything without the session header
+        let mut token_message = vec![MessageKind::Session as u8];
+        token_message.extend_from_slice(&encode(SessionMessage::Token(token())));
+        assert_eq!(parse_resume_hello(&token_message), None);
+        assert_eq!(parse_resume_hello(&resume_hello(token())[1..]), None);
+        assert_eq!(parse_resume_hello(&[]), None);
+    }
+
+    #[test]
+    fn debug_hides_the_token() {
+        assert_eq!(format!("{:?}", token()), "SessionToken(..)");
+    }
+}
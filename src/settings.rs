@@ -0,0 +1,265 @@
+use std::{fs, io, path::Path, time::Duration};
+
+use laminar::Config as LaminarConfig;
+use naia_client_socket::LinkConditionerConfig;
+use serde::{Deserialize, Serialize};
+
+// One serializable place for the laminar and link conditioner config, as a bevy resource.
+// Insert it before adding a networking plugin to have the plugin start from it, otherwise the
+// plugin inserts one from its own fields. Changing the resource later is picked up by the next
+// frame: the conditioner straight away, the laminar config by the next connect/listen.
+//
+//  (
+//      laminar: (idle_connection_timeout_ms: 3000, heartbeat_interval_ms: Some(1000)),
+//      link_conditioner: Some((incoming_latency: 100, incoming_jitter: 20, incoming_loss: 0.02)),
+//  )
+//
+// Anything left out keeps its default.
+
+/// Bevy resource (client and server), read by the networking plugins
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSettings {
+    pub laminar: LaminarSettings,
+    /// incoming conditioning like naia's, None for a clean link
+    pub link_conditioner: Option<ConditionerSettings>,
+}
+
+/// laminar::Config, with durations in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LaminarSettings {
+    pub idle_connection_timeout_ms: u64,
+    pub heartbeat_interval_ms: Option<u64>,
+    pub max_packet_size: usize,
+    pub max_fragments: u8,
+    pub fragment_size: u16,
+    pub fragment_reassembly_buffer_size: u16,
+    pub receive_buffer_max_size: usize,
+    pub rtt_smoothing_factor: f32,
+    pub rtt_max_value: u16,
+    pub max_packets_in_flight: u16,
+}
+
+/// naia's LinkConditionerConfig: latency and jitter in milliseconds, loss and corruption 0.0 - 1.0
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConditionerSettings {
+    pub incoming_latency: u32,
+    pub incoming_jitter: u32,
+    pub incoming_loss: f32,
+    pub incoming_corruption: f32,
+}
+
+impl NetworkSettings {
+    pub fn from_ron(text: &str) -> Result<Self, ron::Error> {
+        ron::de::from_str(text)
+    }
+
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::from_ron(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let text = self.to_ron().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(path, text)
+    }
+
+    pub fn laminar_config(&self) -> LaminarConfig {
+        (&self.laminar).into()
+    }
+
+    pub fn link_conditioner_config(&self) -> Option<LinkConditionerConfig> {
+        self.link_conditioner.map(Into::into)
+    }
+}
+
+impl Default for LaminarSettings {
+    fn default() -> Self {
+        (&LaminarConfig::default()).into()
+    }
+}
+
+impl From<&LaminarConfig> for LaminarSettings {
+    fn from(config: &LaminarConfig) -> Self {
+        Self {
+            idle_connection_timeout_ms: config.idle_connection_timeout.as_millis() as u64,
+            heartbeat_interval_ms: config.heartbeat_interval.map(|interval| interval.as_millis() as u64),
+            max_packet_size: config.max_packet_size,
+            max_fragments: config.max_fragments,
+            fragment_size: config.fragment_size,
+            fragment_reassembly_buffer_size: config.fragment_reassembly_buffer_size,
+            receive_buffer_max_size: config.receive_buffer_max_size,
+            rtt_smoothing_factor: config.rtt_smoothing_factor,
+            rtt_max_value: config.rtt_max_value,
+            max_packets_in_flight: config.max_packets_in_flight,
+        }
+    }
+}
+
+impl From<&LaminarSettings> for LaminarConfig {
+    fn from(settings: &LaminarSettings) -> Self {
+        Self {
+            idle_connection_timeout: Duration::from_millis(settings.idle_connection_timeout_ms),
+            heartbeat_interval: settings.heartbeat_interval_ms.map(Duration::from_millis),
+            max_packet_size: settings.max_packet_size,
+            max_fragments: settings.max_fragments,
+            fragment_size: settings.fragment_size,
+            fragment_reassembly_buffer_size: settings.fragment_reassembly_buffer_size,
+            receive_buffer_max_size: settings.receive_buffer_max_size,
+            rtt_smoothing_factor: settings.rtt_smoothing_factor,
+            rtt_max_value: settings.rtt_max_value,
+            max_packets_in_flight: settings.max_packets_in_flight,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&LinkConditionerConfig> for ConditionerSettings {
+    fn from(config: &LinkConditionerConfig) -> Self {
+        Self {
+            incoming_latency: config.incoming_latency,
+            incoming_jitter: config.incoming_jitter,
+            incoming_loss: config.incoming_loss,
+            incoming_corruption: config.incoming_corruption,
+        }
+    }
+}
+
+impl From<ConditionerSettings> for LinkConditionerConfig {
+    fn from(settings: ConditionerSettings) -> Self {
+        Self {
+            incoming_latency: settings.incoming_latency,
+            incoming_jitter: settings.incoming_jitter,
+            incoming_loss: settings.incoming_loss,
+            incoming_corruption: settings.incoming_corruption,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn laminar_config() -> LaminarConfig {
+        LaminarConfig {
+            idle_connection_timeout: Duration::from_millis(4500),
+            heartbeat_interval: Some(Duration::from_millis(250)),
+            max_packet_size: 64 * 1024,
+            max_fragments: 32,
+            fragment_size: 1024,
+            fragment_reassembly_buffer_size: 128,
+            receive_buffer_max_size: 2048,
+            rtt_smoothing_factor: 0.25,
+            rtt_max_value: 400,
+            max_packets_in_flight: 256,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn omitted_fields_keep_their_defaults() {
+        let settings = NetworkSettings::from_ron("(laminar: (idle_connection_timeout_ms: 3000))").unwrap();
+        assert_eq!(settings.laminar, LaminarSettings { idle_connection_timeout_ms: 3000, ..Default::default() });
+        assert_eq!(settings.link_conditioner, None);
+
+        let settings = NetworkSettings::from_ron("(link_conditioner: Some((incoming_latency: 100)))").unwrap();
+        assert_eq!(settings.laminar, LaminarSettings::default());
+        assert_eq!(settings.link_conditioner, Some(ConditionerSettings { incoming_latency: 100, ..Default::default() }));
+
+        assert_eq!(NetworkSettings::from_ron("()").unwrap(), NetworkSettings::default());
+        assert!(NetworkSettings::from_ron("(laminar: (idle_connection_timeout_ms: \"soon\"))").is_err());
+    }
+
+    #[test]
+    fn defaults_match_laminar() {
+        let config = NetworkSettings::default().laminar_config();
+        let default = LaminarConfig::default();
+        assert_eq!(config.idle_connection_timeout, default.idle_connection_timeout);
+        assert_eq!(config.heartbeat_interval, default.heartbeat_interval);
+        assert_eq!(config.max_packets_in_flight, default.max_packets_in_flight);
+    }
+
+    #[test]
+    fn ron_roundtrip() {
+        let settings = NetworkSettings {
+            laminar: (&laminar_config()).into(),
+            link_conditioner: Some(ConditionerSettings {
+                incoming_latency: 120,
+                incoming_jitter: 15,
+                incoming_loss: 0.05,
+                incoming_corruption: 0.001,
+            }),
+        };
+        let text = settings.to_ron().unwrap();
+        assert_eq!(NetworkSettings::from_ron(&text).unwrap(), settings);
+
+        let no_heartbeat = NetworkSettings {
+            laminar: LaminarSettings { heartbeat_interval_ms: None, ..Default::default() },
+            link_conditioner: None,
+        };
+        assert_eq!(NetworkSettings::from_ron(&no_heartbeat.to_ron().unwrap()).unwrap(), no_heartbeat);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("network-settings-{}.ron", std::process::id()));
+        let settings = NetworkSettings {
+            laminar: LaminarSettings { heartbeat_interval_ms: Some(500), ..Default::default() },
+            link_conditioner: None,
+        };
+        settings.save(&path).unwrap();
+        let loaded = NetworkSettings::load(&path);
+        fs::write(&path, "(laminar: ").unwrap();
+        let broken = NetworkSettings::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), settings);
+        assert_eq!(broken.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn laminar_config_roundtrip() {
+        let config = laminar_config();
+        let settings = LaminarSettings::from(&config);
+        assert_eq!(settings.idle_connection_timeout_ms, 4500);
+        assert_eq!(settings.heartbeat_interval_ms, Some(250));
+
+        let back = LaminarConfig::from(&settings);
+        assert_eq!(back.idle_connection_timeout, config.idle_connection_timeout);
+        assert_eq!(back.heartbeat_interval, config.heartbeat_interval);
+        assert_eq!(back.max_packet_size, config.max_packet_size);
+        assert_eq!(back.max_fragments, config.max_fragments);
+        assert_eq!(back.fragment_size, config.fragment_size);
+        assert_eq!(back.fragment_reassembly_buffer_size, config.fragment_reassembly_buffer_size);
+        assert_eq!(back.receive_buffer_max_size, config.receive_buffer_max_size);
+        assert_eq!(back.rtt_smoothing_factor, config.rtt_smoothing_factor);
+        assert_eq!(back.rtt_max_value, config.rtt_max_value);
+        assert_eq!(back.max_packets_in_flight, config.max_packets_in_flight);
+        assert_eq!(LaminarSettings::from(&back), settings);
+
+        let config = LaminarConfig { heartbeat_interval: None, ..laminar_config() };
+        assert_eq!(LaminarConfig::from(&LaminarSettings::from(&config)).heartbeat_interval, None);
+    }
+
+    #[test]
+    fn conditioner_roundtrip() {
+        let settings = ConditionerSettings {
+            incoming_latency: 80,
+            incoming_jitter: 10,
+            incoming_loss: 0.1,
+            incoming_corruption: 0.01,
+        };
+        let config = NetworkSettings { link_conditioner: Some(settings), ..Default::default() }
+            .link_conditioner_config()
+            .unwrap();
+        assert_eq!(config.incoming_latency, 80);
+        assert_eq!(config.incoming_jitter, 10);
+        assert_eq!(ConditionerSettings::from(&config), settings);
+        assert!(NetworkSettings::default().link_conditioner_config().is_none());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{collections::VecDeque, convert::TryInto};
+
+use bevy::log;
+
+use crate::protocol::{read_varint, write_varint};
+
+// Server -> client state snapshots, delta encoded against the newest snapshot the client acked.
+// Snapshots are opaque bytes; the delta just records which byte ranges changed, so it works best
+// when the user's encoding keeps a stable layout between frames.
+//
+//  Snapshot:    [seq: u32][has_baseline: u8][baseline_seq: u32, if has_baseline][len: u32][body]
+//  SnapshotAck: [seq: u32]
+//
+// Without a baseline the body is the full snapshot, otherwise the body is a list of
+//  [skip: varint][len: varint][len bytes]
+// runs, applied on top of the baseline (truncated or zero padded to `len`).
+//
+// seq wraps, so it's compared with is_newer rather than `>`.
+
+// equal bytes shorter than this inside a changed region are sent rather than starting a new run
+const MIN_GAP: usize = 4;
+
+// how many received snapshots the client keeps around as possible baselines.
+// SnapshotConfig::max_baseline_age must stay below this.
+const CLIENT_HISTORY: usize = 64;
+
+// sanity limit on the length a snapshot claims to have, before we allocate for it
+const MAX_SNAPSHOT_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    /// send a full snapshot if the client's last ack is more than this many snapshots old.
+    /// clients only remember the last 64 snapshots, so keep it below that.
+    pub max_baseline_age: u32,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            max_baseline_age: 32,
+        }
+    }
+}
+
+/// Bevy event (client), a snapshot from the server, already reassembled from its delta.
+/// Only sent for snapshots newer than any we've seen, late arrivals are dropped.
+#[derive(Debug, Clone)]
+pub struct SnapshotReceived {
+    pub seq: u32,
+    pub data: Vec<u8>,
+}
+
+/// per-peer server side state: what we've sent recently and what the client has acked
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotEncoder {
+    next_seq: u32,
+    history: VecDeque<(u32, Vec<u8>)>,
+    acked: Option<u32>,
+}
+
+impl SnapshotEncoder {
+    /// returns the seq and the payload to send
+    pub(crate) fn encode(&mut self, data: &[u8], config: &SnapshotConfig) -> (u32, Vec<u8>) {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        let baseline = self
+            .acked
+            .filter(|acked| seq.wrapping_sub(*acked) <= config.max_baseline_age)
+            .and_then(|acked| self.history.iter().find(|(s, _)| *s == acked));
+
+        let mut payload = Vec::with_capacity(13 + data.len());
+        payload.extend_from_slice(&seq.to_le_bytes());
+        match baseline {
+            Some((baseline_seq, baseline)) => {
+                payload.push(1);
+                payload.extend_from_slice(&baseline_seq.to_le_bytes());
+                payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                encode_delta(baseline, data, &mut payload);
+            }
+            None => {
+                payload.push(0);
+                payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                payload.extend_from_slice(data);
+            }
+        }
+
+        self.history.push_back((seq, data.to_vec()));
+        while self.history.len() > config.max_baseline_age as usize + 1 {
+            self.history.pop_front();
+        }
+        (seq, payload)
+    }
+
+    pub(crate) fn handle_ack(&mut self, bytes: &[u8]) {
+        let seq = match bytes.get(0..4) {
+            Some(seq) => u32::from_le_bytes(seq.try_into().unwrap()),
+            None => return,
+        };
+        if self.acked.map_or(true, |acked| is_newer(seq, acked)) && self.history.iter().any(|(s, _)| *s == seq) {
+            self.acked = Some(seq);
+        }
+    }
+}
+
+/// client side: recent snapshots to decode deltas against
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotDecoder {
+    history: VecDeque<(u32, Vec<u8>)>,
+    latest: Option<u32>,
+}
+
+impl SnapshotDecoder {
+    pub(crate) fn reset(&mut self) {
+        self.history.clear();
+        self.latest = None;
+    }
+
+    /// returns the ack payload to send back, and the snapshot if it's the newest one yet
+    pub(crate) fn decode(&mut self, bytes: &[u8]) -> Option<(Vec<u8>, Option<SnapshotReceived>)> {
+        let seq = read_u32(bytes, 0)?;
+        let (baseline_seq, offset) = match *bytes.get(4)? {
+            0 => (None, 5),
+            1 => (Some(read_u32(bytes, 5)?), 9),
+            _ => return None,
+        };
+        let len = read_u32(bytes, offset)? as usize;
+        let body = &bytes[offset + 4..];
+        if len > MAX_SNAPSHOT_LEN {
+            return None;
+        }
+
+        let data = if let Some(baseline_seq) = baseline_seq {
+            let baseline = match self.history.iter().find(|(s, _)| *s == baseline_seq) {
+                Some((_, baseline)) => baseline,
+                None => {
+                    log::debug!("Dropping snapshot {}, baseline {} is gone", seq, baseline_seq);
+                    return None;
+                }
+            };
+            apply_delta(baseline, body, len)?
+        } else {
+            body.to_vec()
+        };
+        if data.len() != len {
+            return None;
+        }
+
+        if !self.history.iter().any(|(s, _)| *s == seq) {
+            self.history.push_back((seq, data.clone()));
+            if self.history.len() > CLIENT_HISTORY {
+                self.history.pop_front();
+            }
+        }
+
+        let ack = seq.to_le_bytes().to_vec();
+        if self.latest.map_or(true, |latest| is_newer(seq, latest)) {
+            self.latest = Some(seq);
+            Some((ack, Some(SnapshotReceived { seq, data })))
+        } else {
+            Some((ack, None))
+        }
+    }
+}
+
+fn encode_delta(baseline: &[u8], data: &[u8], out: &mut Vec<u8>) {
+    let base = |i: usize| baseline.get(i).copied().unwrap_or(0);
+    let mut written = 0;
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == base(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut end = i + 1;
+        let mut j = i + 1;
+        while j < data.len() {
+            if data[j] != base(j) {
+                end = j + 1;
+            } else if j - end >= MIN_GAP {
+                break;
+            }
+            j += 1;
+        }
+        write_varint(out, start - written);
+        write_varint(out, end - start);
+        out.extend_from_slice(&data[start..end]);
+        written = end;
+        i = end;
+    }
+}
+
+fn apply_delta(baseline: &[u8], mut body: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut data = baseline.to_vec();
+    data.resize(len, 0);
+    let mut pos = 0;
+    while !body.is_empty() {
+        let skip = read_varint(&mut body)?;
+        let run = read_varint(&mut body)?;
+        pos = pos.checked_add(skip)?;
+        let end = pos.checked_add(run)?;
+        let bytes = body.get(..run)?;
+        data.get_mut(pos..end)?.copy_from_slice(bytes);
+        body = &body[run..];
+        pos = end;
+    }
+    Some(data)
+}
+
+// true if `a` comes after `b`, allowing for wrapping
+fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(baseline: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        encode_delta(baseline, data, &mut body);
+        apply_delta(baseline, &body, data.len()).unwrap()
+    }
+
+    #[test]
+    fn delta_roundtrip() {
+        let baseline: Vec<u8> = (0..100).collect();
+        let mut changed = baseline.clone();
+        changed[3] = 200;
+        changed[5] = 201;
+        changed[60] = 202;
+        assert_eq!(roundtrip(&baseline, &changed), changed);
+        assert_eq!(roundtrip(&baseline, &baseline), baseline);
+        assert_eq!(roundtrip(&baseline, &baseline[..40]), &baseline[..40]);
+        let mut longer = baseline.clone();
+        longer.extend_from_slice(&[0, 0, 7, 0]);
+        assert_eq!(roundtrip(&baseline, &longer), longer);
+        assert_eq!(roundtrip(&[], &changed), changed);
+    }
+
+    #[test]
+    fn unchanged_snapshot_has_empty_delta() {
+        let mut body = Vec::new();
+        encode_delta(&[1, 2, 3], &[1, 2, 3], &mut body);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn bad_delta_is_rejected() {
+        let mut body = Vec::new();
+        write_varint(&mut body, 8);
+        write_varint(&mut body, 4);
+        body.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(apply_delta(&[0; 10], &body, 10), None);
+        assert_eq!(apply_delta(&[0; 10], &body[..body.len() - 1], 12), None);
+    }
+
+    #[test]
+    fn encoder_decoder_roundtrip() {
+        let config = SnapshotConfig::default();
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+
+        let first = vec![1u8; 64];
+        let (seq, payload) = encoder.encode(&first, &config);
+        assert_eq!(payload[4], 0, "nothing acked yet, so no baseline");
+        let (ack, received) = decoder.decode(&payload).unwrap();
+        assert_eq!(received.unwrap().data, first);
+        assert_eq!(ack, seq.to_le_bytes());
+        encoder.handle_ack(&ack);
+
+        let mut second = first.clone();
+        second[10] = 9;
+        second.push(4);
+        let (_, payload) = encoder.encode(&second, &config);
+        assert_eq!(payload[4], 1);
+        assert!(payload.len() < second.len());
+        let (_, received) = decoder.decode(&payload).unwrap();
+        assert_eq!(received.unwrap().data, second);
+    }
+
+    #[test]
+    fn late_snapshots_are_acked_but_not_delivered() {
+        let config = SnapshotConfig::default();
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+        let (_, older) = encoder.encode(&[1, 2, 3], &config);
+        let (_, newer) = encoder.encode(&[4, 5, 6], &config);
+        assert!(decoder.decode(&newer).unwrap().1.is_some());
+        let (ack, received) = decoder.decode(&older).unwrap();
+        assert_eq!(ack, 0u32.to_le_bytes());
+        assert!(received.is_none());
+    }
+
+    #[test]
+    fn missing_baseline_is_dropped() {
+        let config = SnapshotConfig::default();
+        let mut encoder = SnapshotEncoder::default();
+        let (_, first) = encoder.encode(&[1, 2, 3], &config);
+        encoder.handle_ack(&first[..4]);
+        let (_, delta) = encoder.encode(&[1, 2, 4], &config);
+        assert!(SnapshotDecoder::default().decode(&delta).is_none());
+    }
+
+    #[test]
+    fn seq_wraps() {
+        let config = SnapshotConfig::default();
+        let mut encoder = SnapshotEncoder { next_seq: u32::MAX, ..Default::default() };
+        let mut decoder = SnapshotDecoder::default();
+        let (seq, payload) = encoder.encode(&[1, 2, 3], &config);
+        assert_eq!(seq, u32::MAX);
+        let (ack, received) = decoder.decode(&payload).unwrap();
+        assert_eq!(received.unwrap().data, [1, 2, 3]);
+        encoder.handle_ack(&ack);
+        assert_eq!(encoder.acked, Some(u32::MAX));
+
+        let (seq, payload) = encoder.encode(&[1, 2, 3, 4], &config);
+        assert_eq!(seq, 0);
+        assert_eq!(payload[4], 1, "u32::MAX is a real baseline");
+        let (ack, received) = decoder.decode(&payload).unwrap();
+        assert_eq!(received.unwrap().data, [1, 2, 3, 4]);
+        encoder.handle_ack(&ack);
+        assert_eq!(encoder.acked, Some(0));
+    }
+
+    #[test]
+    fn unknown_acks_are_ignored() {
+        let config = SnapshotConfig::default();
+        let mut encoder = SnapshotEncoder::default();
+        encoder.encode(&[1], &config);
+        encoder.handle_ack(&5u32.to_le_bytes());
+        encoder.handle_ack(&[0, 0]);
+        assert_eq!(encoder.acked, None);
+    }
+}
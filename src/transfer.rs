@@ -0,0 +1,850 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    time::Duration,
+};
+
+use bevy::{app::EventWriter, log};
+use instant::Instant;
+use laminar::Packet as LaminarPacket;
+
+use crate::protocol::MessageKind;
+use crate::PeerHandle;
+
+// Chunked transfer of large blobs, bypassing laminar's fragmentation limits.
+// The offer and any abort go reliable, chunks and acks go unreliable; the sender keeps a window
+// of unacked chunks in flight and resends any that aren't acked in time.
+//
+//  TransferOffer: [id: u32][total_len: u64][chunk_size: u32][crc32: u32][name_len: u8][name]
+//  TransferChunk: [id: u32][index: u32][bytes]
+//  TransferAck:   [id: u32][index: u32]*
+//  TransferAbort: [id: u32][from_sender: u8][reason: u8]
+//
+// ids are allocated by the sender, so the same id can exist in each direction.
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum TransferDirection {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct TransferHandle {
+    peer: PeerHandle,
+    id: u32,
+    direction: TransferDirection,
+}
+
+impl TransferHandle {
+    pub fn peer(&self) -> PeerHandle {
+        self.peer
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn direction(&self) -> TransferDirection {
+        self.direction
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TransferError {
+    /// no progress from the other side within TransferConfig::timeout
+    Timeout,
+    /// the connection went away
+    Disconnected,
+    /// the reassembled blob didn't match the sender's checksum
+    IntegrityCheckFailed,
+    /// bigger than the receiver's TransferConfig::max_incoming_size
+    TooLarge,
+    /// cancelled by either side
+    Cancelled,
+    /// send_blob was given a name over 255 bytes, nothing was sent
+    NameTooLong,
+    /// the receiver already has as many incoming transfers (or bytes) from us as it allows
+    TooManyTransfers,
+    /// TransferConfig::chunk_size is 0 or doesn't fit in a u32, nothing was sent
+    InvalidChunkSize,
+}
+
+impl TransferError {
+    fn to_u8(self) -> u8 {
+        match self {
+            TransferError::Timeout => 0,
+            TransferError::Disconnected => 1,
+            TransferError::IntegrityCheckFailed => 2,
+            TransferError::TooLarge => 3,
+            TransferError::Cancelled => 4,
+            TransferError::NameTooLong => 5,
+            TransferError::TooManyTransfers => 6,
+            TransferError::InvalidChunkSize => 7,
+        }
+    }
+
+    fn from_u8(byte: u8) -> Self {
+        match byte {
+            0 => TransferError::Timeout,
+            1 => TransferError::Disconnected,
+            2 => TransferError::IntegrityCheckFailed,
+            3 => TransferError::TooLarge,
+            5 => TransferError::NameTooLong,
+            6 => TransferError::TooManyTransfers,
+            7 => TransferError::InvalidChunkSize,
+            _ => TransferError::Cancelled,
+        }
+    }
+}
+
+/// Bevy event, sent at most once per frame per transfer while bytes are moving
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    pub handle: TransferHandle,
+    pub name: String,
+    pub bytes_done: usize,
+    pub bytes_total: usize,
+}
+
+/// Bevy event. `data` is the received blob for incoming transfers, None for outgoing ones.
+#[derive(Debug, Clone)]
+pub struct TransferCompleted {
+    pub handle: TransferHandle,
+    pub name: String,
+    pub data: Option<Vec<u8>>,
+}
+
+/// Bevy event
+#[derive(Debug, Clone)]
+pub struct TransferFailed {
+    pub handle: TransferHandle,
+    pub name: String,
+    pub error: TransferError,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferConfig {
+    /// payload bytes per chunk datagram, keep it under the MTU
+    pub chunk_size: usize,
+    /// max number of unacked chunks in flight per transfer
+    pub window: usize,
+    /// resend a chunk if it's not acked after this long
+    pub resend_after: Duration,
+    /// give up if the other side is silent for this long
+    pub timeout: Duration,
+    /// incoming offers bigger than this are refused
+    pub max_incoming_size: usize,
+    /// incoming transfers one peer can have going at once, further offers are refused
+    pub max_incoming_per_peer: usize,
+    /// total size of one peer's incoming transfers at once, further offers are refused
+    pub max_incoming_bytes_per_peer: usize,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024,
+            window: 64,
+            resend_after: Duration::from_millis(250),
+            timeout: Duration::from_secs(10),
+            max_incoming_size: 64 * 1024 * 1024,
+            max_incoming_per_peer: 4,
+            max_incoming_bytes_per_peer: 128 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum TransferEvent {
+    Progress(TransferProgress),
+    Completed(TransferCompleted),
+    Failed(TransferFailed),
+}
+
+struct Outgoing {
+    name: String,
+    data: Vec<u8>,
+    chunk_size: usize,
+    acked: Vec<bool>,
+    num_acked: usize,
+    in_flight: HashMap<u32, Instant>,
+    next_unsent: u32,
+    last_activity: Instant,
+    reported: usize,
+}
+
+impl Outgoing {
+    fn num_chunks(&self) -> u32 {
+        self.acked.len() as u32
+    }
+
+    fn chunk(&self, index: u32) -> &[u8] {
+        let start = index as usize * self.chunk_size;
+        let end = (start + self.chunk_size).min(self.data.len());
+        &self.data[start..end]
+    }
+
+    fn bytes_acked(&self) -> usize {
+        (self.num_acked * self.chunk_size).min(self.data.len())
+    }
+}
+
+// data and received grow as chunks arrive, so an offer alone costs next to nothing
+struct Incoming {
+    name: String,
+    crc: u32,
+    chunk_size: usize,
+    total_len: usize,
+    num_chunks: usize,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    num_received: usize,
+    pending_acks: Vec<u32>,
+    last_activity: Instant,
+    reported: usize,
+}
+
+impl Incoming {
+    fn bytes_received(&self) -> usize {
+        (self.num_received * self.chunk_size).min(self.total_len)
+    }
+}
+
+/// all transfers in both directions, owned by a NetworkResource.
+/// the poller feeds it messages and drains `outbox` and `events` every frame.
+pub(crate) struct Transfers {
+    pub(crate) config: TransferConfig,
+    next_id: u32,
+    outgoing: HashMap<(PeerHandle, u32), Outgoing>,
+    incoming: HashMap<(PeerHandle, u32), Incoming>,
+    // incoming transfers we completed recently, so we can keep acking resent chunks
+    // in case our final acks were lost.
+    finished: HashMap<(PeerHandle, u32), Instant>,
+    pub(crate) outbox: Vec<(MessageKind, LaminarPacket)>,
+    pub(crate) events: Vec<TransferEvent>,
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Self {
+            config: TransferConfig::default(),
+            next_id: 0,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            finished: HashMap::new(),
+            outbox: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Transfers {
+    pub(crate) fn start(&mut self, peer: PeerHandle, name: &str, data: Vec<u8>, now: Instant) -> Result<TransferHandle, TransferError> {
+        if name.len() > u8::MAX as usize {
+            return Err(TransferError::NameTooLong);
+        }
+        let chunk_size = self.config.chunk_size;
+        if chunk_size == 0 || chunk_size > u32::MAX as usize {
+            return Err(TransferError::InvalidChunkSize);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let num_chunks = (data.len() + chunk_size - 1) / chunk_size;
+
+        let mut offer = Vec::with_capacity(21 + name.len());
+        offer.extend_from_slice(&id.to_le_bytes());
+        offer.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        offer.extend_from_slice(&(chunk_size as u32).to_le_bytes());
+        offer.extend_from_slice(&crc32(&data).to_le_bytes());
+        offer.push(name.len() as u8);
+        offer.extend_from_slice(name.as_bytes());
+        self.outbox.push((MessageKind::TransferOffer, LaminarPacket::reliable_unordered(peer, offer)));
+
+        self.outgoing.insert((peer, id), Outgoing {
+            name: name.to_string(),
+            data,
+            chunk_size,
+            acked: vec![false; num_chunks],
+            num_acked: 0,
+            in_flight: HashMap::new(),
+            next_unsent: 0,
+            last_activity: now,
+            reported: 0,
+        });
+        Ok(TransferHandle { peer, id, direction: TransferDirection::Outgoing })
+    }
+
+    pub(crate) fn cancel(&mut self, handle: TransferHandle) {
+        let name = match handle.direction {
+            TransferDirection::Outgoing => self.outgoing.remove(&(handle.peer, handle.id)).map(|t| t.name),
+            TransferDirection::Incoming => self.incoming.remove(&(handle.peer, handle.id)).map(|t| t.name),
+        };
+        if let Some(name) = name {
+            self.abort(handle, name, TransferError::Cancelled);
+        }
+    }
+
+    /// fail everything to/from a peer that went away
+    pub(crate) fn drop_peer(&mut self, peer: PeerHandle) {
+        let outgoing: Vec<u32> = self.outgoing.keys().filter(|(p, _)| *p == peer).map(|(_, id)| *id).collect();
+        for id in outgoing {
+            let transfer = self.outgoing.remove(&(peer, id)).unwrap();
+            self.fail(TransferHandle { peer, id, direction: TransferDirection::Outgoing }, transfer.name, TransferError::Disconnected);
+        }
+        let incoming: Vec<u32> = self.incoming.keys().filter(|(p, _)| *p == peer).map(|(_, id)| *id).collect();
+        for id in incoming {
+            let transfer = self.incoming.remove(&(peer, id)).unwrap();
+            self.fail(TransferHandle { peer, id, direction: TransferDirection::Incoming }, transfer.name, TransferError::Disconnected);
+        }
+        self.finished.retain(|(p, _), _| *p != peer);
+    }
+
+    pub(crate) fn handle_message(&mut self, kind: MessageKind, peer: PeerHandle, bytes: &[u8], now: Instant) {
+        let handled = match kind {
+            MessageKind::TransferOffer => self.handle_offer(peer, bytes, now),
+            MessageKind::TransferChunk => self.handle_chunk(peer, bytes, now),
+            MessageKind::TransferAck => self.handle_ack(peer, bytes, now),
+            MessageKind::TransferAbort => self.handle_abort(peer, bytes),
+            _ => None,
+        };
+        if handled.is_none() {
+            log::warn!("Malformed {:?} message from {}", kind, peer);
+        }
+    }
+
+    fn handle_offer(&mut self, peer: PeerHandle, bytes: &[u8], now: Instant) -> Option<()> {
+        let id = read_u32(bytes, 0)?;
+        let total_len = read_u64(bytes, 4)? as usize;
+        let chunk_size = read_u32(bytes, 12)? as usize;
+        let crc = read_u32(bytes, 16)?;
+        let name_len = *bytes.get(20)? as usize;
+        let name = std::str::from_utf8(bytes.get(21..21 + name_len)?).ok()?.to_string();
+        if chunk_size == 0 {
+            return None;
+        }
+        let handle = TransferHandle { peer, id, direction: TransferDirection::Incoming };
+        if self.incoming.contains_key(&(peer, id)) || self.finished.contains_key(&(peer, id)) {
+            return Some(());
+        }
+        if total_len > self.config.max_incoming_size {
+            self.abort(handle, name, TransferError::TooLarge);
+            return Some(());
+        }
+        let (count, bytes) = self.incoming.iter()
+            .filter(|((p, _), _)| *p == peer)
+            .fold((0, 0), |(count, bytes), (_, transfer)| (count + 1, bytes + transfer.total_len));
+        if count >= self.config.max_incoming_per_peer || bytes + total_len > self.config.max_incoming_bytes_per_peer {
+            self.abort(handle, name, TransferError::TooManyTransfers);
+            return Some(());
+        }
+        let num_chunks = (total_len + chunk_size - 1) / chunk_size;
+        self.incoming.insert((peer, id), Incoming {
+            name,
+            crc,
+            chunk_size,
+            total_len,
+            num_chunks,
+            data: Vec::new(),
+            received: Vec::new(),
+            num_received: 0,
+            pending_acks: Vec::new(),
+            last_activity: now,
+            reported: 0,
+        });
+        Some(())
+    }
+
+    fn handle_chunk(&mut self, peer: PeerHandle, bytes: &[u8], now: Instant) -> Option<()> {
+        let id = read_u32(bytes, 0)?;
+        let index = read_u32(bytes, 4)?;
+        let chunk = &bytes[8..];
+        if self.finished.contains_key(&(peer, id)) {
+            // we're done, but the sender hasn't seen all our acks yet
+            let mut ack = id.to_le_bytes().to_vec();
+            ack.extend_from_slice(&index.to_le_bytes());
+            self.outbox.push((MessageKind::TransferAck, LaminarPacket::unreliable(peer, ack)));
+            return Some(());
+        }
+        // chunks can overtake the (reliable) offer, the sender will resend them
+        let transfer = match self.incoming.get_mut(&(peer, id)) {
+            Some(transfer) => transfer,
+            None => return Some(()),
+        };
+        let index_usize = index as usize;
+        let start = index_usize * transfer.chunk_size;
+        if index_usize >= transfer.num_chunks || start + chunk.len() > transfer.total_len {
+            return None;
+        }
+        if transfer.received.len() <= index_usize {
+            transfer.received.resize(index_usize + 1, false);
+        }
+        if transfer.data.len() < start + chunk.len() {
+            transfer.data.resize(start + chunk.len(), 0);
+        }
+        if !transfer.received[index_usize] {
+            transfer.data[start..start + chunk.len()].copy_from_slice(chunk);
+            transfer.received[index_usize] = true;
+            transfer.num_received += 1;
+        }
+        transfer.pending_acks.push(index);
+        transfer.last_activity = now;
+        Some(())
+    }
+
+    fn handle_ack(&mut self, peer: PeerHandle, bytes: &[u8], now: Instant) -> Option<()> {
+        let id = read_u32(bytes, 0)?;
+        let transfer = match self.outgoing.get_mut(&(peer, id)) {
+            Some(transfer) => transfer,
+            None => return Some(()),
+        };
+        let mut offset = 4;
+        while let Some(index) = read_u32(bytes, offset) {
+            offset += 4;
+            if let Some(acked) = transfer.acked.get_mut(index as usize) {
+                if !*acked {
+                    *acked = true;
+                    transfer.num_acked += 1;
+                }
+            }
+            transfer.in_flight.remove(&index);
+            transfer.last_activity = now;
+        }
+        Some(())
+    }
+
+    fn handle_abort(&mut self, peer: PeerHandle, bytes: &[u8]) -> Option<()> {
+        let id = read_u32(bytes, 0)?;
+        let from_sender = *bytes.get(4)? != 0;
+        let error = TransferError::from_u8(*bytes.get(5)?);
+        // if the sender aborted, it's one of our incoming transfers
+        let name = if from_sender {
+            self.incoming.remove(&(peer, id)).map(|t| t.name)
+        } else {
+            self.outgoing.remove(&(peer, id)).map(|t| t.name)
+        };
+        if let Some(name) = name {
+            let direction = if from_sender { TransferDirection::Incoming } else { TransferDirection::Outgoing };
+            self.fail(TransferHandle { peer, id, direction }, name, error);
+        }
+        Some(())
+    }
+
+    /// send chunks, resends and acks, and check for timeouts and completion
+    pub(crate) fn update(&mut self, now: Instant) {
+        let config = self.config.clone();
+        let mut done = Vec::new();
+        let mut failed = Vec::new();
+
+        for (&(peer, id), transfer) in self.outgoing.iter_mut() {
+            let handle = TransferHandle { peer, id, direction: TransferDirection::Outgoing };
+            if transfer.num_acked == transfer.acked.len() {
+                done.push(handle);
+                continue;
+            }
+            if now - transfer.last_activity > config.timeout {
+                failed.push(handle);
+                continue;
+            }
+            let mut to_send: Vec<u32> = transfer
+                .in_flight
+                .iter()
+                .filter(|(_, sent_at)| now - **sent_at >= config.resend_after)
+                .map(|(index, _)| *index)
+                .collect();
+            while transfer.in_flight.len() + to_send.len() < config.window
+                && transfer.next_unsent < transfer.num_chunks()
+            {
+                if !transfer.acked[transfer.next_unsent as usize] {
+                    to_send.push(transfer.next_unsent);
+                }
+                transfer.next_unsent += 1;
+            }
+            for index in to_send {
+                let mut payload = Vec::with_capacity(8 + transfer.chunk_size);
+                payload.extend_from_slice(&id.to_le_bytes());
+                payload.extend_from_slice(&index.to_le_bytes());
+                payload.extend_from_slice(transfer.chunk(index));
+                self.outbox.push((MessageKind::TransferChunk, LaminarPacket::unreliable(peer, payload)));
+                transfer.in_flight.insert(index, now);
+            }
+            if transfer.bytes_acked() != transfer.reported {
+                transfer.reported = transfer.bytes_acked();
+                self.events.push(TransferEvent::Progress(TransferProgress {
+                    handle,
+                    name: transfer.name.clone(),
+                    bytes_done: transfer.reported,
+                    bytes_total: transfer.data.len(),
+                }));
+            }
+        }
+        for handle in done {
+            let transfer = self.outgoing.remove(&(handle.peer, handle.id)).unwrap();
+            self.events.push(TransferEvent::Completed(TransferCompleted { handle, name: transfer.name, data: None }));
+        }
+        for handle in failed {
+            let transfer = self.outgoing.remove(&(handle.peer, handle.id)).unwrap();
+            self.abort(handle, transfer.name, TransferError::Timeout);
+        }
+
+        let mut done = Vec::new();
+        let mut failed = Vec::new();
+        for (&(peer, id), transfer) in self.incoming.iter_mut() {
+            let handle = TransferHandle { peer, id, direction: TransferDirection::Incoming };
+            for acks in transfer.pending_acks.chunks(256) {
+                let mut payload = Vec::with_capacity(4 + acks.len() * 4);
+                payload.extend_from_slice(&id.to_le_bytes());
+                for index in acks {
+                    payload.extend_from_slice(&index.to_le_bytes());
+                }
+                self.outbox.push((MessageKind::TransferAck, LaminarPacket::unreliable(peer, payload)));
+            }
+            transfer.pending_acks.clear();
+            if transfer.num_received == transfer.num_chunks {
+                done.push(handle);
+                continue;
+            }
+            if now - transfer.last_activity > config.timeout {
+                failed.push(handle);
+                continue;
+            }
+            if transfer.bytes_received() != transfer.reported {
+                transfer.reported = transfer.bytes_received();
+                self.events.push(TransferEvent::Progress(TransferProgress {
+                    handle,
+                    name: transfer.name.clone(),
+                    bytes_done: transfer.reported,
+                    bytes_total: transfer.total_len,
+                }));
+            }
+        }
+        for handle in done {
+            let transfer = self.incoming.remove(&(handle.peer, handle.id)).unwrap();
+            if crc32(&transfer.data) == transfer.crc {
+                self.finished.insert((handle.peer, handle.id), now);
+                self.events.push(TransferEvent::Completed(TransferCompleted {
+                    handle,
+                    name: transfer.name,
+                    data: Some(transfer.data),
+                }));
+            } else {
+                self.abort(handle, transfer.name, TransferError::IntegrityCheckFailed);
+            }
+        }
+        for handle in failed {
+            let transfer = self.incoming.remove(&(handle.peer, handle.id)).unwrap();
+            self.abort(handle, transfer.name, TransferError::Timeout);
+        }
+
+        self.finished.retain(|_, finished_at| now - *finished_at < config.timeout);
+    }
+
+    /// tell the other side we're giving up, and fail it locally
+    fn abort(&mut self, handle: TransferHandle, name: String, error: TransferError) {
+        let mut payload = handle.id.to_le_bytes().to_vec();
+        payload.push((handle.direction == TransferDirection::Outgoing) as u8);
+        payload.push(error.to_u8());
+        self.outbox.push((MessageKind::TransferAbort, LaminarPacket::reliable_unordered(handle.peer, payload)));
+        self.fail(handle, name, error);
+    }
+
+    fn fail(&mut self, handle: TransferHandle, name: String, error: TransferError) {
+        self.events.push(TransferEvent::Failed(TransferFailed { handle, name, error }));
+    }
+}
+
+/// drain queued transfer events into bevy events
+pub(crate) fn publish_events(
+    transfers: &mut Transfers,
+    progress: &mut EventWriter<TransferProgress>,
+    completed: &mut EventWriter<TransferCompleted>,
+    failed: &mut EventWriter<TransferFailed>,
+) {
+    for event in transfers.events.drain(..) {
+        match event {
+            TransferEvent::Progress(event) => progress.send(event),
+            TransferEvent::Completed(event) => completed.send(event),
+            TransferEvent::Failed(event) => failed.send(event),
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+// plain CRC-32 (IEEE), bitwise rather than table driven - it only runs once per blob
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn sender_addr() -> SocketAddr {
+        ([10, 0, 0, 1], 1000).into()
+    }
+
+    fn receiver_addr() -> SocketAddr {
+        ([10, 0, 0, 2], 2000).into()
+    }
+
+    fn transfers(chunk_size: usize) -> Transfers {
+        let mut transfers = Transfers::default();
+        transfers.config.chunk_size = chunk_size;
+        transfers
+    }
+
+    fn blob(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    // hand everything `from` has queued to `to`, which sees it as coming from `from_addr`.
+    // `keep` can drop (false) or alter messages on the way.
+    fn deliver(
+        from: &mut Transfers,
+        to: &mut Transfers,
+        from_addr: SocketAddr,
+        now: Instant,
+        mut keep: impl FnMut(MessageKind, &mut Vec<u8>) -> bool,
+    ) {
+        for (kind, packet) in std::mem::take(&mut from.outbox) {
+            let mut payload = packet.payload().to_vec();
+            if keep(kind, &mut payload) {
+                to.handle_message(kind, from_addr, &payload, now);
+            }
+        }
+    }
+
+    // one frame on each side, with everything delivered
+    fn frame(sender: &mut Transfers, receiver: &mut Transfers, now: Instant) {
+        sender.update(now);
+        deliver(sender, receiver, sender_addr(), now, |_, _| true);
+        receiver.update(now);
+        deliver(receiver, sender, receiver_addr(), now, |_, _| true);
+    }
+
+    fn completed(transfers: &Transfers) -> Vec<TransferCompleted> {
+        transfers.events.iter().filter_map(|event| match event {
+            TransferEvent::Completed(completed) => Some(completed.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    fn failed(transfers: &Transfers) -> Vec<(TransferDirection, TransferError)> {
+        transfers.events.iter().filter_map(|event| match event {
+            TransferEvent::Failed(failed) => Some((failed.handle.direction(), failed.error)),
+            _ => None,
+        }).collect()
+    }
+
+    fn chunk_index(payload: &[u8]) -> u32 {
+        read_u32(payload, 4).unwrap()
+    }
+
+    #[test]
+    fn blob_arrives_intact() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        let now = Instant::now();
+        let data = blob(100);
+        sender.start(receiver_addr(), "map", data.clone(), now).unwrap();
+        for _ in 0..3 {
+            frame(&mut sender, &mut receiver, now);
+        }
+        let received = completed(&receiver);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].name, "map");
+        assert_eq!(received[0].data.as_ref(), Some(&data));
+        assert_eq!(received[0].handle.peer(), sender_addr());
+        let sent = completed(&sender);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].data.is_none());
+    }
+
+    #[test]
+    fn dropped_chunk_is_resent() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        let mut now = Instant::now();
+        let data = blob(64);
+        sender.start(receiver_addr(), "map", data.clone(), now).unwrap();
+        sender.update(now);
+        // the third chunk never makes it
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |kind, payload| {
+            kind != MessageKind::TransferChunk || chunk_index(payload) != 2
+        });
+        receiver.update(now);
+        deliver(&mut receiver, &mut sender, receiver_addr(), now, |_, _| true);
+        assert!(completed(&receiver).is_empty());
+
+        // nothing's resent before resend_after
+        sender.update(now);
+        assert!(sender.outbox.is_empty());
+        now += sender.config.resend_after;
+        sender.update(now);
+        let resent: Vec<u32> = sender.outbox.iter()
+            .filter(|(kind, _)| *kind == MessageKind::TransferChunk)
+            .map(|(_, packet)| chunk_index(packet.payload()))
+            .collect();
+        assert_eq!(resent, vec![2]);
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |_, _| true);
+        receiver.update(now);
+        deliver(&mut receiver, &mut sender, receiver_addr(), now, |_, _| true);
+        assert_eq!(completed(&receiver)[0].data.as_ref(), Some(&data));
+        sender.update(now);
+        assert_eq!(completed(&sender).len(), 1);
+    }
+
+    #[test]
+    fn corrupted_blob_fails_both_sides() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        let now = Instant::now();
+        sender.start(receiver_addr(), "map", blob(40), now).unwrap();
+        sender.update(now);
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |kind, payload| {
+            if kind == MessageKind::TransferChunk && chunk_index(payload) == 1 {
+                payload[8] ^= 0xff;
+            }
+            true
+        });
+        receiver.update(now);
+        assert_eq!(failed(&receiver), vec![(TransferDirection::Incoming, TransferError::IntegrityCheckFailed)]);
+        assert!(completed(&receiver).is_empty());
+        deliver(&mut receiver, &mut sender, receiver_addr(), now, |_, _| true);
+        assert_eq!(failed(&sender), vec![(TransferDirection::Outgoing, TransferError::IntegrityCheckFailed)]);
+    }
+
+    #[test]
+    fn oversized_offers_are_refused() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        receiver.config.max_incoming_size = 63;
+        let now = Instant::now();
+        sender.start(receiver_addr(), "map", blob(64), now).unwrap();
+        frame(&mut sender, &mut receiver, now);
+        assert_eq!(failed(&receiver), vec![(TransferDirection::Incoming, TransferError::TooLarge)]);
+        assert_eq!(failed(&sender), vec![(TransferDirection::Outgoing, TransferError::TooLarge)]);
+        assert!(receiver.incoming.is_empty());
+        assert!(sender.outgoing.is_empty());
+    }
+
+    #[test]
+    fn too_many_offers_are_refused() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        receiver.config.max_incoming_per_peer = 1;
+        let now = Instant::now();
+        sender.start(receiver_addr(), "one", blob(64), now).unwrap();
+        sender.start(receiver_addr(), "two", blob(64), now).unwrap();
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |_, _| true);
+        assert_eq!(receiver.incoming.len(), 1);
+        assert_eq!(failed(&receiver), vec![(TransferDirection::Incoming, TransferError::TooManyTransfers)]);
+
+        let mut receiver = transfers(16);
+        receiver.config.max_incoming_bytes_per_peer = 100;
+        sender.start(receiver_addr(), "three", blob(64), now).unwrap();
+        sender.start(receiver_addr(), "four", blob(64), now).unwrap();
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |_, _| true);
+        assert_eq!(failed(&receiver), vec![(TransferDirection::Incoming, TransferError::TooManyTransfers)]);
+    }
+
+    #[test]
+    fn sender_cancels() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        let now = Instant::now();
+        let handle = sender.start(receiver_addr(), "map", blob(64), now).unwrap();
+        sender.update(now);
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |kind, _| kind == MessageKind::TransferOffer);
+        sender.cancel(handle);
+        assert_eq!(failed(&sender), vec![(TransferDirection::Outgoing, TransferError::Cancelled)]);
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |_, _| true);
+        assert_eq!(failed(&receiver), vec![(TransferDirection::Incoming, TransferError::Cancelled)]);
+        assert!(receiver.incoming.is_empty());
+    }
+
+    #[test]
+    fn receiver_cancels() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        let now = Instant::now();
+        sender.start(receiver_addr(), "map", blob(64), now).unwrap();
+        sender.update(now);
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |kind, _| kind == MessageKind::TransferOffer);
+        let handle = TransferHandle { peer: sender_addr(), id: 0, direction: TransferDirection::Incoming };
+        receiver.cancel(handle);
+        assert_eq!(failed(&receiver), vec![(TransferDirection::Incoming, TransferError::Cancelled)]);
+        deliver(&mut receiver, &mut sender, receiver_addr(), now, |_, _| true);
+        assert_eq!(failed(&sender), vec![(TransferDirection::Outgoing, TransferError::Cancelled)]);
+        assert!(sender.outgoing.is_empty());
+    }
+
+    #[test]
+    fn chunks_after_completion_are_acked_again() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        let now = Instant::now();
+        sender.start(receiver_addr(), "map", blob(32), now).unwrap();
+        sender.update(now);
+        let chunks: Vec<Vec<u8>> = sender.outbox.iter()
+            .filter(|(kind, _)| *kind == MessageKind::TransferChunk)
+            .map(|(_, packet)| packet.payload().to_vec())
+            .collect();
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |_, _| true);
+        receiver.update(now);
+        assert_eq!(completed(&receiver).len(), 1);
+        // our acks got lost, so the sender tries again
+        receiver.outbox.clear();
+        receiver.handle_message(MessageKind::TransferChunk, sender_addr(), &chunks[1], now);
+        let acks: Vec<&LaminarPacket> = receiver.outbox.iter()
+            .filter(|(kind, _)| *kind == MessageKind::TransferAck)
+            .map(|(_, packet)| packet)
+            .collect();
+        assert_eq!(acks.len(), 1);
+        assert_eq!(read_u32(acks[0].payload(), 0), Some(0));
+        assert_eq!(read_u32(acks[0].payload(), 4), Some(1));
+        // and no second completion
+        receiver.events.clear();
+        receiver.update(now);
+        assert!(completed(&receiver).is_empty());
+    }
+
+    #[test]
+    fn silence_times_out() {
+        let (mut sender, mut receiver) = (transfers(16), transfers(16));
+        let now = Instant::now();
+        sender.start(receiver_addr(), "map", blob(64), now).unwrap();
+        sender.update(now);
+        deliver(&mut sender, &mut receiver, sender_addr(), now, |kind, _| kind == MessageKind::TransferOffer);
+        let later = now + sender.config.timeout + Duration::from_millis(1);
+        sender.update(later);
+        receiver.update(later);
+        assert_eq!(failed(&sender), vec![(TransferDirection::Outgoing, TransferError::Timeout)]);
+        assert_eq!(failed(&receiver), vec![(TransferDirection::Incoming, TransferError::Timeout)]);
+    }
+
+    #[test]
+    fn bad_starts_send_nothing() {
+        let mut sender = transfers(0);
+        let now = Instant::now();
+        assert_eq!(sender.start(receiver_addr(), "map", blob(64), now), Err(TransferError::InvalidChunkSize));
+        sender.config.chunk_size = 16;
+        let name = "n".repeat(256);
+        assert_eq!(sender.start(receiver_addr(), &name, blob(64), now), Err(TransferError::NameTooLong));
+        assert!(sender.outbox.is_empty());
+        assert!(sender.outgoing.is_empty());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+}